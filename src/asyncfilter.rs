@@ -0,0 +1,231 @@
+//! This filter runs a [`MatteEstimator`] on a worker thread, so a model slower than the camera
+//! does not hold up the stream. Each frame is handed to the worker and composited with the
+//! newest matte available, which may have been estimated from an earlier frame. If the worker
+//! is still busy when the next frame arrives, the frame waiting for it is replaced, so the
+//! worker always picks up the newest frame and never falls behind. Should the estimator
+//! panic, the worker stops and every following frame fails with an error.
+
+use crate::filter::{FilterError, Matte, MatteEstimator, Quality, StageTimings};
+use opencv::core::Size;
+use opencv::prelude::*;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+#[derive(Debug)]
+struct WorkerState {
+    /// The newest frame the worker has not picked up yet
+    pending: Option<Mat>,
+    /// The newest matte and the timings of estimating it
+    latest: Option<(Mat, StageTimings)>,
+    /// An error of the worker, reported with the next frame
+    error: Option<FilterError>,
+    /// A quality the worker has not passed to the estimator yet
+    quality: Option<Quality>,
+    /// Set if the estimator has to be reset before the next frame
+    reset: bool,
+    /// Incremented with each reset, mattes of frames from before a reset are dropped
+    generation: u64,
+    shutdown: bool,
+    /// Set when the worker has stopped, e.g. because the estimator panicked
+    stopped: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<WorkerState>,
+    changed: Condvar,
+}
+
+#[derive(Debug)]
+pub struct AsyncFilter {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+    timings: StageTimings,
+}
+
+impl AsyncFilter {
+    /// Start a worker thread running `estimator`.
+    pub fn new(estimator: Box<dyn MatteEstimator>) -> Result<AsyncFilter, FilterError> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(WorkerState {
+                pending: None,
+                latest: None,
+                error: None,
+                quality: None,
+                reset: false,
+                generation: 0,
+                shutdown: false,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let worker_shared = Arc::clone(&shared);
+        let worker = std::thread::Builder::new()
+            .name(String::from("fakecam-inference"))
+            .spawn(move || run_worker(estimator, &worker_shared))
+            .map_err(|e| FilterError::Other(format!("Failed to start inference thread: {}", e)))?;
+        Ok(AsyncFilter {
+            shared,
+            worker: Some(worker),
+            timings: StageTimings::default(),
+        })
+    }
+}
+
+/// Marks the worker as stopped when dropped, which also happens when the worker unwinds from a
+/// panic, so nobody waits for a matte which never comes.
+struct StopGuard<'a>(&'a Shared);
+
+impl<'a> Drop for StopGuard<'a> {
+    fn drop(&mut self) {
+        // The lock is poisoned if the panic happened while holding it
+        let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.stopped = true;
+        self.0.changed.notify_all();
+    }
+}
+
+fn run_worker(mut estimator: Box<dyn MatteEstimator>, shared: &Shared) {
+    let _guard = StopGuard(shared);
+    loop {
+        let (frame, quality, reset, generation) = {
+            let mut state = shared.state.lock().unwrap();
+            while state.pending.is_none() && !state.shutdown {
+                state = shared.changed.wait(state).unwrap();
+            }
+            let frame = match state.pending.take() {
+                Some(frame) if !state.shutdown => frame,
+                _ => return,
+            };
+            let reset = mem::take(&mut state.reset);
+            (frame, state.quality.take(), reset, state.generation)
+        };
+        if reset {
+            estimator.reset();
+        }
+        if let Some(quality) = quality {
+            estimator.set_quality(&quality);
+        }
+        let result = estimator.estimate(&frame);
+        let timings = estimator.timings().cloned().unwrap_or_default();
+
+        let mut state = shared.state.lock().unwrap();
+        if state.generation != generation {
+            // Reset while estimating, the matte belongs to a frame from before the reset
+            continue;
+        }
+        match result {
+            Ok(matte) => state.latest = Some((matte.pha, timings)),
+            Err(e) => state.error = Some(e),
+        }
+        shared.changed.notify_all();
+    }
+}
+
+/// Whether `latest` holds a matte for frames of `size`.
+fn has_matte(latest: &Option<(Mat, StageTimings)>, size: Size) -> Result<bool, FilterError> {
+    match latest {
+        Some((pha, _)) => Ok(pha.size()? == size),
+        None => Ok(false),
+    }
+}
+
+impl Drop for AsyncFilter {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+        if let Some(worker) = self.worker.take() {
+            // The worker finishes the frame it is working on first
+            let _ = worker.join();
+        }
+    }
+}
+
+impl MatteEstimator for AsyncFilter {
+    /// Hand `src_image` to the worker and return the newest matte. Only if there is no matte
+    /// for frames of this size yet, e.g. for the first frame, this waits for the worker.
+    fn estimate(&mut self, src_image: &Mat) -> Result<Matte, FilterError> {
+        let start = Instant::now();
+        let size = src_image.size()?;
+        // The caller may modify the frame in place once this returns
+        let frame = src_image.try_clone()?;
+
+        let mut state = self.shared.state.lock().unwrap();
+        state.pending = Some(frame);
+        self.shared.changed.notify_all();
+        loop {
+            if let Some(e) = state.error.take() {
+                return Err(e);
+            }
+            if state.stopped {
+                return Err(FilterError::Other(String::from(
+                    "The inference thread stopped after the estimator panicked",
+                )));
+            }
+            if has_matte(&state.latest, size)? {
+                break;
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        let (pha, estimate_timings) = state.latest.as_ref().expect("Checked above");
+        // The worker replaces the matte instead of writing to it, so it can be shared
+        let pha = Mat::copy(pha)?;
+        self.timings.clear();
+        self.timings.extend(estimate_timings);
+        drop(state);
+        self.timings.record("handoff", start);
+
+        // The foreground estimate of an earlier frame would lag behind, so the frame itself is
+        // used as foreground
+        Ok(Matte { fgr: None, pha })
+    }
+
+    fn timings(&self) -> Option<&StageTimings> {
+        Some(&self.timings)
+    }
+
+    /// The worker passes `quality` on to the estimator before the next frame.
+    fn set_quality(&mut self, quality: &Quality) {
+        self.shared.state.lock().unwrap().quality = Some(*quality);
+    }
+
+    /// Drop the newest matte and reset the estimator before the next frame, so the next frame
+    /// waits for a matte estimated after the reset.
+    fn reset(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.pending = None;
+        state.latest = None;
+        state.error = None;
+        state.reset = true;
+        state.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{Scalar, CV_8UC3};
+
+    #[derive(Debug)]
+    struct PanickingEstimator;
+
+    impl MatteEstimator for PanickingEstimator {
+        fn estimate(&mut self, _src_image: &Mat) -> Result<Matte, FilterError> {
+            panic!("Estimator failed");
+        }
+    }
+
+    #[test]
+    fn panicking_estimator_is_an_error() {
+        let mut filter = AsyncFilter::new(Box::new(PanickingEstimator)).unwrap();
+        let frame = Mat::new_rows_cols_with_default(2, 2, CV_8UC3, Scalar::all(0.0)).unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                filter.estimate(&frame),
+                Err(FilterError::Other(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! This module provides the backgrounds that filters replacing the background composite onto.
+//! A background is either a solid colour, a still image or a video file which is looped. It is
+//! scaled to the frame size according to a [`FitMode`].
+
+use crate::filter::FilterError;
+use gstreamer::glib;
+use opencv::core::{Rect, Scalar, Size, BORDER_CONSTANT, CV_8UC3};
+use opencv::imgproc::{cvt_color, resize, COLOR_BGR2RGB, INTER_AREA};
+use opencv::prelude::*;
+use opencv::videoio::{VideoCapture, CAP_ANY, CAP_PROP_POS_FRAMES};
+use std::fmt;
+use std::str::FromStr;
+
+/// How a background is scaled if its aspect ratio differs from that of the frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, glib::Enum)]
+#[enum_type(name = "FakecamFitMode")]
+pub enum FitMode {
+    /// Scale width and height independently, distorting the background
+    #[enum_value(name = "Stretch to the frame", nick = "stretch")]
+    Stretch,
+    /// Scale to cover the whole frame and crop what does not fit
+    #[enum_value(name = "Cover the frame and crop", nick = "cover")]
+    Cover,
+    /// Scale to fit into the frame and fill the remaining space with black bars
+    #[enum_value(name = "Fit into the frame with black bars", nick = "contain")]
+    Contain,
+}
+
+impl FitMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FitMode::Stretch => "stretch",
+            FitMode::Cover => "cover",
+            FitMode::Contain => "contain",
+        }
+    }
+}
+
+impl fmt::Display for FitMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for FitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stretch" => Ok(FitMode::Stretch),
+            "cover" => Ok(FitMode::Cover),
+            "contain" => Ok(FitMode::Contain),
+            other => Err(format!(
+                "Unknown fit mode '{}', expected one of stretch, cover or contain",
+                other
+            )),
+        }
+    }
+}
+
+enum Source {
+    Solid(Scalar),
+    /// A still image in BGR as loaded by OpenCV
+    Image(Mat),
+    /// A video which is restarted once it ends
+    Video(VideoCapture),
+}
+
+impl fmt::Debug for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Solid(color) => f.debug_tuple("Solid").field(color).finish(),
+            Source::Image(image) => f.debug_tuple("Image").field(&image.size()).finish(),
+            Source::Video(_) => f.write_str("Video"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Background {
+    source: Source,
+    fit: FitMode,
+    size: Size,
+    /// The last decoded video frame, in BGR
+    raw: Mat,
+    /// The current background, scaled to the frame size and converted to RGB
+    frame: Mat,
+}
+
+/// Scale `src` to `size` according to `fit`, writing the result to `dst`.
+pub fn fit_image(src: &Mat, dst: &mut Mat, size: Size, fit: FitMode) -> Result<(), FilterError> {
+    let src_size = src.size()?;
+    if src_size.width <= 0 || src_size.height <= 0 {
+        return Err(FilterError::Other(String::from("Background image is empty")));
+    }
+    let scale_x = size.width as f64 / src_size.width as f64;
+    let scale_y = size.height as f64 / src_size.height as f64;
+    match fit {
+        FitMode::Stretch => resize(src, dst, size, 0.0, 0.0, INTER_AREA)?,
+        FitMode::Cover => {
+            let scale = scale_x.max(scale_y);
+            let scaled_size = Size::new(
+                ((src_size.width as f64 * scale).ceil() as i32).max(size.width),
+                ((src_size.height as f64 * scale).ceil() as i32).max(size.height),
+            );
+            let mut scaled = Mat::default();
+            resize(src, &mut scaled, scaled_size, 0.0, 0.0, INTER_AREA)?;
+            let crop = Rect::new(
+                (scaled_size.width - size.width) / 2,
+                (scaled_size.height - size.height) / 2,
+                size.width,
+                size.height,
+            );
+            Mat::roi(&scaled, crop)?.copy_to(dst)?;
+        }
+        FitMode::Contain => {
+            let scale = scale_x.min(scale_y);
+            let scaled_size = Size::new(
+                ((src_size.width as f64 * scale).floor() as i32).clamp(1, size.width),
+                ((src_size.height as f64 * scale).floor() as i32).clamp(1, size.height),
+            );
+            let mut scaled = Mat::default();
+            resize(src, &mut scaled, scaled_size, 0.0, 0.0, INTER_AREA)?;
+            let left = (size.width - scaled_size.width) / 2;
+            let top = (size.height - scaled_size.height) / 2;
+            opencv::core::copy_make_border(
+                &scaled,
+                dst,
+                top,
+                size.height - scaled_size.height - top,
+                left,
+                size.width - scaled_size.width - left,
+                BORDER_CONSTANT,
+                Scalar::all(0.0),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+impl Background {
+    /// A background of a single colour, given in RGB.
+    pub fn solid(color: Scalar, size: Size) -> Result<Background, FilterError> {
+        let mut background = Background {
+            source: Source::Solid(color),
+            fit: FitMode::Stretch,
+            size: Size::default(),
+            raw: Mat::default(),
+            frame: Mat::default(),
+        };
+        background.set_size(size)?;
+        Ok(background)
+    }
+
+    /// Load the background from the image or video file at `location`.
+    pub fn open(location: &str, fit: FitMode, size: Size) -> Result<Background, FilterError> {
+        let image = opencv::imgcodecs::imread(location, opencv::imgcodecs::IMREAD_COLOR)?;
+        let source = if !image.empty() {
+            Source::Image(image)
+        } else {
+            let capture = VideoCapture::from_file(location, CAP_ANY)?;
+            if !capture.is_opened()? {
+                return Err(FilterError::Other(format!(
+                    "Failed to load background {}, it is neither an image nor a video",
+                    location
+                )));
+            }
+            Source::Video(capture)
+        };
+        let mut background = Background {
+            source,
+            fit,
+            size: Size::default(),
+            raw: Mat::default(),
+            frame: Mat::default(),
+        };
+        background.set_size(size)?;
+        Ok(background)
+    }
+
+    /// Change the size of the frames the background is composited onto.
+    pub fn set_size(&mut self, size: Size) -> Result<(), FilterError> {
+        self.size = size;
+        self.render()
+    }
+
+    /// Change how the background is scaled to the frame size.
+    pub fn set_fit(&mut self, fit: FitMode) -> Result<(), FilterError> {
+        self.fit = fit;
+        self.render()
+    }
+
+    /// Advance the background by one frame and return it. For still backgrounds this always
+    /// returns the same frame.
+    pub fn next_frame(&mut self) -> Result<&Mat, FilterError> {
+        if let Source::Video(capture) = &mut self.source {
+            if !capture.read(&mut self.raw)? {
+                // Loop the video once it ended
+                capture.set(CAP_PROP_POS_FRAMES, 0.0)?;
+                if !capture.read(&mut self.raw)? {
+                    return Err(FilterError::Other(String::from(
+                        "Failed to read frame from background video",
+                    )));
+                }
+            }
+            self.render()?;
+        }
+        Ok(&self.frame)
+    }
+
+    /// Scale the current source frame to the frame size.
+    fn render(&mut self) -> Result<(), FilterError> {
+        let bgr = match &self.source {
+            Source::Solid(color) => {
+                self.frame = Mat::new_size_with_default(self.size, CV_8UC3, *color)?;
+                return Ok(());
+            }
+            Source::Image(image) => image,
+            // No frame has been decoded yet, it will be rendered with the first one
+            Source::Video(_) if self.raw.empty() => {
+                self.frame = Mat::new_size_with_default(self.size, CV_8UC3, Scalar::all(0.0))?;
+                return Ok(());
+            }
+            Source::Video(_) => &self.raw,
+        };
+        let mut scaled = Mat::default();
+        fit_image(bgr, &mut scaled, self.size, self.fit)?;
+        // OpenCV decodes to BGR, frames are RGB
+        cvt_color(&scaled, &mut self.frame, COLOR_BGR2RGB, 0)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+//! The bench subcommand, which pushes frames through a filter without a pipeline around it and
+//! reports how long each stage of the filter took and how many buffers it allocated per frame,
+//! for every combination of resolution and downsample ratio asked for.
+
+use crate::background::{Background, FitMode};
+use crate::cli::BenchOptions;
+use crate::filter::{Filter, FilterError};
+use crate::plugin::{self, Mode, Settings};
+use crate::registry;
+use opencv::core::{Scalar, Size, CV_8UC3};
+use opencv::prelude::*;
+use std::time::{Duration, Instant};
+
+/// Frames filtered before measuring, so one-time setup in the model is not measured.
+const WARMUP_FRAMES: u32 = 5;
+
+/// The durations measured for one stage, in the order the stage first ran.
+#[derive(Debug)]
+struct StageStats {
+    name: &'static str,
+    durations: Vec<Duration>,
+    /// The buffers allocated over all frames
+    allocations: usize,
+}
+
+impl StageStats {
+    fn allocations_per_frame(&self) -> f64 {
+        self.allocations as f64 / self.durations.len().max(1) as f64
+    }
+
+    fn mean(&self) -> Duration {
+        let total: Duration = self.durations.iter().sum();
+        total / self.durations.len().max(1) as u32
+    }
+
+    fn p95(&self) -> Duration {
+        let mut sorted = self.durations.clone();
+        sorted.sort_unstable();
+        let index = (sorted.len() as f64 * 0.95).ceil() as usize;
+        sorted
+            .get(index.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// The input frames, either random noise or the frames of a video, which is looped.
+enum FrameSource {
+    Synthetic(Mat),
+    Video(Background),
+}
+
+impl FrameSource {
+    fn new(options: &BenchOptions, size: Size) -> Result<FrameSource, FilterError> {
+        match &options.video {
+            // A background video is already decoded, looped and scaled to the frame size
+            Some(video) => Ok(FrameSource::Video(Background::open(
+                &video.to_string_lossy(),
+                FitMode::Cover,
+                size,
+            )?)),
+            None => {
+                let mut frame = Mat::new_size_with_default(size, CV_8UC3, Scalar::all(0.0))?;
+                opencv::core::randu(&mut frame, &Scalar::all(0.0), &Scalar::all(255.0))?;
+                Ok(FrameSource::Synthetic(frame))
+            }
+        }
+    }
+
+    /// Copy the next frame to `frame`, so the filter can modify it.
+    fn next_frame(&mut self, frame: &mut Mat) -> Result<(), FilterError> {
+        match self {
+            FrameSource::Synthetic(synthetic) => synthetic.copy_to(frame)?,
+            FrameSource::Video(video) => video.next_frame()?.copy_to(frame)?,
+        }
+        Ok(())
+    }
+}
+
+/// The result of benchmarking one configuration.
+#[derive(Debug)]
+struct BenchResult {
+    /// Time spent in the filter for each measured frame
+    total: StageStats,
+    stages: Vec<StageStats>,
+}
+
+impl BenchResult {
+    fn fps(&self) -> f64 {
+        let total: Duration = self.total.durations.iter().sum();
+        self.total.durations.len() as f64 / total.as_secs_f64()
+    }
+}
+
+fn bench_config(
+    options: &BenchOptions,
+    size: Size,
+    downsample_ratio: f64,
+) -> Result<BenchResult, FilterError> {
+    let mut settings = Settings {
+        filter: options.filter.name.clone(),
+        model_location: options
+            .filter
+            .model
+            .as_ref()
+            .map(|model| model.to_string_lossy().into_owned()),
+        model_config: options
+            .filter
+            .model_config
+            .as_ref()
+            .map(|config| config.to_string_lossy().into_owned()),
+        mode: options.filter.mode,
+        background_location: options
+            .filter
+            .background
+            .as_ref()
+            .map(|background| background.to_string_lossy().into_owned()),
+        background_fit: options.filter.background_fit,
+        downsample_ratio,
+        blur_kind: options.filter.blur_kind,
+        async_inference: options.filter.async_inference,
+        max_inference_interval: options.filter.max_inference_interval,
+        ..Default::default()
+    };
+    if let Some(blur_radius) = options.filter.blur_radius {
+        settings.blur_radius = blur_radius;
+    }
+    if let Some(key_color) = options.filter.key_color {
+        settings.key_color = key_color;
+    }
+    let mut filter = plugin::build_filter(&settings)?;
+    let mut background = plugin::build_background(&settings, size)?;
+    let mut source = FrameSource::new(options, size)?;
+
+    let mut frame = Mat::default();
+    let mut output = Mat::default();
+    let mut result = BenchResult {
+        total: StageStats {
+            name: "total",
+            durations: Vec::with_capacity(options.frames as usize),
+            allocations: 0,
+        },
+        stages: Vec::new(),
+    };
+    for i in 0..WARMUP_FRAMES + options.frames {
+        source.next_frame(&mut frame)?;
+        let bg = background.next_frame()?;
+
+        let start = Instant::now();
+        if settings.mode == Mode::Alpha {
+            filter.filter_alpha(&frame, &mut output)?;
+        } else {
+            filter.filter_inplace(&mut frame, bg)?;
+        }
+        let elapsed = start.elapsed();
+
+        if i < WARMUP_FRAMES {
+            continue;
+        }
+        result.total.durations.push(elapsed);
+        result.total.allocations += record_stages(&mut result.stages, &*filter);
+    }
+    Ok(result)
+}
+
+/// Add the stages of the last frame to `stages` and return how many buffers they allocated.
+fn record_stages(stages: &mut Vec<StageStats>, filter: &dyn Filter) -> usize {
+    let timings = match filter.timings() {
+        Some(timings) => timings,
+        None => return 0,
+    };
+    for (name, duration) in timings.iter() {
+        match stages.iter_mut().find(|stage| stage.name == name) {
+            Some(stage) => stage.durations.push(duration),
+            None => stages.push(StageStats {
+                name,
+                durations: vec![duration],
+                allocations: 0,
+            }),
+        }
+    }
+    let mut total = 0;
+    for stage in stages.iter_mut() {
+        let allocations = timings.allocations(stage.name);
+        stage.allocations += allocations;
+        total += allocations;
+    }
+    total
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn print_result(size: Size, downsample_ratio: f64, result: &BenchResult) {
+    let resolution = format!("{}x{}", size.width, size.height);
+    println!(
+        "{:<11} {:>5} {:>7.1}  {:<12} {:>9.2} {:>9.2} {:>7.1}",
+        resolution,
+        downsample_ratio,
+        result.fps(),
+        result.total.name,
+        millis(result.total.mean()),
+        millis(result.total.p95()),
+        result.total.allocations_per_frame()
+    );
+    for stage in &result.stages {
+        println!(
+            "{:<11} {:>5} {:>7}  {:<12} {:>9.2} {:>9.2} {:>7.1}",
+            "",
+            "",
+            "",
+            stage.name,
+            millis(stage.mean()),
+            millis(stage.p95()),
+            stage.allocations_per_frame()
+        );
+    }
+}
+
+/// Run the benchmark described by `options` and print the results.
+pub fn run(options: &BenchOptions) -> Result<(), FilterError> {
+    let source = match &options.video {
+        Some(video) => video.display().to_string(),
+        None => String::from("synthetic frames"),
+    };
+    let filter = match &options.filter.name {
+        Some(name) => name.as_str(),
+        None => {
+            registry::default_filter(options.filter.mode, options.filter.model_config.is_some())
+        }
+    };
+    println!(
+        "Filtering {} frames of {} with the {} filter in mode {}, after {} warmup frames",
+        options.frames, source, filter, options.filter.mode, WARMUP_FRAMES
+    );
+    println!(
+        "{:<11} {:>5} {:>7}  {:<12} {:>9} {:>9} {:>7}",
+        "Resolution", "Ratio", "FPS", "Stage", "Mean [ms]", "P95 [ms]", "Allocs"
+    );
+    for &(width, height) in &options.resolutions {
+        let size = Size::new(width, height);
+        for &downsample_ratio in &options.downsample_ratios {
+            let result = bench_config(options, size, downsample_ratio)?;
+            print_result(size, downsample_ratio, &result);
+        }
+    }
+    Ok(())
+}
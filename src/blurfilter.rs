@@ -0,0 +1,172 @@
+//! This filter blurs the background behind a person instead of replacing it. In a
+//! [`crate::chain::FilterChain`] it replaces the background with a blurred copy of the frame,
+//! so the person is composited sharply over it by the following filters. The background image
+//! passed to the filter is ignored.
+
+use crate::filter::{Filter, FilterError, FrameContext, StageTimings};
+use gstreamer::glib;
+use opencv::core::{Point, Size, BORDER_DEFAULT, CV_32F};
+use opencv::imgproc::{filter_2d, gaussian_blur, get_structuring_element, MORPH_ELLIPSE};
+use opencv::prelude::*;
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+/// The kind of blur applied to the background.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, glib::Enum)]
+#[enum_type(name = "FakecamBlurKind")]
+pub enum BlurKind {
+    /// A regular gaussian blur, cheap and smooth.
+    #[enum_value(name = "Gaussian blur", nick = "gaussian")]
+    Gaussian,
+    /// A disc-shaped kernel which imitates the out-of-focus look of a camera lens. This is
+    /// considerably more expensive than the gaussian blur for large radii.
+    #[enum_value(name = "Lens-like bokeh blur", nick = "bokeh")]
+    Bokeh,
+}
+
+impl BlurKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlurKind::Gaussian => "gaussian",
+            BlurKind::Bokeh => "bokeh",
+        }
+    }
+}
+
+impl fmt::Display for BlurKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for BlurKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gaussian" => Ok(BlurKind::Gaussian),
+            "bokeh" => Ok(BlurKind::Bokeh),
+            other => Err(format!(
+                "Unknown blur kind '{}', expected gaussian or bokeh",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BlurFilter {
+    kind: BlurKind,
+    radius: u32,
+    /// Normalized disc kernel, only used for bokeh blur
+    kernel: Mat,
+    /// Blurred copy of the last frame, kept around to avoid reallocating it on every frame
+    blurred: Mat,
+    timings: StageTimings,
+}
+
+impl BlurFilter {
+    /// Create a filter which blurs the background of the frames using the given kind of blur
+    /// with the given radius in pixels.
+    pub fn new(kind: BlurKind, radius: u32) -> Result<BlurFilter, FilterError> {
+        let mut filter = BlurFilter {
+            kind,
+            radius,
+            kernel: Mat::default(),
+            blurred: Mat::default(),
+            timings: StageTimings::default(),
+        };
+        filter.set_blur(kind, radius)?;
+        Ok(filter)
+    }
+
+    /// Change the kind and strength of the blur.
+    pub fn set_blur(&mut self, kind: BlurKind, radius: u32) -> Result<(), FilterError> {
+        let radius = radius.max(1);
+        if kind == BlurKind::Bokeh {
+            let size = (2 * radius + 1) as i32;
+            let disc = get_structuring_element(
+                MORPH_ELLIPSE,
+                Size::new(size, size),
+                Point::new(-1, -1),
+            )?;
+            let mut kernel = Mat::default();
+            disc.convert_to(&mut kernel, CV_32F, 1.0, 0.0)?;
+            let sum = opencv::core::sum_elems(&kernel)?[0];
+            kernel.convert_to(&mut self.kernel, CV_32F, 1.0 / sum, 0.0)?;
+        } else {
+            self.kernel = Mat::default();
+        }
+        self.kind = kind;
+        self.radius = radius;
+        Ok(())
+    }
+
+    fn blur(&mut self, src_image: &Mat) -> Result<(), FilterError> {
+        match self.kind {
+            BlurKind::Gaussian => {
+                // A kernel of 2r+1 covers about three standard deviations on each side
+                let size = (2 * self.radius + 1) as i32;
+                let sigma = self.radius as f64 / 3.0;
+                gaussian_blur(
+                    src_image,
+                    &mut self.blurred,
+                    Size::new(size, size),
+                    sigma,
+                    sigma,
+                    BORDER_DEFAULT,
+                )?;
+            }
+            BlurKind::Bokeh => {
+                filter_2d(
+                    src_image,
+                    &mut self.blurred,
+                    -1,
+                    &self.kernel,
+                    Point::new(-1, -1),
+                    0.0,
+                    BORDER_DEFAULT,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Filter for BlurFilter {
+    /// Blur the whole frame. In a chain only the background is blurred, see
+    /// [`Filter::process`].
+    fn filter_inplace(&mut self, src_image: &mut Mat, _bg_image: &Mat) -> Result<(), FilterError> {
+        self.timings.clear();
+        let start = Instant::now();
+        self.blur(src_image)?;
+        self.blurred.copy_to(src_image)?;
+        self.timings.record("blur", start);
+        Ok(())
+    }
+
+    fn timings(&self) -> Option<&StageTimings> {
+        Some(&self.timings)
+    }
+
+    /// Replace the background of `ctx` with a blurred copy of the frame. Without a background
+    /// nothing is composited, so there is nothing to blur either.
+    fn process(&mut self, ctx: &mut FrameContext) -> Result<(), FilterError> {
+        self.timings.clear();
+        if ctx.background.is_none() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        self.blur(ctx.frame)?;
+        // A header sharing the buffer of the blurred copy rather than a copy of its pixels. The
+        // buffer is overwritten by the next frame, once the context is gone.
+        ctx.background = Some(Cow::Owned(Mat::copy(&self.blurred)?));
+        // The sharp frame itself is used as foreground. At soft edges it is blended with its own
+        // blurred copy, so the foreground colour estimated by the model is not needed.
+        ctx.fgr = None;
+        self.timings.record("blur", start);
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+//! A filter chain runs several filters one after the other over the same frame, e.g. estimating
+//! the matte, refining it and compositing the frame onto the background. The filters share a
+//! [`FrameContext`], so a filter sees the matte and everything else the previous filters found
+//! out about the frame.
+
+use crate::composite::{AlphaMode, Blending, Compositor};
+use crate::filter::{
+    Filter, FilterError, FrameContext, FrameMeta, Matte, MatteEstimator, PostProcessing, Quality,
+    StageTimings,
+};
+use opencv::imgproc::{cvt_color, COLOR_RGB2RGBA};
+use opencv::prelude::*;
+use std::mem;
+use std::time::Instant;
+
+#[derive(Debug)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn Filter>>,
+    /// The matte of the last frame
+    last_pha: Option<Mat>,
+    /// Describes the next frame, see [`Filter::set_frame_meta`]
+    meta: FrameMeta,
+    /// Copy of the frame for the filters to modify when the output is RGBA
+    frame: Mat,
+    /// Turns the frame and matte into RGBA
+    compositor: Compositor,
+    timings: StageTimings,
+}
+
+impl FilterChain {
+    /// Create a chain running `filters` in the given order.
+    pub fn new(filters: Vec<Box<dyn Filter>>) -> FilterChain {
+        FilterChain {
+            filters,
+            last_pha: None,
+            meta: FrameMeta::default(),
+            frame: Mat::default(),
+            compositor: Compositor::default(),
+            timings: StageTimings::default(),
+        }
+    }
+}
+
+impl Filter for FilterChain {
+    fn filter_inplace(&mut self, src_image: &mut Mat, bg_image: &Mat) -> Result<(), FilterError> {
+        // Dropped first, so the estimator can write the new matte to its buffer
+        self.last_pha = None;
+        let mut ctx = FrameContext::new(src_image, Some(bg_image));
+        ctx.meta = self.meta;
+        self.process(&mut ctx)?;
+        self.last_pha = ctx.pha.take();
+        Ok(())
+    }
+
+    fn filter_alpha(&mut self, src_image: &Mat, dst_image: &mut Mat) -> Result<(), FilterError> {
+        // Moved out while the filters borrow it, and put back to reuse its buffer next frame
+        let mut frame = mem::take(&mut self.frame);
+        src_image.copy_to(&mut frame)?;
+        self.last_pha = None;
+        let mut ctx = FrameContext::new(&mut frame, None);
+        ctx.meta = self.meta;
+        self.process(&mut ctx)?;
+        let (fgr, pha) = (ctx.fgr.take(), ctx.pha.take());
+        let start = Instant::now();
+        self.last_pha = match pha {
+            Some(pha) => {
+                let matte = Matte { fgr, pha };
+                self.compositor.to_rgba(&frame, &matte, dst_image)?;
+                self.timings
+                    .record_allocations("composite", self.compositor.allocations());
+                Some(matte.pha)
+            }
+            // None of the filters estimates a matte, so the whole frame is foreground
+            None => {
+                cvt_color(&frame, dst_image, COLOR_RGB2RGBA, 0)?;
+                None
+            }
+        };
+        self.timings.record("composite", start);
+        self.frame = frame;
+        Ok(())
+    }
+
+    fn matte(&self) -> Option<&Mat> {
+        self.last_pha.as_ref()
+    }
+
+    fn timings(&self) -> Option<&StageTimings> {
+        Some(&self.timings)
+    }
+
+    fn set_quality(&mut self, quality: &Quality) {
+        for filter in &mut self.filters {
+            filter.set_quality(quality);
+        }
+    }
+
+    fn set_post_processing(&mut self, post_processing: &PostProcessing) -> Result<(), FilterError> {
+        for filter in &mut self.filters {
+            filter.set_post_processing(post_processing)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        for filter in &mut self.filters {
+            filter.reset();
+        }
+        self.last_pha = None;
+    }
+
+    fn set_frame_meta(&mut self, meta: &FrameMeta) {
+        self.meta = *meta;
+    }
+
+    /// Run the filters over `ctx`. Chains can be nested, the filters of the inner chain share
+    /// the context of the outer one. The matte stays in `ctx` for the following filters.
+    fn process(&mut self, ctx: &mut FrameContext) -> Result<(), FilterError> {
+        self.timings.clear();
+        for filter in &mut self.filters {
+            filter.process(ctx)?;
+            if let Some(timings) = filter.timings() {
+                self.timings.extend(timings);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Estimates the matte of the frame and passes it on to the following filters, without
+/// modifying the frame.
+#[derive(Debug)]
+pub struct MatteStage {
+    estimator: Box<dyn MatteEstimator>,
+    /// The matte of the last frame
+    last_pha: Option<Mat>,
+}
+
+impl MatteStage {
+    pub fn new(estimator: Box<dyn MatteEstimator>) -> MatteStage {
+        MatteStage {
+            estimator,
+            last_pha: None,
+        }
+    }
+}
+
+impl Filter for MatteStage {
+    fn filter_inplace(&mut self, src_image: &mut Mat, bg_image: &Mat) -> Result<(), FilterError> {
+        // Dropped first, so the estimator can write the new matte to its buffer
+        self.last_pha = None;
+        let mut ctx = FrameContext::new(src_image, Some(bg_image));
+        self.process(&mut ctx)?;
+        self.last_pha = ctx.pha.take();
+        Ok(())
+    }
+
+    fn matte(&self) -> Option<&Mat> {
+        self.last_pha.as_ref()
+    }
+
+    fn timings(&self) -> Option<&StageTimings> {
+        self.estimator.timings()
+    }
+
+    fn set_quality(&mut self, quality: &Quality) {
+        self.estimator.set_quality(quality);
+    }
+
+    fn reset(&mut self) {
+        self.estimator.reset();
+        self.last_pha = None;
+    }
+
+    fn process(&mut self, ctx: &mut FrameContext) -> Result<(), FilterError> {
+        let matte = self.estimator.estimate(ctx.frame)?;
+        ctx.pha = Some(matte.pha);
+        ctx.fgr = matte.fgr;
+        Ok(())
+    }
+}
+
+/// Composites the frame onto the background using the matte estimated by an earlier filter.
+/// Frames without a matte or without a background are left as they are.
+#[derive(Debug)]
+pub struct CompositeStage {
+    compositor: Compositor,
+    timings: StageTimings,
+}
+
+impl CompositeStage {
+    pub fn new(blending: Blending) -> CompositeStage {
+        CompositeStage {
+            compositor: Compositor::new(blending),
+            timings: StageTimings::default(),
+        }
+    }
+}
+
+impl Filter for CompositeStage {
+    fn filter_inplace(&mut self, src_image: &mut Mat, bg_image: &Mat) -> Result<(), FilterError> {
+        self.process(&mut FrameContext::new(src_image, Some(bg_image)))
+    }
+
+    fn timings(&self) -> Option<&StageTimings> {
+        Some(&self.timings)
+    }
+
+    fn set_post_processing(&mut self, post_processing: &PostProcessing) -> Result<(), FilterError> {
+        self.compositor.set_blending(post_processing.blending);
+        Ok(())
+    }
+
+    fn process(&mut self, ctx: &mut FrameContext) -> Result<(), FilterError> {
+        self.timings.clear();
+        let (bg_image, pha) = match (&ctx.background, &ctx.pha) {
+            (Some(bg_image), Some(pha)) => (bg_image, pha),
+            _ => return Ok(()),
+        };
+        let start = Instant::now();
+        match &ctx.fgr {
+            Some(fgr) => {
+                self.compositor
+                    .composite(fgr, pha, bg_image, AlphaMode::Straight, ctx.frame)?
+            }
+            // Without a foreground estimate the frame itself is the foreground
+            None => self.compositor.composite_inplace(ctx.frame, pha, bg_image)?,
+        }
+        self.timings.record("composite", start);
+        self.timings
+            .record_allocations("composite", self.compositor.allocations());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{Scalar, CV_32FC1, CV_8UC3};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// A stage remembering the metadata of each frame it saw.
+    #[derive(Debug)]
+    struct MetaRecorder {
+        seen: Arc<Mutex<Vec<FrameMeta>>>,
+    }
+
+    impl Filter for MetaRecorder {
+        fn filter_inplace(
+            &mut self,
+            _src_image: &mut Mat,
+            _bg_image: &Mat,
+        ) -> Result<(), FilterError> {
+            Ok(())
+        }
+
+        fn process(&mut self, ctx: &mut FrameContext) -> Result<(), FilterError> {
+            self.seen.lock().unwrap().push(ctx.meta);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stages_see_the_frame_meta() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = MetaRecorder { seen: seen.clone() };
+        // The recorder is nested, as the stages of a matte filter are
+        let inner = FilterChain::new(vec![Box::new(recorder)]);
+        let mut chain = FilterChain::new(vec![Box::new(inner)]);
+        let meta = FrameMeta {
+            pts: Some(Duration::from_millis(40)),
+            duration: Some(Duration::from_millis(40)),
+            discont: true,
+        };
+        let mut frame = Mat::new_rows_cols_with_default(2, 2, CV_8UC3, Scalar::all(0.0)).unwrap();
+        let bg = frame.clone();
+        let mut rgba = Mat::default();
+
+        chain.set_frame_meta(&meta);
+        chain.filter_inplace(&mut frame, &bg).unwrap();
+        chain.filter_alpha(&bg, &mut rgba).unwrap();
+        assert_eq!(*seen.lock().unwrap(), [meta, meta]);
+    }
+
+    #[test]
+    fn composite_allocations_are_recorded() {
+        let mut stage = CompositeStage::new(Blending::default());
+        let mut frame = Mat::new_rows_cols_with_default(2, 2, CV_8UC3, Scalar::all(0.0)).unwrap();
+        let bg = Mat::new_rows_cols_with_default(2, 2, CV_8UC3, Scalar::all(255.0)).unwrap();
+        let pha = Mat::new_rows_cols_with_default(2, 2, CV_32FC1, Scalar::all(0.5)).unwrap();
+        let mut allocations = Vec::new();
+        for _ in 0..2 {
+            let mut ctx = FrameContext::new(&mut frame, Some(&bg));
+            ctx.pha = Some(pha.clone());
+            stage.process(&mut ctx).unwrap();
+            allocations.push(stage.timings.allocations("composite"));
+        }
+        assert!(allocations[0] > 0);
+        assert_eq!(allocations[1], 0);
+    }
+}
@@ -0,0 +1,239 @@
+//! This filter separates the person from the background by keying out the colour of a green
+//! screen (or any other evenly coloured backdrop) instead of using a model, so it works without
+//! onnxruntime. Pixels are compared by hue, which changes much less than brightness across an
+//! unevenly lit screen. Greyish and dark pixels have no reliable hue and are never keyed out.
+//!
+//! Light reflected by the screen tints the edges of the person in its colour. Spill suppression
+//! desaturates colours close to the key colour, which removes that tint.
+
+use crate::composite::Compositor;
+use crate::filter::{Filter, FilterError, FrameContext, Matte, PostProcessing, StageTimings};
+use opencv::core::{Scalar, Vector, CV_32F, CV_32FC3, CV_8UC3};
+use opencv::imgproc::{cvt_color, COLOR_HSV2RGB, COLOR_RGB2HSV};
+use opencv::prelude::*;
+use std::time::Instant;
+
+/// Saturation below which a pixel is never keyed out, whatever its hue.
+const MIN_SATURATION: f64 = 0.15;
+/// Brightness below which a pixel is never keyed out, whatever its hue.
+const MIN_VALUE: f64 = 0.1;
+/// Width of the ramps above `MIN_SATURATION` and `MIN_VALUE` up to full keying, so the matte
+/// does not jump at the thresholds.
+const THRESHOLD_SOFTNESS: f64 = 0.1;
+/// Hue distance in degrees beyond the soft edge of the key in which spill is still suppressed.
+const SPILL_HUE_RANGE: f64 = 30.0;
+
+/// The hue of the colour `rgb`, given as 0xRRGGBB, in degrees as used by OpenCV for float
+/// images.
+fn hue(rgb: u32) -> f64 {
+    let r = ((rgb >> 16) & 0xff) as f64;
+    let g = ((rgb >> 8) & 0xff) as f64;
+    let b = (rgb & 0xff) as f64;
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    if delta == 0.0 {
+        return 0.0;
+    }
+    let hue = if max == r {
+        60.0 * (g - b) / delta
+    } else if max == g {
+        120.0 + 60.0 * (b - r) / delta
+    } else {
+        240.0 + 60.0 * (r - g) / delta
+    };
+    hue.rem_euclid(360.0)
+}
+
+/// Map `src` linearly so that `one` becomes 1 and `zero` becomes 0, clamping the result to
+/// 0..1. `one` may be larger or smaller than `zero`.
+fn ramp(src: &Mat, one: f64, zero: f64) -> Result<Mat, FilterError> {
+    let scale = 1.0 / (one - zero);
+    let mut linear = Mat::default();
+    src.convert_to(&mut linear, CV_32F, scale, -zero * scale)?;
+    let mut clamped = Mat::default();
+    opencv::core::min(&linear, &Scalar::all(1.0), &mut clamped)?;
+    opencv::core::max(&clamped, &Scalar::all(0.0), &mut linear)?;
+    Ok(linear)
+}
+
+/// The colour keyed out and how.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChromaKey {
+    /// The key colour as 0xRRGGBB
+    pub color: u32,
+    /// Hue distance in degrees up to which pixels are keyed out completely
+    pub tolerance: f64,
+    /// Hue distance in degrees beyond the tolerance over which the matte fades in
+    pub softness: f64,
+    /// How much spill is suppressed, from 0 for not at all to 1 for completely
+    pub spill_suppression: f64,
+}
+
+#[derive(Debug)]
+pub struct ChromaKeyFilter {
+    /// Hue of the key colour in degrees
+    key_hue: f64,
+    /// Hue distance in degrees up to which pixels are keyed out completely
+    tolerance: f64,
+    /// Hue distance in degrees beyond the tolerance over which the matte fades in
+    softness: f64,
+    /// How much spill is suppressed, from 0 for not at all to 1 for completely
+    spill_suppression: f64,
+    compositor: Compositor,
+    /// The matte of the last frame
+    last_pha: Option<Mat>,
+    timings: StageTimings,
+}
+
+impl ChromaKeyFilter {
+    pub fn new(key: &ChromaKey) -> ChromaKeyFilter {
+        let mut filter = ChromaKeyFilter {
+            key_hue: 0.0,
+            tolerance: 0.0,
+            softness: 1.0,
+            spill_suppression: 0.0,
+            compositor: Compositor::default(),
+            last_pha: None,
+            timings: StageTimings::default(),
+        };
+        filter.set_key(key);
+        filter
+    }
+
+    /// Key out the colour described by `key` from the next frame on.
+    pub fn set_key(&mut self, key: &ChromaKey) {
+        self.key_hue = hue(key.color);
+        self.tolerance = key.tolerance;
+        // A hard edge would divide by zero in the ramp
+        self.softness = key.softness.max(1.0);
+        self.spill_suppression = key.spill_suppression.clamp(0.0, 1.0);
+    }
+
+    /// Estimate the matte of `frame` and suppress the spill in it.
+    fn key(&mut self, frame: &mut Mat) -> Result<Mat, FilterError> {
+        let start = Instant::now();
+        let mut rgb = Mat::default();
+        frame.convert_to(&mut rgb, CV_32FC3, 1.0 / 255.0, 0.0)?;
+        let mut hsv = Mat::default();
+        cvt_color(&rgb, &mut hsv, COLOR_RGB2HSV, 0)?;
+        let mut channels = Vector::<Mat>::new();
+        opencv::core::split(&hsv, &mut channels)?;
+        let (hue, saturation, value) = (channels.get(0)?, channels.get(1)?, channels.get(2)?);
+
+        // Distance of each hue to the key hue, the short way round the colour wheel
+        let mut difference = Mat::default();
+        opencv::core::absdiff(&hue, &Scalar::all(self.key_hue), &mut difference)?;
+        let mut other_way = Mat::default();
+        opencv::core::subtract(
+            &Scalar::all(360.0),
+            &difference,
+            &mut other_way,
+            &opencv::core::no_array(),
+            -1,
+        )?;
+        let mut distance = Mat::default();
+        opencv::core::min(&difference, &other_way, &mut distance)?;
+
+        let edge = self.tolerance + self.softness;
+        let mut keyed = Mat::default();
+        opencv::core::multiply(
+            &ramp(&distance, self.tolerance, edge)?,
+            &ramp(&saturation, MIN_SATURATION + THRESHOLD_SOFTNESS, MIN_SATURATION)?,
+            &mut keyed,
+            1.0,
+            -1,
+        )?;
+        let mut background = Mat::default();
+        opencv::core::multiply(
+            &keyed,
+            &ramp(&value, MIN_VALUE + THRESHOLD_SOFTNESS, MIN_VALUE)?,
+            &mut background,
+            1.0,
+            -1,
+        )?;
+        let mut pha = Mat::default();
+        opencv::core::subtract(
+            &Scalar::all(1.0),
+            &background,
+            &mut pha,
+            &opencv::core::no_array(),
+            -1,
+        )?;
+        self.timings.record("key", start);
+
+        if self.spill_suppression > 0.0 {
+            let start = Instant::now();
+            let spill = ramp(&distance, edge, edge + SPILL_HUE_RANGE)?;
+            let mut factor = Mat::default();
+            spill.convert_to(&mut factor, CV_32F, -self.spill_suppression, 1.0)?;
+            let mut desaturated = Mat::default();
+            opencv::core::multiply(&saturation, &factor, &mut desaturated, 1.0, -1)?;
+            channels.set(1, desaturated)?;
+            opencv::core::merge(&channels, &mut hsv)?;
+            cvt_color(&hsv, &mut rgb, COLOR_HSV2RGB, 0)?;
+            rgb.convert_to(frame, CV_8UC3, 255.0, 0.0)?;
+            self.timings.record("spill", start);
+        }
+        Ok(pha)
+    }
+}
+
+impl Filter for ChromaKeyFilter {
+    fn filter_inplace(&mut self, src_image: &mut Mat, bg_image: &Mat) -> Result<(), FilterError> {
+        if *(src_image.mat_size()) != *(bg_image.mat_size()) {
+            return Err(FilterError::Other(format!(
+                "Camera image has size {:?} but background image has size {:?}.",
+                src_image.mat_size(),
+                bg_image.mat_size()
+            )));
+        }
+        self.timings.clear();
+        let pha = self.key(src_image)?;
+        let start = Instant::now();
+        self.compositor.composite_inplace(src_image, &pha, bg_image)?;
+        self.timings.record("composite", start);
+        self.last_pha = Some(pha);
+        Ok(())
+    }
+
+    fn filter_alpha(&mut self, src_image: &Mat, dst_image: &mut Mat) -> Result<(), FilterError> {
+        self.timings.clear();
+        let mut frame = src_image.try_clone()?;
+        let pha = self.key(&mut frame)?;
+        let start = Instant::now();
+        let matte = Matte {
+            fgr: None,
+            pha: Mat::copy(&pha)?,
+        };
+        self.compositor.to_rgba(&frame, &matte, dst_image)?;
+        self.timings.record("composite", start);
+        self.last_pha = Some(pha);
+        Ok(())
+    }
+
+    fn matte(&self) -> Option<&Mat> {
+        self.last_pha.as_ref()
+    }
+
+    fn timings(&self) -> Option<&StageTimings> {
+        Some(&self.timings)
+    }
+
+    fn set_post_processing(&mut self, post_processing: &PostProcessing) -> Result<(), FilterError> {
+        self.set_key(&post_processing.key);
+        self.compositor.set_blending(post_processing.blending);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.last_pha = None;
+    }
+
+    /// Pass the matte on to the following filters, with the spill suppressed in the frame.
+    fn process(&mut self, ctx: &mut FrameContext) -> Result<(), FilterError> {
+        self.timings.clear();
+        ctx.pha = Some(self.key(ctx.frame)?);
+        ctx.fgr = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,712 @@
+//! Parsing of the command line arguments of the fakecam binary.
+
+use crate::background::FitMode;
+use crate::blurfilter::BlurKind;
+use crate::plugin::Mode;
+use crate::registry;
+use quick_error::quick_error;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+Usage: fakecam [OPTIONS]
+       fakecam process [OPTIONS] <INPUT> <OUTPUT>
+       fakecam bench [OPTIONS]
+       fakecam --list-filters
+
+Reads frames from a camera, replaces or blurs the background behind the person and writes the
+result to a (virtual) video device, e.g. one created by v4l2loopback.
+
+The process subcommand does the same for a recorded video file instead, writing the result to
+an MP4 file with H.264 video and AAC audio. It runs as fast as possible and reports progress.
+
+The bench subcommand runs the filter on synthetic frames, or the frames of a recorded video, and
+reports how long each stage took and the framerate achieved.
+
+Options:
+  -i, --input <DEVICE>        Camera device to read from [default: /dev/video0]
+  -o, --output <DEVICE>       Video device to write to [default: /dev/video4]
+      --resolution <WxH>      Resolution to request from the camera, e.g. 1280x720
+      --framerate <FPS>       Framerate to request from the camera
+      --list-filters          List the filters compiled in and exit
+  -h, --help                  Print this help
+
+Bench options:
+      --frames <N>                 Number of frames to measure per configuration [default: 100]
+      --resolutions <WxH,...>      Frame sizes to measure [default: 640x360,1280x720,1920x1080]
+      --downsample-ratios <R,...>  Downsample ratios to measure [default: 0.25]
+      --video <FILE>               Use the frames of a video instead of synthetic ones
+
+Filter options, for all commands:
+      --filter <NAME>         Filter to use, chosen from the mode and model config by default
+  -m, --model <FILE>          ONNX matting model to use
+      --model-config <FILE>   Config of a segmentation model, e.g. MODNet, if it is not RVM
+  -b, --background <FILE>     Image or video to use as background in replace mode
+      --background-fit <FIT>  One of stretch, cover or contain [default: cover]
+      --mode <MODE>           One of noop, blur, replace or alpha [default: replace]
+      --blur-kind <KIND>      One of gaussian or bokeh [default: gaussian]
+      --blur-radius <PX>      Radius of the background blur in blur mode [default: 15]
+      --async-inference       Run the model on a worker thread, so a slow model does not slow
+                              down the video. The matte then lags behind the video a little.
+      --max-inference-interval <N>
+                              Run the model at least every N frames and move the matte along
+                              with the motion in between, saving CPU time [default: 1]
+      --target-fps <FPS>      Lower the quality of the matte while the filter is too slow for
+                              this framerate, and raise it again once it is fast enough
+      --key-color <RRGGBB>    Colour of the green screen for the chroma filter [default: 00ff00]
+";
+
+const DEFAULT_INPUT: &str = "/dev/video0";
+const DEFAULT_OUTPUT: &str = "/dev/video4";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum CliError {
+        UnknownArgument(arg: String) {
+            display("Unknown argument '{}'", arg)
+        }
+        MissingValue(arg: String) {
+            display("Missing value for '{}'", arg)
+        }
+        MissingArgument(name: &'static str) {
+            display("Missing argument {}", name)
+        }
+        InvalidValue(arg: String, value: String, reason: String) {
+            display("Invalid value '{}' for '{}': {}", value, arg, reason)
+        }
+        MissingFile(what: &'static str, path: PathBuf) {
+            display("{} {} does not exist", what, path.display())
+        }
+        MissingModel(filter: String) {
+            display("Filter {} requires a model, pass one with --model", filter)
+        }
+    }
+}
+
+/// The resolution and framerate requested from the camera. Fields which are `None` are left
+/// for GStreamer to negotiate.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureFormat {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub framerate: Option<i32>,
+}
+
+/// How the frames are filtered, shared by all commands.
+#[derive(Debug, Clone)]
+pub struct FilterOptions {
+    /// Name of the filter in the registry, derived from the mode if it is `None`
+    pub name: Option<String>,
+    pub model: Option<PathBuf>,
+    pub model_config: Option<PathBuf>,
+    pub background: Option<PathBuf>,
+    pub background_fit: FitMode,
+    pub mode: Mode,
+    pub blur_kind: BlurKind,
+    /// Radius of the background blur in pixels, the element's default if `None`
+    pub blur_radius: Option<u32>,
+    pub async_inference: bool,
+    pub max_inference_interval: u32,
+    pub target_fps: Option<f64>,
+    /// Colour keyed out by the chroma filter as 0xRRGGBB
+    pub key_color: Option<u32>,
+}
+
+impl Default for FilterOptions {
+    fn default() -> Self {
+        FilterOptions {
+            name: None,
+            model: None,
+            model_config: None,
+            background: None,
+            background_fit: FitMode::Cover,
+            mode: Mode::Replace,
+            blur_kind: BlurKind::Gaussian,
+            blur_radius: None,
+            async_inference: false,
+            max_inference_interval: 1,
+            target_fps: None,
+            key_color: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub format: CaptureFormat,
+    pub filter: FilterOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessOptions {
+    /// The recorded video file
+    pub input: PathBuf,
+    /// The MP4 file to write
+    pub output: PathBuf,
+    pub filter: FilterOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Number of frames measured for each resolution and downsample ratio
+    pub frames: u32,
+    pub resolutions: Vec<(i32, i32)>,
+    pub downsample_ratios: Vec<f64>,
+    /// Video to take the frames from, synthetic frames are used if this is `None`
+    pub video: Option<PathBuf>,
+    pub filter: FilterOptions,
+}
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// Print the usage and exit
+    Help,
+    /// Run the live camera pipeline
+    Run(Options),
+    /// Process a video file
+    Process(ProcessOptions),
+    /// Measure how fast the filter is
+    Bench(BenchOptions),
+    /// Print the filters compiled in and exit
+    ListFilters,
+}
+
+fn next_value<I: Iterator<Item = String>>(args: &mut I, arg: &str) -> Result<String, CliError> {
+    args.next().ok_or_else(|| CliError::MissingValue(String::from(arg)))
+}
+
+fn parse_resolution(arg: &str, value: &str) -> Result<(i32, i32), CliError> {
+    let invalid = |reason: &str| {
+        CliError::InvalidValue(String::from(arg), String::from(value), String::from(reason))
+    };
+    let (width, height) = value
+        .split_once('x')
+        .ok_or_else(|| invalid("expected WIDTHxHEIGHT"))?;
+    let width: i32 = width.parse().map_err(|_| invalid("width is not a number"))?;
+    let height: i32 = height.parse().map_err(|_| invalid("height is not a number"))?;
+    if width <= 0 || height <= 0 {
+        return Err(invalid("width and height must be positive"));
+    }
+    Ok((width, height))
+}
+
+fn parse_positive(arg: &str, value: &str) -> Result<i32, CliError> {
+    match value.parse::<i32>() {
+        Ok(number) if number > 0 => Ok(number),
+        _ => Err(CliError::InvalidValue(
+            String::from(arg),
+            String::from(value),
+            String::from("expected a positive number"),
+        )),
+    }
+}
+
+fn parse_ratio(arg: &str, value: &str) -> Result<f64, CliError> {
+    match value.parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 && ratio <= 1.0 => Ok(ratio),
+        _ => Err(CliError::InvalidValue(
+            String::from(arg),
+            String::from(value),
+            String::from("expected a number greater than 0 and at most 1"),
+        )),
+    }
+}
+
+fn ensure_exists(what: &'static str, path: &Path) -> Result<(), CliError> {
+    if path.exists() {
+        Ok(())
+    } else {
+        Err(CliError::MissingFile(what, path.to_path_buf()))
+    }
+}
+
+/// Parse `arg` if it is one of the filter options. Returns whether it was.
+fn parse_filter_option<I: Iterator<Item = String>>(
+    arg: &str,
+    args: &mut I,
+    options: &mut FilterOptions,
+) -> Result<bool, CliError> {
+    match arg {
+        "--filter" => {
+            let value = next_value(args, arg)?;
+            if registry::find(&value).is_none() {
+                let reason = format!("the filters available are {}", registry::filter_names());
+                return Err(CliError::InvalidValue(String::from(arg), value, reason));
+            }
+            options.name = Some(value);
+        }
+        "-m" | "--model" => options.model = Some(next_value(args, arg)?.into()),
+        "--model-config" => options.model_config = Some(next_value(args, arg)?.into()),
+        "-b" | "--background" => options.background = Some(next_value(args, arg)?.into()),
+        "--background-fit" => {
+            let value = next_value(args, arg)?;
+            options.background_fit = value
+                .parse()
+                .map_err(|reason| CliError::InvalidValue(String::from(arg), value, reason))?;
+        }
+        "--mode" => {
+            let value = next_value(args, arg)?;
+            options.mode = value
+                .parse()
+                .map_err(|reason| CliError::InvalidValue(String::from(arg), value, reason))?;
+        }
+        "--blur-kind" => {
+            let value = next_value(args, arg)?;
+            options.blur_kind = value
+                .parse()
+                .map_err(|reason| CliError::InvalidValue(String::from(arg), value, reason))?;
+        }
+        "--blur-radius" => {
+            let value = next_value(args, arg)?;
+            match value.parse::<u32>() {
+                Ok(radius) if (1..=255).contains(&radius) => options.blur_radius = Some(radius),
+                _ => {
+                    return Err(CliError::InvalidValue(
+                        String::from(arg),
+                        value,
+                        String::from("expected a radius from 1 to 255 pixels"),
+                    ))
+                }
+            }
+        }
+        "--async-inference" => options.async_inference = true,
+        "--target-fps" => {
+            let value = next_value(args, arg)?;
+            match value.parse::<f64>() {
+                Ok(fps) if fps > 0.0 && fps <= 240.0 => options.target_fps = Some(fps),
+                _ => {
+                    return Err(CliError::InvalidValue(
+                        String::from(arg),
+                        value,
+                        String::from("expected a framerate greater than 0 and at most 240"),
+                    ))
+                }
+            }
+        }
+        "--key-color" => {
+            let value = next_value(args, arg)?;
+            let hex = value.trim_start_matches('#');
+            match u32::from_str_radix(hex, 16) {
+                Ok(color) if hex.len() == 6 => options.key_color = Some(color),
+                _ => {
+                    return Err(CliError::InvalidValue(
+                        String::from(arg),
+                        value,
+                        String::from("expected a colour as RRGGBB in hexadecimal"),
+                    ))
+                }
+            }
+        }
+        "--max-inference-interval" => {
+            let value = next_value(args, arg)?;
+            match value.parse::<u32>() {
+                Ok(interval) if (1..=30).contains(&interval) => {
+                    options.max_inference_interval = interval;
+                }
+                _ => {
+                    return Err(CliError::InvalidValue(
+                        String::from(arg),
+                        value,
+                        String::from("expected a number of frames from 1 to 30"),
+                    ))
+                }
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn validate_filter_options(options: &FilterOptions) -> Result<(), CliError> {
+    let name = match &options.name {
+        Some(name) => name.as_str(),
+        None => registry::default_filter(options.mode, options.model_config.is_some()),
+    };
+    let needs_model = registry::find(name).map_or(false, |info| info.needs_model);
+    match &options.model {
+        Some(model) => ensure_exists("Model file", model)?,
+        None if needs_model => return Err(CliError::MissingModel(String::from(name))),
+        None => (),
+    }
+    if let Some(model_config) = &options.model_config {
+        ensure_exists("Model config", model_config)?;
+    }
+    if let Some(background) = &options.background {
+        ensure_exists("Background", background)?;
+    }
+    Ok(())
+}
+
+/// Parse the command line arguments, excluding the program name.
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Command, CliError> {
+    let mut args = args.into_iter().peekable();
+    match args.peek().map(String::as_str) {
+        Some("process") => {
+            args.next();
+            return parse_process(args);
+        }
+        Some("bench") => {
+            args.next();
+            return parse_bench(args);
+        }
+        _ => (),
+    }
+    let mut options = Options {
+        input: PathBuf::from(DEFAULT_INPUT),
+        output: PathBuf::from(DEFAULT_OUTPUT),
+        format: CaptureFormat::default(),
+        filter: FilterOptions::default(),
+    };
+
+    while let Some(arg) = args.next() {
+        if parse_filter_option(&arg, &mut args, &mut options.filter)? {
+            continue;
+        }
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--list-filters" => return Ok(Command::ListFilters),
+            "-i" | "--input" => options.input = next_value(&mut args, &arg)?.into(),
+            "-o" | "--output" => options.output = next_value(&mut args, &arg)?.into(),
+            "--resolution" => {
+                let (width, height) = parse_resolution(&arg, &next_value(&mut args, &arg)?)?;
+                options.format.width = Some(width);
+                options.format.height = Some(height);
+            }
+            "--framerate" => {
+                let framerate = parse_positive(&arg, &next_value(&mut args, &arg)?)?;
+                options.format.framerate = Some(framerate);
+            }
+            _ => return Err(CliError::UnknownArgument(arg)),
+        }
+    }
+
+    ensure_exists("Input device", &options.input)?;
+    ensure_exists("Output device", &options.output)?;
+    validate_filter_options(&options.filter)?;
+    Ok(Command::Run(options))
+}
+
+/// Parse the arguments of the process subcommand.
+fn parse_process<I: Iterator<Item = String>>(mut args: I) -> Result<Command, CliError> {
+    let mut filter = FilterOptions::default();
+    let mut files = Vec::new();
+    while let Some(arg) = args.next() {
+        if parse_filter_option(&arg, &mut args, &mut filter)? {
+            continue;
+        }
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            _ if !arg.starts_with('-') && files.len() < 2 => files.push(PathBuf::from(arg)),
+            _ => return Err(CliError::UnknownArgument(arg)),
+        }
+    }
+
+    let mut files = files.into_iter();
+    let input = files.next().ok_or(CliError::MissingArgument("<INPUT>"))?;
+    let output = files.next().ok_or(CliError::MissingArgument("<OUTPUT>"))?;
+    ensure_exists("Input file", &input)?;
+    validate_filter_options(&filter)?;
+    Ok(Command::Process(ProcessOptions {
+        input,
+        output,
+        filter,
+    }))
+}
+
+/// Parse the arguments of the bench subcommand.
+fn parse_bench<I: Iterator<Item = String>>(mut args: I) -> Result<Command, CliError> {
+    let mut options = BenchOptions {
+        frames: 100,
+        resolutions: vec![(640, 360), (1280, 720), (1920, 1080)],
+        downsample_ratios: vec![0.25],
+        video: None,
+        filter: FilterOptions::default(),
+    };
+    while let Some(arg) = args.next() {
+        if parse_filter_option(&arg, &mut args, &mut options.filter)? {
+            continue;
+        }
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--frames" => {
+                options.frames = parse_positive(&arg, &next_value(&mut args, &arg)?)? as u32
+            }
+            "--resolutions" => {
+                options.resolutions = next_value(&mut args, &arg)?
+                    .split(',')
+                    .map(|value| parse_resolution(&arg, value.trim()))
+                    .collect::<Result<_, _>>()?;
+            }
+            "--downsample-ratios" => {
+                options.downsample_ratios = next_value(&mut args, &arg)?
+                    .split(',')
+                    .map(|value| parse_ratio(&arg, value.trim()))
+                    .collect::<Result<_, _>>()?;
+            }
+            "--video" => options.video = Some(next_value(&mut args, &arg)?.into()),
+            _ => return Err(CliError::UnknownArgument(arg)),
+        }
+    }
+
+    if let Some(video) = &options.video {
+        ensure_exists("Video", video)?;
+    }
+    validate_filter_options(&options.filter)?;
+    Ok(Command::Bench(options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, CliError> {
+        parse(args.iter().map(|arg| String::from(*arg)))
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let cases: &[(&[&str], &str)] = &[
+            (&["--foo"], "Unknown argument '--foo'"),
+            (&["-i"], "Missing value for '-i'"),
+            (&["--mode", "green"], "Invalid value 'green' for '--mode'"),
+            (
+                &["--background-fit", "fill"],
+                "Invalid value 'fill' for '--background-fit'",
+            ),
+            (
+                &["--blur-kind", "box"],
+                "Invalid value 'box' for '--blur-kind'",
+            ),
+            (
+                &["--blur-radius", "0"],
+                "Invalid value '0' for '--blur-radius'",
+            ),
+            (
+                &["--blur-radius", "256"],
+                "Invalid value '256' for '--blur-radius'",
+            ),
+            (
+                &["--filter", "magic"],
+                "Invalid value 'magic' for '--filter'",
+            ),
+            (
+                &["--resolution", "1280"],
+                "Invalid value '1280' for '--resolution'",
+            ),
+            (
+                &["--resolution", "0x720"],
+                "Invalid value '0x720' for '--resolution'",
+            ),
+            (
+                &["--framerate", "-5"],
+                "Invalid value '-5' for '--framerate'",
+            ),
+            (
+                &["--target-fps", "0"],
+                "Invalid value '0' for '--target-fps'",
+            ),
+            (
+                &["--target-fps", "500"],
+                "Invalid value '500' for '--target-fps'",
+            ),
+            (
+                &["--key-color", "0f0"],
+                "Invalid value '0f0' for '--key-color'",
+            ),
+            (
+                &["--key-color", "gggggg"],
+                "Invalid value 'gggggg' for '--key-color'",
+            ),
+            (
+                &["--max-inference-interval", "0"],
+                "Invalid value '0' for '--max-inference-interval'",
+            ),
+            (
+                &["--max-inference-interval", "31"],
+                "Invalid value '31' for '--max-inference-interval'",
+            ),
+            (
+                &["-i", "/nonexistent/video0"],
+                "Input device /nonexistent/video0 does not exist",
+            ),
+            (
+                &[
+                    "-i",
+                    "/",
+                    "-o",
+                    "/",
+                    "--mode",
+                    "noop",
+                    "-b",
+                    "/nonexistent.png",
+                ],
+                "Background /nonexistent.png does not exist",
+            ),
+            (&["process"], "Missing argument <INPUT>"),
+            (&["process", "/"], "Missing argument <OUTPUT>"),
+            (
+                &["process", "a.mp4", "b.mp4", "c.mp4"],
+                "Unknown argument 'c.mp4'",
+            ),
+            (
+                &["process", "/nonexistent.mp4", "out.mp4", "--mode", "noop"],
+                "Input file /nonexistent.mp4 does not exist",
+            ),
+            (
+                &["bench", "--frames", "0"],
+                "Invalid value '0' for '--frames'",
+            ),
+            (
+                &["bench", "--resolutions", "640x360,wide"],
+                "Invalid value 'wide' for '--resolutions'",
+            ),
+            (
+                &["bench", "--downsample-ratios", "0.25,2"],
+                "Invalid value '2' for '--downsample-ratios'",
+            ),
+            (
+                &["bench", "--mode", "noop", "--video", "/nonexistent.mp4"],
+                "Video /nonexistent.mp4 does not exist",
+            ),
+            (
+                &["bench", "--resolution", "640x360"],
+                "Unknown argument '--resolution'",
+            ),
+        ];
+        for (args, expected) in cases {
+            match parse_args(args) {
+                Ok(command) => panic!("{:?} parsed as {:?}", args, command),
+                Err(e) => assert!(e.to_string().starts_with(expected), "{:?}: {}", args, e),
+            }
+        }
+    }
+
+    #[cfg(feature = "rvm")]
+    #[test]
+    fn model_filters_need_a_model() {
+        for args in &[
+            &["-i", "/", "-o", "/"][..],
+            &["-i", "/", "-o", "/", "--mode", "blur"],
+            &["bench", "--filter", "segmentation"],
+        ] {
+            assert!(
+                matches!(parse_args(args), Err(CliError::MissingModel(_))),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn help_and_list_filters() {
+        let cases: &[&[&str]] = &[
+            &["-h"],
+            &["--help"],
+            &["process", "--help"],
+            &["bench", "-h"],
+        ];
+        for args in cases {
+            assert!(matches!(parse_args(args), Ok(Command::Help)), "{:?}", args);
+        }
+        assert!(matches!(
+            parse_args(&["--list-filters"]),
+            Ok(Command::ListFilters)
+        ));
+    }
+
+    #[test]
+    fn run_options() {
+        let args = [
+            "-i",
+            "/",
+            "-o",
+            "/",
+            "--resolution",
+            "1280x720",
+            "--framerate",
+            "30",
+            "--filter",
+            "chroma",
+            "--mode",
+            "blur",
+            "--blur-kind",
+            "bokeh",
+            "--blur-radius",
+            "21",
+            "--background-fit",
+            "contain",
+            "--key-color",
+            "#0000ff",
+            "--async-inference",
+            "--max-inference-interval",
+            "3",
+            "--target-fps",
+            "24",
+        ];
+        let options = match parse_args(&args) {
+            Ok(Command::Run(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(options.input, Path::new("/"));
+        assert_eq!(options.output, Path::new("/"));
+        assert_eq!(options.format.width, Some(1280));
+        assert_eq!(options.format.height, Some(720));
+        assert_eq!(options.format.framerate, Some(30));
+        let filter = options.filter;
+        assert_eq!(filter.name.as_deref(), Some("chroma"));
+        assert_eq!(filter.mode, Mode::Blur);
+        assert_eq!(filter.blur_kind, BlurKind::Bokeh);
+        assert_eq!(filter.blur_radius, Some(21));
+        assert_eq!(filter.background_fit, FitMode::Contain);
+        assert_eq!(filter.key_color, Some(0x0000ff));
+        assert!(filter.async_inference);
+        assert_eq!(filter.max_inference_interval, 3);
+        assert_eq!(filter.target_fps, Some(24.0));
+    }
+
+    #[test]
+    fn process_options() {
+        let options = match parse_args(&["process", "--mode", "noop", "/", "out.mp4"]) {
+            Ok(Command::Process(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(options.input, Path::new("/"));
+        assert_eq!(options.output, Path::new("out.mp4"));
+        assert_eq!(options.filter.mode, Mode::Noop);
+    }
+
+    #[test]
+    fn bench_options() {
+        let args = [
+            "bench",
+            "--mode",
+            "noop",
+            "--frames",
+            "10",
+            "--resolutions",
+            "640x360, 1280x720",
+            "--downsample-ratios",
+            "0.25,0.5",
+        ];
+        let options = match parse_args(&args) {
+            Ok(Command::Bench(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(options.frames, 10);
+        assert_eq!(options.resolutions, vec![(640, 360), (1280, 720)]);
+        assert_eq!(options.downsample_ratios, vec![0.25, 0.5]);
+        assert_eq!(options.video, None);
+
+        let options = match parse_args(&["bench", "--mode", "noop"]) {
+            Ok(Command::Bench(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(options.frames, 100);
+        assert_eq!(
+            options.resolutions,
+            vec![(640, 360), (1280, 720), (1920, 1080)]
+        );
+        assert_eq!(options.downsample_ratios, vec![0.25]);
+    }
+}
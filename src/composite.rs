@@ -0,0 +1,656 @@
+//! Compositing of a foreground onto a background using an alpha matte, shared by all filters.
+//!
+//! The foreground and background may be CV_8UC3 with values in 0..255 or CV_32FC3 with values
+//! in 0..1, the matte CV_8UC1 or CV_32FC1 likewise. The result is always CV_8UC3. A
+//! [`Compositor`] keeps its intermediate images between frames, so once the first frame of a
+//! size has been composited, compositing does not allocate any more. How many of its buffers
+//! the last call allocated is told by [`Compositor::allocations`].
+//!
+//! A person pasted onto a new background tends to look cut out, because the lighting of the
+//! background does not match. Two optional steps help with that:
+//! * Light wrap lets the blurred background bleed onto the edges of the person, as light
+//!   from behind would.
+//! * Harmonisation matches the mean and spread of the colours of the person to those of the
+//!   background in Lab space, so brightness and tint are similar.
+//!
+//! Both only apply to straight foregrounds.
+
+use crate::filter::{FilterError, Matte};
+use opencv::core::{
+    Scalar, Size, BORDER_DEFAULT, CMP_GT, CV_32F, CV_32FC1, CV_32FC3, CV_8U, CV_8UC3,
+};
+use opencv::imgproc::{
+    cvt_color, gaussian_blur, COLOR_GRAY2RGB, COLOR_Lab2RGB, COLOR_RGB2Lab, COLOR_RGB2RGBA,
+};
+use opencv::prelude::*;
+use std::mem;
+
+/// Limits of the factor the spread of the foreground colours is scaled by when harmonising, so
+/// e.g. a plain background does not wash out the person.
+const MIN_HARMONIZE_SCALE: f64 = 0.5;
+const MAX_HARMONIZE_SCALE: f64 = 2.0;
+
+/// How the colour of the foreground relates to the matte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// The foreground colour is independent of the matte, as estimated by RVM or as the
+    /// camera frame itself
+    Straight,
+    /// The foreground colour has already been multiplied with the matte
+    Premultiplied,
+}
+
+/// The optional steps making the foreground blend in with the background.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Blending {
+    /// How strongly the background bleeds onto the edges of the person, 0 disables light wrap
+    pub light_wrap: f64,
+    /// How far in pixels the background bleeds onto the person
+    pub light_wrap_radius: u32,
+    /// How strongly the colours of the person are matched to the background, 0 disables this
+    pub harmonize: f64,
+}
+
+impl Default for Blending {
+    fn default() -> Self {
+        Blending {
+            light_wrap: 0.0,
+            light_wrap_radius: 10,
+            harmonize: 0.0,
+        }
+    }
+}
+
+/// Convert `src` to float with values in 0..1, writing to `dst`.
+fn to_unit(src: &Mat, dst: &mut Mat, float_type: i32) -> Result<(), FilterError> {
+    let scale = if src.depth() == CV_32F { 1.0 } else { 1.0 / 255.0 };
+    src.convert_to(dst, float_type, scale, 0.0)?;
+    Ok(())
+}
+
+/// A gaussian blur with the given radius in pixels.
+fn blur(src: &Mat, dst: &mut Mat, radius: u32) -> Result<(), FilterError> {
+    // A kernel of 2r+1 covers about three standard deviations on each side
+    let size = (2 * radius + 1) as i32;
+    let sigma = radius as f64 / 3.0;
+    gaussian_blur(src, dst, Size::new(size, size), sigma, sigma, BORDER_DEFAULT)?;
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct Compositor {
+    blending: Blending,
+    /// The foreground as CV_32FC3
+    fgr: Mat,
+    /// The background as CV_32FC3
+    bg: Mat,
+    /// The matte as CV_32FC1 and repeated for each channel
+    pha: Mat,
+    pha_rgb: Mat,
+    /// One minus the matte for each channel
+    pha_inv: Mat,
+    /// The foreground and background weighted by the matte
+    fgr_part: Mat,
+    bg_part: Mat,
+    blended: Mat,
+    /// Intermediate images of light wrap and harmonisation
+    scratch: Mat,
+    scratch_rgb: Mat,
+    fgr_lab: Mat,
+    bg_lab: Mat,
+    /// Pixels counted as foreground when harmonising
+    mask: Mat,
+    /// Per channel mean and standard deviation of the foreground and background
+    fgr_mean: Mat,
+    fgr_std: Mat,
+    bg_mean: Mat,
+    bg_std: Mat,
+    /// The foreground and matte as CV_8U when converting to RGBA
+    fgr_8u: Mat,
+    pha_8u: Mat,
+    /// How many buffers the last call allocated, including the one of the result
+    allocations: usize,
+}
+
+/// The number of buffers in `after` which are not in `before`, i.e. which were allocated in
+/// between. Buffers which were only swapped are not counted.
+fn count_allocated(before: &[*const u8], after: &[*const u8]) -> usize {
+    after
+        .iter()
+        .filter(|data| !data.is_null() && !before.contains(data))
+        .count()
+}
+
+impl Compositor {
+    pub fn new(blending: Blending) -> Compositor {
+        Compositor {
+            blending,
+            ..Compositor::default()
+        }
+    }
+
+    /// Blend with `blending` from the next frame on.
+    pub fn set_blending(&mut self, blending: Blending) {
+        self.blending = blending;
+    }
+
+    /// The number of buffers the last call to the compositor allocated, 0 once the compositor
+    /// has seen a frame of the size.
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    /// The data of all buffers, to tell which of them a call allocated.
+    fn buffer_data(&self, dst: &Mat) -> [*const u8; 20] {
+        [
+            self.fgr.data(),
+            self.bg.data(),
+            self.pha.data(),
+            self.pha_rgb.data(),
+            self.pha_inv.data(),
+            self.fgr_part.data(),
+            self.bg_part.data(),
+            self.blended.data(),
+            self.scratch.data(),
+            self.scratch_rgb.data(),
+            self.fgr_lab.data(),
+            self.bg_lab.data(),
+            self.mask.data(),
+            self.fgr_mean.data(),
+            self.fgr_std.data(),
+            self.bg_mean.data(),
+            self.bg_std.data(),
+            self.fgr_8u.data(),
+            self.pha_8u.data(),
+            dst.data(),
+        ]
+    }
+
+    /// Composite `fgr` onto `bg` using the matte `pha` and write the result to `dst`.
+    pub fn composite(
+        &mut self,
+        fgr: &Mat,
+        pha: &Mat,
+        bg: &Mat,
+        alpha_mode: AlphaMode,
+        dst: &mut Mat,
+    ) -> Result<(), FilterError> {
+        let before = self.buffer_data(dst);
+        to_unit(fgr, &mut self.fgr, CV_32FC3)?;
+        self.blend(pha, bg, alpha_mode)?;
+        self.blended.convert_to(dst, CV_8UC3, 255.0, 0.0)?;
+        self.allocations = count_allocated(&before, &self.buffer_data(dst));
+        Ok(())
+    }
+
+    /// Composite `frame` onto `bg` using the matte `pha`, replacing the frame with the result.
+    /// The frame is taken to be straight colour, like a camera frame.
+    pub fn composite_inplace(
+        &mut self,
+        frame: &mut Mat,
+        pha: &Mat,
+        bg: &Mat,
+    ) -> Result<(), FilterError> {
+        let before = self.buffer_data(frame);
+        to_unit(frame, &mut self.fgr, CV_32FC3)?;
+        self.blend(pha, bg, AlphaMode::Straight)?;
+        self.blended.convert_to(frame, CV_8UC3, 255.0, 0.0)?;
+        self.allocations = count_allocated(&before, &self.buffer_data(frame));
+        Ok(())
+    }
+
+    /// Combine the foreground of `matte` and its alpha into the RGBA image `dst`. If the matte
+    /// has no foreground estimate, `src` is used instead.
+    pub fn to_rgba(&mut self, src: &Mat, matte: &Matte, dst: &mut Mat) -> Result<(), FilterError> {
+        let before = self.buffer_data(dst);
+        match &matte.fgr {
+            Some(estimate) => {
+                estimate.convert_to(&mut self.fgr_8u, CV_8U, 255.0, 0.0)?;
+                cvt_color(&self.fgr_8u, dst, COLOR_RGB2RGBA, 0)?;
+            }
+            None => cvt_color(src, dst, COLOR_RGB2RGBA, 0)?,
+        }
+        matte.pha.convert_to(&mut self.pha_8u, CV_8U, 255.0, 0.0)?;
+        opencv::core::insert_channel(&self.pha_8u, dst, 3)?;
+        self.allocations = count_allocated(&before, &self.buffer_data(dst));
+        Ok(())
+    }
+
+    /// Blend `self.fgr` onto `bg` into `self.blended`.
+    fn blend(&mut self, pha: &Mat, bg: &Mat, alpha_mode: AlphaMode) -> Result<(), FilterError> {
+        let size = self.fgr.size()?;
+        if pha.size()? != size || bg.size()? != size {
+            return Err(FilterError::Other(format!(
+                "Cannot composite a foreground of size {:?} with a matte of size {:?} onto a \
+                 background of size {:?}",
+                size,
+                pha.size()?,
+                bg.size()?
+            )));
+        }
+        to_unit(pha, &mut self.pha, CV_32FC1)?;
+        cvt_color(&self.pha, &mut self.pha_rgb, COLOR_GRAY2RGB, 0)?;
+        opencv::core::subtract(
+            &Scalar::all(1.0),
+            &self.pha_rgb,
+            &mut self.pha_inv,
+            &opencv::core::no_array(),
+            -1,
+        )?;
+        to_unit(bg, &mut self.bg, CV_32FC3)?;
+        opencv::core::multiply(&self.bg, &self.pha_inv, &mut self.bg_part, 1.0, -1)?;
+
+        if alpha_mode == AlphaMode::Straight {
+            if self.blending.harmonize > 0.0 {
+                self.harmonize()?;
+            }
+            if self.blending.light_wrap > 0.0 {
+                self.wrap_light()?;
+            }
+        }
+
+        let fgr_part = match alpha_mode {
+            AlphaMode::Straight => {
+                opencv::core::multiply(&self.fgr, &self.pha_rgb, &mut self.fgr_part, 1.0, -1)?;
+                &self.fgr_part
+            }
+            AlphaMode::Premultiplied => &self.fgr,
+        };
+        opencv::core::add(
+            fgr_part,
+            &self.bg_part,
+            &mut self.blended,
+            &opencv::core::no_array(),
+            -1,
+        )?;
+        Ok(())
+    }
+
+    /// Shift and scale the colours of `self.fgr` in Lab space towards the mean and standard
+    /// deviation of the background.
+    fn harmonize(&mut self) -> Result<(), FilterError> {
+        cvt_color(&self.fgr, &mut self.fgr_lab, COLOR_RGB2Lab, 0)?;
+        cvt_color(&self.bg, &mut self.bg_lab, COLOR_RGB2Lab, 0)?;
+        opencv::core::compare(&self.pha, &Scalar::all(0.5), &mut self.mask, CMP_GT)?;
+        if opencv::core::count_non_zero(&self.mask)? == 0 {
+            // No person in the frame, so there are no colours to match
+            return Ok(());
+        }
+        opencv::core::mean_std_dev(
+            &self.fgr_lab,
+            &mut self.fgr_mean,
+            &mut self.fgr_std,
+            &self.mask,
+        )?;
+        opencv::core::mean_std_dev(
+            &self.bg_lab,
+            &mut self.bg_mean,
+            &mut self.bg_std,
+            &opencv::core::no_array(),
+        )?;
+
+        let strength = self.blending.harmonize.min(1.0);
+        let mut scale = Scalar::all(1.0);
+        let mut offset = Scalar::all(0.0);
+        for channel in 0..3 {
+            let fgr_mean = *self.fgr_mean.at::<f64>(channel)?;
+            let fgr_std = *self.fgr_std.at::<f64>(channel)?;
+            let bg_mean = *self.bg_mean.at::<f64>(channel)?;
+            let bg_std = *self.bg_std.at::<f64>(channel)?;
+            let full_scale = if fgr_std > 0.0 {
+                (bg_std / fgr_std).clamp(MIN_HARMONIZE_SCALE, MAX_HARMONIZE_SCALE)
+            } else {
+                1.0
+            };
+            // Only go part of the way from the colours as they are to the fully matched ones
+            scale[channel as usize] = 1.0 + strength * (full_scale - 1.0);
+            offset[channel as usize] = strength * (bg_mean - fgr_mean * full_scale);
+        }
+        opencv::core::multiply(&self.fgr_lab, &scale, &mut self.scratch, 1.0, -1)?;
+        opencv::core::add(
+            &self.scratch,
+            &offset,
+            &mut self.fgr_lab,
+            &opencv::core::no_array(),
+            -1,
+        )?;
+        cvt_color(&self.fgr_lab, &mut self.fgr, COLOR_Lab2RGB, 0)?;
+        Ok(())
+    }
+
+    /// Blend the blurred background onto `self.fgr` where the person is close to the
+    /// background.
+    fn wrap_light(&mut self) -> Result<(), FilterError> {
+        let radius = self.blending.light_wrap_radius.max(1);
+        // How much background surrounds each pixel, limited to the person
+        blur(&self.pha_inv, &mut self.scratch, radius)?;
+        opencv::core::multiply(
+            &self.scratch,
+            &self.pha_rgb,
+            &mut self.scratch_rgb,
+            self.blending.light_wrap.min(1.0),
+            -1,
+        )?;
+        // fgr + (blurred bg - fgr) * wrap
+        blur(&self.bg, &mut self.scratch, radius)?;
+        opencv::core::subtract(
+            &self.scratch,
+            &self.fgr,
+            &mut self.blended,
+            &opencv::core::no_array(),
+            -1,
+        )?;
+        opencv::core::multiply(&self.blended, &self.scratch_rgb, &mut self.scratch, 1.0, -1)?;
+        opencv::core::add(
+            &self.fgr,
+            &self.scratch,
+            &mut self.blended,
+            &opencv::core::no_array(),
+            -1,
+        )?;
+        mem::swap(&mut self.fgr, &mut self.blended);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::Vec3b;
+    use opencv::imgcodecs::{imread, IMREAD_UNCHANGED};
+
+    fn filled(typ: i32, value: f64) -> Mat {
+        Mat::new_rows_cols_with_default(2, 3, typ, Scalar::all(value)).unwrap()
+    }
+
+    /// A reference image from testdata/composite. The images are small gradients and textures
+    /// generated independently of OpenCV. They are read as BGR, which does not matter as
+    /// compositing treats all channels alike.
+    fn reference(name: &str) -> Mat {
+        let path = format!("{}/testdata/composite/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let image = imread(&path, IMREAD_UNCHANGED).unwrap();
+        assert!(!image.empty(), "Cannot read {}", path);
+        image
+    }
+
+    /// Assert that every channel of `image` is within `tolerance` of the reference image.
+    fn assert_matches(image: &Mat, name: &str, tolerance: u8) {
+        let expected = reference(name);
+        assert_eq!(image.typ(), expected.typ(), "{}", name);
+        assert_eq!(image.size().unwrap(), expected.size().unwrap(), "{}", name);
+        let pairs = image
+            .data_bytes()
+            .unwrap()
+            .iter()
+            .zip(expected.data_bytes().unwrap());
+        for (i, (actual, expected)) in pairs.enumerate() {
+            assert!(
+                actual.abs_diff(*expected) <= tolerance,
+                "{}: byte {} is {}, expected {}",
+                name,
+                i,
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn straight_with_u8_matte() {
+        let mut dst = Mat::default();
+        Compositor::default()
+            .composite(
+                &reference("foreground.png"),
+                &reference("matte.png"),
+                &reference("background.png"),
+                AlphaMode::Straight,
+                &mut dst,
+            )
+            .unwrap();
+        assert_matches(&dst, "straight.png", 1);
+    }
+
+    #[test]
+    fn straight_with_f32_matte() {
+        let mut pha = Mat::default();
+        reference("matte.png")
+            .convert_to(&mut pha, CV_32FC1, 1.0 / 255.0, 0.0)
+            .unwrap();
+        let mut dst = Mat::default();
+        Compositor::default()
+            .composite(
+                &reference("foreground.png"),
+                &pha,
+                &reference("background.png"),
+                AlphaMode::Straight,
+                &mut dst,
+            )
+            .unwrap();
+        assert_matches(&dst, "straight.png", 1);
+    }
+
+    #[test]
+    fn premultiplied_foreground_is_not_weighted_again() {
+        let mut fgr = Mat::default();
+        reference("foreground.png")
+            .convert_to(&mut fgr, CV_32FC3, 1.0 / 255.0, 0.0)
+            .unwrap();
+        let mut dst = Mat::default();
+        Compositor::default()
+            .composite(
+                &fgr,
+                &reference("matte.png"),
+                &reference("background.png"),
+                AlphaMode::Premultiplied,
+                &mut dst,
+            )
+            .unwrap();
+        assert_matches(&dst, "premultiplied.png", 1);
+    }
+
+    #[test]
+    fn inplace_uses_the_frame_as_foreground() {
+        let mut frame = reference("foreground.png");
+        Compositor::default()
+            .composite_inplace(
+                &mut frame,
+                &reference("matte.png"),
+                &reference("background.png"),
+            )
+            .unwrap();
+        assert_matches(&frame, "straight.png", 1);
+    }
+
+    #[test]
+    fn size_mismatch_is_an_error() {
+        let pha = Mat::new_rows_cols_with_default(4, 4, CV_32FC1, Scalar::all(1.0)).unwrap();
+        let mut dst = Mat::default();
+        let result = Compositor::default().composite(
+            &filled(CV_8UC3, 200.0),
+            &pha,
+            &filled(CV_8UC3, 100.0),
+            AlphaMode::Straight,
+            &mut dst,
+        );
+        assert!(matches!(result, Err(FilterError::Other(_))));
+    }
+
+    #[test]
+    fn buffers_are_reused_between_frames() {
+        let mut compositor = Compositor::new(Blending {
+            light_wrap: 0.5,
+            light_wrap_radius: 1,
+            harmonize: 0.5,
+        });
+        let (fgr, pha, bg) = (
+            filled(CV_8UC3, 200.0),
+            filled(CV_32FC1, 0.75),
+            filled(CV_8UC3, 100.0),
+        );
+        let mut dst = Mat::default();
+        // Light wrap swaps the foreground and blended buffers, so two frames bring them back
+        // to where they started
+        for _ in 0..2 {
+            compositor
+                .composite(&fgr, &pha, &bg, AlphaMode::Straight, &mut dst)
+                .unwrap();
+        }
+        let pointers = (compositor.fgr.data(), compositor.blended.data(), dst.data());
+        for _ in 0..2 {
+            compositor
+                .composite(&fgr, &pha, &bg, AlphaMode::Straight, &mut dst)
+                .unwrap();
+        }
+        assert_eq!(
+            (compositor.fgr.data(), compositor.blended.data(), dst.data()),
+            pointers
+        );
+        assert_eq!(compositor.allocations(), 0);
+    }
+
+    #[test]
+    fn first_frame_allocations_are_counted() {
+        let mut compositor = Compositor::default();
+        let (fgr, pha, bg) = (
+            filled(CV_8UC3, 200.0),
+            filled(CV_32FC1, 0.75),
+            filled(CV_8UC3, 100.0),
+        );
+        let mut dst = Mat::default();
+        compositor
+            .composite(&fgr, &pha, &bg, AlphaMode::Straight, &mut dst)
+            .unwrap();
+        assert!(compositor.allocations() > 0);
+        compositor
+            .composite(&fgr, &pha, &bg, AlphaMode::Straight, &mut dst)
+            .unwrap();
+        assert_eq!(compositor.allocations(), 0);
+    }
+
+    /// A matte of `width` by 4 pixels with the person on the right of column `edge`.
+    fn edge_matte(width: i32, edge: i32) -> Mat {
+        let mut pha =
+            Mat::new_rows_cols_with_default(4, width, CV_32FC1, Scalar::all(0.0)).unwrap();
+        for row in 0..4 {
+            for col in edge..width {
+                *pha.at_2d_mut::<f32>(row, col).unwrap() = 1.0;
+            }
+        }
+        pha
+    }
+
+    /// The first channel of the pixel in the middle row of `image` at column `col`.
+    fn pixel(image: &Mat, col: i32) -> u8 {
+        image.at_2d::<Vec3b>(2, col).unwrap()[0]
+    }
+
+    #[test]
+    fn light_wrap_brightens_only_the_edges() {
+        let (width, edge) = (40, 20);
+        let pha = edge_matte(width, edge);
+        let fgr = Mat::new_rows_cols_with_default(4, width, CV_8UC3, Scalar::all(50.0)).unwrap();
+        let bg = Mat::new_rows_cols_with_default(4, width, CV_8UC3, Scalar::all(250.0)).unwrap();
+        let composite = |light_wrap| {
+            let mut dst = Mat::default();
+            Compositor::new(Blending {
+                light_wrap,
+                light_wrap_radius: 3,
+                harmonize: 0.0,
+            })
+            .composite(&fgr, &pha, &bg, AlphaMode::Straight, &mut dst)
+            .unwrap();
+            dst
+        };
+        let (plain, wrapped) = (composite(0.0), composite(1.0));
+
+        assert_eq!(pixel(&plain, edge), 50);
+        // The background shows through right at the edge of the person
+        assert!(pixel(&wrapped, edge) > 80, "{}", pixel(&wrapped, edge));
+        assert!(pixel(&wrapped, edge) > pixel(&wrapped, edge + 2));
+        // Further in than the radius, and on the background, nothing changes
+        for col in (edge + 4..width).chain(0..edge) {
+            assert_eq!(pixel(&wrapped, col), pixel(&plain, col), "column {}", col);
+        }
+    }
+
+    /// The mean of `image` in Lab space.
+    fn lab_mean(image: &Mat) -> [f64; 3] {
+        let (mut unit, mut lab) = (Mat::default(), Mat::default());
+        to_unit(image, &mut unit, CV_32FC3).unwrap();
+        cvt_color(&unit, &mut lab, COLOR_RGB2Lab, 0).unwrap();
+        let mean = opencv::core::mean(&lab, &opencv::core::no_array()).unwrap();
+        [mean[0], mean[1], mean[2]]
+    }
+
+    #[test]
+    fn harmonisation_moves_the_foreground_towards_the_background() {
+        let size = (12, 16);
+        let fgr = Mat::new_rows_cols_with_default(
+            size.0,
+            size.1,
+            CV_8UC3,
+            Scalar::new(60.0, 90.0, 160.0, 0.0),
+        )
+        .unwrap();
+        let bg = Mat::new_rows_cols_with_default(
+            size.0,
+            size.1,
+            CV_8UC3,
+            Scalar::new(200.0, 180.0, 60.0, 0.0),
+        )
+        .unwrap();
+        let pha =
+            Mat::new_rows_cols_with_default(size.0, size.1, CV_32FC1, Scalar::all(1.0)).unwrap();
+        let (fgr_mean, bg_mean) = (lab_mean(&fgr), lab_mean(&bg));
+        let cases = [0.0, 0.25, 0.5, 1.0];
+        for &strength in &cases {
+            let mut dst = Mat::default();
+            Compositor::new(Blending {
+                light_wrap: 0.0,
+                light_wrap_radius: 10,
+                harmonize: strength,
+            })
+            .composite(&fgr, &pha, &bg, AlphaMode::Straight, &mut dst)
+            .unwrap();
+            if strength == 0.0 {
+                assert_eq!(dst.data_bytes().unwrap(), fgr.data_bytes().unwrap());
+            }
+            let mean = lab_mean(&dst);
+            for channel in 0..3 {
+                let expected =
+                    fgr_mean[channel] + strength * (bg_mean[channel] - fgr_mean[channel]);
+                assert!(
+                    (mean[channel] - expected).abs() < 1.5,
+                    "strength {}, channel {}: {} instead of {}",
+                    strength,
+                    channel,
+                    mean[channel],
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rgba_has_the_matte_as_alpha() {
+        let mut compositor = Compositor::default();
+        let matte = Matte {
+            fgr: Some(filled(CV_32FC3, 0.2)),
+            pha: filled(CV_32FC1, 0.4),
+        };
+        let mut dst = Mat::default();
+        compositor
+            .to_rgba(&filled(CV_8UC3, 0.0), &matte, &mut dst)
+            .unwrap();
+        assert_eq!(dst.typ(), opencv::core::CV_8UC4);
+        assert!(dst
+            .data_bytes()
+            .unwrap()
+            .chunks_exact(4)
+            .all(|p| p == [51, 51, 51, 102]));
+        compositor
+            .to_rgba(&filled(CV_8UC3, 0.0), &matte, &mut dst)
+            .unwrap();
+        assert_eq!(compositor.allocations(), 0);
+    }
+}
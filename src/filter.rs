@@ -1,6 +1,11 @@
+use crate::chromakeyfilter::ChromaKey;
+use crate::composite::Blending;
+use crate::refinefilter::Refinement;
 use opencv::prelude::*;
 use quick_error::quick_error;
 use core::fmt::Debug;
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
 
 quick_error!{
     #[derive(Debug)]
@@ -28,4 +33,213 @@ pub trait Filter: Debug + Send {
         self.filter_inplace(&mut mod_image, bg_image)?;
         Ok(mod_image)
     }
+
+    /// Write `src_image` to `dst_image` as RGBA, with the alpha channel separating the person
+    /// from the background instead of compositing the person onto a new background. Filters
+    /// which do not estimate a matte produce an opaque image.
+    fn filter_alpha(&mut self, src_image: &Mat, dst_image: &mut Mat) -> Result<(), FilterError> {
+        opencv::imgproc::cvt_color(src_image, dst_image, opencv::imgproc::COLOR_RGB2RGBA, 0)?;
+        Ok(())
+    }
+
+    /// The alpha matte estimated for the last frame as CV_32FC1, if the filter estimates one.
+    fn matte(&self) -> Option<&Mat> {
+        None
+    }
+
+    /// Move the matte estimated for the last frame out of the filter, so [`Filter::process`]
+    /// can pass it on without copying. Filters implementing [`Filter::matte`] and relying on
+    /// the default [`Filter::process`] implement this as well.
+    fn take_matte(&mut self) -> Option<Mat> {
+        None
+    }
+
+    /// How long the stages of filtering the last frame took, if the filter measures it.
+    fn timings(&self) -> Option<&StageTimings> {
+        None
+    }
+
+    /// Trade quality for speed as described by `quality`. Filters without anything to adjust
+    /// ignore this.
+    fn set_quality(&mut self, _quality: &Quality) {}
+
+    /// Change how the matte is keyed, refined and blended from the next frame on, without
+    /// rebuilding the filter. Filters without anything to adjust ignore this.
+    fn set_post_processing(
+        &mut self,
+        _post_processing: &PostProcessing,
+    ) -> Result<(), FilterError> {
+        Ok(())
+    }
+
+    /// Forget everything learned from previous frames, e.g. after a seek or when the frame
+    /// size changed, so the next frame is filtered as if it were the first one.
+    fn reset(&mut self) {}
+
+    /// Describe the frame passed to the next call of [`Filter::filter_inplace`] or
+    /// [`Filter::filter_alpha`]. Chains hand it on to their filters in the [`FrameContext`],
+    /// other filters ignore it.
+    fn set_frame_meta(&mut self, _meta: &FrameMeta) {}
+
+    /// Filter the frame of `ctx` as one step of a [`crate::chain::FilterChain`]. By default
+    /// the frame is composited onto the background of `ctx`, or left as it is if there is no
+    /// background, and the matte of the filter is passed on to the following filters. The
+    /// chain keeps the matte from then on, so [`Filter::matte`] of the filter itself is empty.
+    fn process(&mut self, ctx: &mut FrameContext) -> Result<(), FilterError> {
+        match &ctx.background {
+            Some(bg_image) => self.filter_inplace(ctx.frame, bg_image)?,
+            None => {
+                let mut rgba = Mat::default();
+                self.filter_alpha(ctx.frame, &mut rgba)?;
+            }
+        }
+        if let Some(pha) = self.take_matte() {
+            ctx.pha = Some(pha);
+        }
+        Ok(())
+    }
+}
+
+/// Everything known about the frame being filtered, shared by the filters of a
+/// [`crate::chain::FilterChain`] so each filter can build on the results of the previous ones.
+#[derive(Debug)]
+pub struct FrameContext<'a> {
+    /// The frame as RGB, modified in place by the filters
+    pub frame: &'a mut Mat,
+    /// The image the person is composited onto, `None` if only the matte is wanted. Borrowed
+    /// from the caller unless a filter replaced it.
+    pub background: Option<Cow<'a, Mat>>,
+    /// The alpha matte as CV_32FC1, once a filter estimated it. See [`Matte`]. The filters
+    /// replace it with their result instead of keeping a copy.
+    pub pha: Option<Mat>,
+    /// The foreground colour as CV_32FC3, if the model estimates it. See [`Matte`].
+    pub fgr: Option<Mat>,
+    /// What the buffer says about the frame
+    pub meta: FrameMeta,
+}
+
+impl<'a> FrameContext<'a> {
+    pub fn new(frame: &'a mut Mat, background: Option<&'a Mat>) -> FrameContext<'a> {
+        FrameContext {
+            frame,
+            background: background.map(Cow::Borrowed),
+            pha: None,
+            fgr: None,
+            meta: FrameMeta::default(),
+        }
+    }
+}
+
+/// What is known about a frame besides its pixels, taken from the metadata of its buffer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameMeta {
+    /// When the frame is presented, relative to the start of the stream
+    pub pts: Option<Duration>,
+    pub duration: Option<Duration>,
+    /// Whether the frame does not follow the previous one, e.g. after a seek or dropped frames
+    pub discont: bool,
+}
+
+/// How much quality is traded for speed, chosen by the [`crate::quality::QualityController`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    /// Resolution RVM works at internally relative to the frames it is given
+    pub downsample_ratio: f64,
+    /// Size of the frames given to the model relative to the camera frames
+    pub inference_scale: f64,
+    /// Run the model at least every this many frames
+    pub inference_interval: u32,
+}
+
+/// The settings of the steps after the matte has been estimated, which can be changed while
+/// the filter is running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PostProcessing {
+    pub key: ChromaKey,
+    pub refinement: Refinement,
+    pub blending: Blending,
+}
+
+/// The time spent in each stage of processing a frame, e.g. inference or compositing, and how
+/// many buffers the stages allocated. This is only a handful of measurements per frame, so it
+/// is cheap enough to always record.
+#[derive(Debug, Clone, Default)]
+pub struct StageTimings {
+    stages: Vec<(&'static str, Duration)>,
+    allocations: Vec<(&'static str, usize)>,
+}
+
+impl StageTimings {
+    pub fn clear(&mut self) {
+        self.stages.clear();
+        self.allocations.clear();
+    }
+
+    /// Record that `stage` ran from `start` until now.
+    pub fn record(&mut self, stage: &'static str, start: Instant) {
+        self.stages.push((stage, start.elapsed()));
+    }
+
+    /// Record that `stage` allocated `count` buffers for the frame, e.g. tensors or images it
+    /// could not reuse from the previous frame.
+    pub fn record_allocations(&mut self, stage: &'static str, count: usize) {
+        self.allocations.push((stage, count));
+    }
+
+    /// The number of buffers `stage` allocated for the frame.
+    pub fn allocations(&self, stage: &str) -> usize {
+        self.allocations
+            .iter()
+            .filter(|(name, _)| *name == stage)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// How long `stage` took, if it ran for the frame.
+    pub fn duration(&self, stage: &str) -> Option<Duration> {
+        self.stages
+            .iter()
+            .filter(|(name, _)| *name == stage)
+            .map(|(_, duration)| *duration)
+            .reduce(|total, duration| total + duration)
+    }
+
+    /// Append the stages recorded by `other`, e.g. those of a wrapped estimator.
+    pub fn extend(&mut self, other: &StageTimings) {
+        self.stages.extend_from_slice(&other.stages);
+        self.allocations.extend_from_slice(&other.allocations);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, Duration)> + '_ {
+        self.stages.iter().copied()
+    }
+}
+
+/// The result of separating a frame into foreground and background.
+#[derive(Debug)]
+pub struct Matte {
+    /// The foreground colour as CV_32FC3 with values in 0..1, if the model estimates it.
+    /// If this is `None` the original frame should be used as foreground.
+    pub fgr: Option<Mat>,
+    /// The alpha matte as CV_32FC1 with the same size as the frame. It is 1 for foreground,
+    /// 0 for background and in between at soft edges.
+    pub pha: Mat,
+}
+
+/// Something that can separate a person from the background of a frame, e.g. a matting model.
+/// Filters which only differ in what they do with the background (replace, blur, ...) use
+/// this to share the same model.
+pub trait MatteEstimator: Debug + Send {
+    fn estimate(&mut self, src_image: &Mat) -> Result<Matte, FilterError>;
+
+    /// How long the stages of the last estimate took, if the estimator measures it.
+    fn timings(&self) -> Option<&StageTimings> {
+        None
+    }
+
+    /// Trade quality for speed as described by `quality`, see [`Filter::set_quality`].
+    fn set_quality(&mut self, _quality: &Quality) {}
+
+    /// Forget everything learned from previous frames, see [`Filter::reset`].
+    fn reset(&mut self) {}
 }
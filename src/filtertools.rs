@@ -1,22 +1,50 @@
 //! This module implements some general-purpose image-processing functions that can be used
 //! by filters.
 
+use crate::filter::FilterError;
+use opencv::core::{Size, Vector};
+use opencv::imgproc::{calc_hist, compare_hist, resize, HISTCMP_BHATTACHARYYA, INTER_AREA};
 use opencv::prelude::*;
 
-/// Replace the pixels in `src` for which `fgr_mask` indicates background exists with pixels
-/// from `bg`. The `fgr_mask` is expected to be a single-channel Mat with f32 values. It should
-/// be 1 for foreground and 0 for background or in between. Further, `src`, `bg` and `fgr_mask`
-/// have to have the same dimensions.
-pub fn apply_mask(src: &Mat, bg: &Mat, fgr_mask: &Mat) -> Result<Mat, opencv::Error> {
-    let mut mask_bc = Mat::new_rows_cols_with_default(src.rows(), src.cols(), opencv::core::CV_32FC3, opencv::core::Scalar::from((0.0, 0.0, 0.0)))?;
-    opencv::core::mix_channels(
-        fgr_mask,
-        &mut mask_bc,
-        &[0,0, 0,1, 0,2]
-    )?;
-    opencv::highgui::imshow("preview", &mask_bc)?;
-    opencv::highgui::wait_key(15)?;
-    let mask_bc_inv = opencv::core::Scalar::from((1.0, 1.0, 1.0)) - &mask_bc;
-    //Ok((src * &mask_bc + bg * mask_bc_inv).into_result()?.to_mat()?)
-    Ok(Mat::default())
+/// Width of the frames the histograms for scene cut detection are computed on.
+const SCENE_CUT_WIDTH: i32 = 64;
+/// Bhattacharyya distance between the histograms of two frames above which they are considered
+/// to be from different scenes. 0 is identical, 1 is no overlap at all.
+const SCENE_CUT_DISTANCE: f64 = 0.5;
+
+/// Detects cuts between scenes, e.g. switching cameras, by comparing the colour histograms of
+/// consecutive frames. Lighting changes and motion change the histogram much less than a cut.
+#[derive(Debug, Default)]
+pub struct SceneCutDetector {
+    /// Histogram of the last frame, empty before the first one
+    histogram: Mat,
+}
+
+impl SceneCutDetector {
+    /// Whether `frame`, an RGB frame, starts a new scene compared to the last frame passed.
+    pub fn is_cut(&mut self, frame: &Mat) -> Result<bool, FilterError> {
+        let height = (frame.rows() * SCENE_CUT_WIDTH / frame.cols().max(1)).max(1);
+        let mut small = Mat::default();
+        resize(frame, &mut small, Size::new(SCENE_CUT_WIDTH, height), 0.0, 0.0, INTER_AREA)?;
+        let mut histogram = Mat::default();
+        calc_hist(
+            &small,
+            &Vector::from_slice(&[0, 1, 2]),
+            &Mat::default(),
+            &mut histogram,
+            &Vector::from_slice(&[8, 8, 8]),
+            &Vector::from_slice(&[0.0, 256.0, 0.0, 256.0, 0.0, 256.0]),
+            false,
+        )?;
+        let cut = !self.histogram.empty()
+            && compare_hist(&self.histogram, &histogram, HISTCMP_BHATTACHARYYA)?
+                > SCENE_CUT_DISTANCE;
+        self.histogram = histogram;
+        Ok(cut)
+    }
+
+    /// Forget the last frame, so the next one is not compared to anything.
+    pub fn reset(&mut self) {
+        self.histogram = Mat::default();
+    }
 }
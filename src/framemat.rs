@@ -0,0 +1,307 @@
+//! Maps the planes of GStreamer video frames to OpenCV Mats laid out as described by
+//! [`frame_layout`]. GStreamer pads the rows of a plane to its stride, e.g. to a multiple of four
+//! bytes for RGB frames of odd width, so the Mats use the stride of the frame as their step.
+//!
+//! Planar formats are stored as one Mat with the chroma planes below the luma plane. That Mat
+//! can only point into the frame if the planes follow each other without any padding. Otherwise
+//! the planes are copied into a Mat of their own, and written back with
+//! [`FrameMat::write_back`] after the Mat has been modified.
+
+use crate::filter::FilterError;
+use crate::videoformat::frame_layout;
+use core::ffi::c_void;
+use gstreamer::BufferRef;
+use gstreamer_video::{VideoFormat, VideoFrameRef};
+use opencv::core::{Scalar, CV_8UC1};
+use opencv::prelude::*;
+
+/// The bytes per row and the rows of each plane of a planar format, as stored in the Mat of the
+/// whole frame.
+fn packed_planes(format: VideoFormat, width: i32, height: i32) -> Vec<(i32, i32)> {
+    match format {
+        VideoFormat::Nv12 => vec![(width, height), (width, height / 2)],
+        VideoFormat::I420 => vec![
+            (width, height),
+            (width / 2, height / 2),
+            (width / 2, height / 2),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// The `rows` x `row_bytes` block starting `offset` bytes into the continuous CV_8UC1 `mat`.
+fn block(mat: &Mat, offset: i32, row_bytes: i32, rows: i32) -> Result<Mat, FilterError> {
+    let block = mat
+        .reshape(1, 1)?
+        .col_bounds(offset, offset + row_bytes * rows)?
+        .reshape(1, rows)?;
+    Ok(block)
+}
+
+/// Wrap a single plane starting at `data` as a CV_8UC1 Mat, without copying.
+unsafe fn wrap_plane(
+    data: *mut u8,
+    stride: usize,
+    row_bytes: i32,
+    rows: i32,
+) -> Result<Mat, FilterError> {
+    let plane =
+        Mat::new_rows_cols_with_data(rows, row_bytes, CV_8UC1, data as *mut c_void, stride)?;
+    Ok(plane)
+}
+
+/// A video frame as a Mat.
+#[derive(Debug)]
+pub struct FrameMat {
+    mat: Mat,
+    /// Whether `mat` holds a copy of the planes instead of pointing into the frame
+    copied: bool,
+}
+
+impl FrameMat {
+    /// Map a frame which is only read. OpenCV does not know about constness, so the Mat must
+    /// not be written to.
+    ///
+    /// # Safety
+    /// Unless the planes were copied, the Mat points into the memory of the frame and must not
+    /// be used after the frame has been dropped.
+    pub unsafe fn readable(frame: &VideoFrameRef<&BufferRef>) -> Result<FrameMat, FilterError> {
+        let mut planes = Vec::new();
+        for plane in 0..frame.n_planes() {
+            let data = frame.plane_data(plane).map_err(|e| {
+                FilterError::Other(format!("Failed to read out frame plane {}: {}", plane, e))
+            })?;
+            planes.push((
+                data.as_ptr() as *mut u8,
+                frame.plane_stride()[plane as usize],
+            ));
+        }
+        FrameMat::map(frame.format(), frame.width(), frame.height(), &planes)
+    }
+
+    /// Map a frame which may be written to. If the planes had to be copied, changes to the Mat
+    /// only reach the frame through [`FrameMat::write_back`].
+    ///
+    /// # Safety
+    /// Unless the planes were copied, the Mat points into the memory of the frame and must not
+    /// be used after the frame has been dropped.
+    pub unsafe fn writable(
+        frame: &mut VideoFrameRef<&mut BufferRef>,
+    ) -> Result<FrameMat, FilterError> {
+        let planes = writable_planes(frame)?;
+        FrameMat::map(frame.format(), frame.width(), frame.height(), &planes)
+    }
+
+    /// Map the planes given by their data and stride, which make up a frame of the given format
+    /// and size.
+    unsafe fn map(
+        format: VideoFormat,
+        width: u32,
+        height: u32,
+        planes: &[(*mut u8, i32)],
+    ) -> Result<FrameMat, FilterError> {
+        let (width, height) = (width as i32, height as i32);
+        let (rows, typ) = frame_layout(format, height)?;
+        let (data, stride) = *planes.first().ok_or_else(|| {
+            FilterError::Other(String::from("Video frame does not have any planes"))
+        })?;
+        let packed = packed_planes(format, width, height);
+        if planes.len() == 1 || FrameMat::is_contiguous(planes, &packed) {
+            let mat = Mat::new_rows_cols_with_data(
+                rows,
+                width,
+                typ,
+                data as *mut c_void,
+                stride as usize,
+            )?;
+            return Ok(FrameMat { mat, copied: false });
+        }
+        if planes.len() != packed.len() {
+            return Err(FilterError::Other(format!(
+                "Video format {} with {} planes is not supported",
+                format,
+                planes.len()
+            )));
+        }
+        let mat = Mat::new_rows_cols_with_default(rows, width, typ, Scalar::all(0.0))?;
+        let mut offset = 0;
+        for (&(data, stride), &(row_bytes, plane_rows)) in planes.iter().zip(&packed) {
+            let plane = wrap_plane(data, stride as usize, row_bytes, plane_rows)?;
+            plane.copy_to(&mut block(&mat, offset, row_bytes, plane_rows)?)?;
+            offset += row_bytes * plane_rows;
+        }
+        Ok(FrameMat { mat, copied: true })
+    }
+
+    /// Whether the planes follow each other without padding, as they do in the Mat of the whole
+    /// frame.
+    fn is_contiguous(planes: &[(*mut u8, i32)], packed: &[(i32, i32)]) -> bool {
+        if planes.len() != packed.len() {
+            return false;
+        }
+        let start = planes[0].0 as usize;
+        let mut offset = 0;
+        for (&(data, stride), &(row_bytes, rows)) in planes.iter().zip(packed) {
+            if stride != row_bytes || data as usize != start + offset as usize {
+                return false;
+            }
+            offset += row_bytes * rows;
+        }
+        true
+    }
+
+    pub fn mat(&self) -> &Mat {
+        &self.mat
+    }
+
+    pub fn mat_mut(&mut self) -> &mut Mat {
+        &mut self.mat
+    }
+
+    /// Copy the Mat back to the planes of `frame`, if the planes were copied when mapping it.
+    pub fn write_back(&self, frame: &mut VideoFrameRef<&mut BufferRef>) -> Result<(), FilterError> {
+        if !self.copied {
+            return Ok(());
+        }
+        let planes = writable_planes(frame)?;
+        unsafe { self.write_planes(frame.format(), frame.width(), frame.height(), &planes) }
+    }
+
+    /// Copy the Mat back to the planes given by their data and stride, if the planes were
+    /// copied when mapping them.
+    unsafe fn write_planes(
+        &self,
+        format: VideoFormat,
+        width: u32,
+        height: u32,
+        planes: &[(*mut u8, i32)],
+    ) -> Result<(), FilterError> {
+        if !self.copied {
+            return Ok(());
+        }
+        let packed = packed_planes(format, width as i32, height as i32);
+        let mut offset = 0;
+        for (&(data, stride), &(row_bytes, rows)) in planes.iter().zip(&packed) {
+            let mut dst = wrap_plane(data, stride as usize, row_bytes, rows)?;
+            block(&self.mat, offset, row_bytes, rows)?.copy_to(&mut dst)?;
+            offset += row_bytes * rows;
+        }
+        Ok(())
+    }
+}
+
+/// The data and stride of each plane of a writable frame.
+fn writable_planes(
+    frame: &mut VideoFrameRef<&mut BufferRef>,
+) -> Result<Vec<(*mut u8, i32)>, FilterError> {
+    let strides = frame.plane_stride().to_vec();
+    let mut planes = Vec::new();
+    for plane in 0..frame.n_planes() {
+        let data = frame.plane_data_mut(plane).map_err(|e| {
+            FilterError::Other(format!("Failed to read out frame plane {}: {}", plane, e))
+        })?;
+        planes.push((data.as_mut_ptr(), strides[plane as usize]));
+    }
+    Ok(planes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{Vec3b, CV_8UC3};
+
+    #[test]
+    fn packed_planes_of_planar_formats() {
+        assert_eq!(
+            packed_planes(VideoFormat::Nv12, 640, 480),
+            vec![(640, 480), (640, 240)]
+        );
+        assert_eq!(
+            packed_planes(VideoFormat::I420, 640, 480),
+            vec![(640, 480), (320, 240), (320, 240)]
+        );
+        assert!(packed_planes(VideoFormat::Rgb, 640, 480).is_empty());
+    }
+
+    #[test]
+    fn contiguous_planes() {
+        let mut buffer = vec![0u8; 12];
+        let start = buffer.as_mut_ptr();
+        let packed = packed_planes(VideoFormat::I420, 4, 2);
+        let planes = unsafe { [(start, 4), (start.add(8), 2), (start.add(10), 2)] };
+        assert!(FrameMat::is_contiguous(&planes, &packed));
+        // Padded rows
+        let planes = unsafe { [(start, 8), (start.add(8), 2), (start.add(10), 2)] };
+        assert!(!FrameMat::is_contiguous(&planes, &packed));
+        // A gap between the planes
+        let planes = unsafe { [(start, 4), (start.add(9), 2), (start.add(11), 2)] };
+        assert!(!FrameMat::is_contiguous(&planes, &packed));
+        // Missing planes
+        assert!(!FrameMat::is_contiguous(&planes[..2], &packed));
+    }
+
+    #[test]
+    fn rgb_with_padded_stride() {
+        // 3 pixels of 3 bytes, padded to 12 bytes per row
+        let mut data: Vec<u8> = (0..24).map(|i| if i % 12 < 9 { i } else { 0xff }).collect();
+        let frame =
+            unsafe { FrameMat::map(VideoFormat::Rgb, 3, 2, &[(data.as_mut_ptr(), 12)]).unwrap() };
+        assert!(!frame.copied);
+        assert_eq!(frame.mat().typ(), CV_8UC3);
+        assert_eq!((frame.mat().rows(), frame.mat().cols()), (2, 3));
+        assert_eq!(
+            *frame.mat().at_2d::<Vec3b>(0, 2).unwrap(),
+            Vec3b::from([6, 7, 8])
+        );
+        assert_eq!(
+            *frame.mat().at_2d::<Vec3b>(1, 0).unwrap(),
+            Vec3b::from([12, 13, 14])
+        );
+    }
+
+    #[test]
+    fn i420_with_padded_strides() {
+        // 4x2 pixels, luma rows padded to 8 bytes and chroma rows to 4
+        let mut y: Vec<u8> = vec![
+            1, 2, 3, 4, 0xff, 0xff, 0xff, 0xff, 5, 6, 7, 8, 0xff, 0xff, 0xff, 0xff,
+        ];
+        let mut u: Vec<u8> = vec![9, 10, 0xff, 0xff];
+        let mut v: Vec<u8> = vec![11, 12, 0xff, 0xff];
+        let planes = [
+            (y.as_mut_ptr(), 8),
+            (u.as_mut_ptr(), 4),
+            (v.as_mut_ptr(), 4),
+        ];
+        let mut frame = unsafe { FrameMat::map(VideoFormat::I420, 4, 2, &planes).unwrap() };
+        assert!(frame.copied);
+        assert_eq!((frame.mat().rows(), frame.mat().cols()), (3, 4));
+        assert_eq!(
+            frame.mat().data_bytes().unwrap(),
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]
+        );
+
+        for (i, value) in frame
+            .mat_mut()
+            .data_bytes_mut()
+            .unwrap()
+            .iter_mut()
+            .enumerate()
+        {
+            *value = 100 + i as u8;
+        }
+        unsafe {
+            frame
+                .write_planes(VideoFormat::I420, 4, 2, &planes)
+                .unwrap()
+        };
+        assert_eq!(
+            y,
+            vec![
+                100, 101, 102, 103, 0xff, 0xff, 0xff, 0xff, 104, 105, 106, 107, 0xff, 0xff, 0xff,
+                0xff
+            ]
+        );
+        assert_eq!(u, vec![108, 109, 0xff, 0xff]);
+        assert_eq!(v, vec![110, 111, 0xff, 0xff]);
+    }
+}
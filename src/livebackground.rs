@@ -0,0 +1,195 @@
+//! This module handles backgrounds received on the `background` sink pad of the element, so that
+//! any GStreamer source, e.g. a screen capture or a second camera, can provide the background.
+//! Incoming frames are scaled to the size of the camera frames and queued with their running
+//! time. For each camera frame the latest background frame which is not ahead of it is used,
+//! and if the background stream stalls or ends the last frame is reused.
+
+use crate::background::{fit_image, FitMode};
+use crate::filter::FilterError;
+use crate::videoformat::FormatConverter;
+use gstreamer::{Caps, ClockTime, FlowError, FlowSuccess};
+use gstreamer_video::VideoInfo;
+use opencv::core::Size;
+use opencv::prelude::*;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+/// Number of frames queued before the background stream is blocked. This keeps sources which
+/// are not live, e.g. a video file, from being read faster than the camera.
+const MAX_QUEUED: usize = 4;
+
+/// The state of the `background` pad, only used by its streaming thread.
+#[derive(Debug)]
+pub struct BackgroundInput {
+    info: Option<VideoInfo>,
+    converter: Option<FormatConverter>,
+    segment: gstreamer::Segment,
+    /// Frame scaled to the camera frame size, before it is moved to the queue
+    rgb: Mat,
+}
+
+impl Default for BackgroundInput {
+    fn default() -> Self {
+        BackgroundInput {
+            info: None,
+            converter: None,
+            segment: gstreamer::Segment::new(),
+            rgb: Mat::default(),
+        }
+    }
+}
+
+impl BackgroundInput {
+    pub fn set_caps(&mut self, caps: &Caps) -> Result<(), FilterError> {
+        let info = VideoInfo::from_caps(caps)
+            .map_err(|e| FilterError::Other(format!("Invalid background caps: {}", e)))?;
+        self.converter = Some(FormatConverter::new(info.format())?);
+        self.info = Some(info);
+        Ok(())
+    }
+
+    pub fn set_segment(&mut self, segment: &gstreamer::Segment) {
+        self.segment = segment.clone();
+    }
+
+    /// The info of the frames as negotiated on the pad.
+    pub fn info(&self) -> Option<&VideoInfo> {
+        self.info.as_ref()
+    }
+
+    /// The running time of a frame with the given timestamp, if it can be determined.
+    pub fn running_time(&self, pts: Option<ClockTime>) -> Option<ClockTime> {
+        self.segment
+            .downcast_ref::<ClockTime>()
+            .and_then(|segment| segment.to_running_time(pts?))
+    }
+
+    /// Convert `frame`, laid out as described by [`crate::videoformat::frame_layout`], to RGB
+    /// and scale it to `size`.
+    pub fn prepare(&mut self, frame: &Mat, size: Size, fit: FitMode) -> Result<Mat, FilterError> {
+        let converter = self.converter.as_mut().ok_or_else(|| {
+            FilterError::Other(String::from("No caps negotiated on the background pad"))
+        })?;
+        converter.to_rgb(frame)?;
+        fit_image(converter.rgb_mut(), &mut self.rgb, size, fit)?;
+        // The queue keeps the frame, so the next one needs a new Mat
+        Ok(std::mem::take(&mut self.rgb))
+    }
+}
+
+#[derive(Debug)]
+struct QueueState {
+    /// Frames not used yet, ordered by running time. Frames without a running time are used as
+    /// soon as the next camera frame arrives.
+    queue: VecDeque<(Option<ClockTime>, Mat)>,
+    /// The frame used for the last camera frame
+    current: Option<Mat>,
+    /// The size of the camera frames, frames of another size are dropped
+    size: Size,
+    flushing: bool,
+}
+
+/// The background frames passed from the `background` pad to the camera streaming thread.
+#[derive(Debug)]
+pub struct FrameQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+impl Default for FrameQueue {
+    fn default() -> Self {
+        FrameQueue {
+            state: Mutex::new(QueueState {
+                queue: VecDeque::new(),
+                current: None,
+                size: Size::default(),
+                // Until the pad is activated
+                flushing: true,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+}
+
+impl FrameQueue {
+    /// Queue `frame`, blocking while the queue is full.
+    pub fn push(
+        &self,
+        running_time: Option<ClockTime>,
+        frame: Mat,
+    ) -> Result<FlowSuccess, FlowError> {
+        let mut state = self.state.lock().unwrap();
+        while state.queue.len() >= MAX_QUEUED && !state.flushing {
+            state = self.changed.wait(state).unwrap();
+        }
+        if state.flushing {
+            return Err(FlowError::Flushing);
+        }
+        state.queue.push_back((running_time, frame));
+        Ok(FlowSuccess::Ok)
+    }
+
+    /// The background for a camera frame at `running_time`, or `None` if no background frame
+    /// was received yet. The returned Mat shares its data with the queued frame.
+    pub fn select(&self, running_time: Option<ClockTime>) -> Result<Option<Mat>, FilterError> {
+        let mut state = self.state.lock().unwrap();
+        let mut selected = None;
+        match running_time {
+            Some(running_time) => {
+                while let Some((frame_time, _)) = state.queue.front() {
+                    if frame_time.map_or(false, |frame_time| frame_time > running_time) {
+                        break;
+                    }
+                    selected = state.queue.pop_front().map(|(_, frame)| frame);
+                }
+            }
+            // Without timing information the newest frame is the best guess
+            None => {
+                selected = state.queue.pop_back().map(|(_, frame)| frame);
+                state.queue.clear();
+            }
+        }
+        if let Some(frame) = selected {
+            state.current = Some(frame);
+            self.changed.notify_all();
+        }
+        match &state.current {
+            Some(frame) if frame.size()? == state.size => Ok(Some(Mat::copy(frame)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Change the size of the camera frames. Frames of the old size are dropped.
+    pub fn set_size(&self, size: Size) {
+        let mut state = self.state.lock().unwrap();
+        if state.size != size {
+            state.size = size;
+            state.queue.clear();
+            state.current = None;
+            self.changed.notify_all();
+        }
+    }
+
+    pub fn size(&self) -> Size {
+        self.state.lock().unwrap().size
+    }
+
+    /// While flushing, queued frames are dropped and pushing fails. The last frame is kept, so
+    /// it can be reused after a seek.
+    pub fn set_flushing(&self, flushing: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.flushing = flushing;
+        if flushing {
+            state.queue.clear();
+        }
+        self.changed.notify_all();
+    }
+
+    /// Drop all frames, including the last one.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.queue.clear();
+        state.current = None;
+        self.changed.notify_all();
+    }
+}
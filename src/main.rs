@@ -2,60 +2,433 @@ use gstreamer::prelude::*;
 use gtk::prelude::*;
 use gtk::{Application, ApplicationWindow};
 use gtk4 as gtk;
-use crate::filter::Filter;
+use crate::cli::{BenchOptions, CaptureFormat, Command, FilterOptions, Options, ProcessOptions};
+use crate::filter::{Filter, FilterError};
+use quick_error::quick_error;
+use std::io::Write;
+use std::process::ExitCode;
 
+#[cfg(feature = "rvm")]
+mod asyncfilter;
+mod background;
+mod bench;
+mod blurfilter;
+mod chain;
+mod chromakeyfilter;
+mod cli;
+mod composite;
 mod plugin;
+mod preview;
+mod quality;
+mod refinefilter;
+mod registry;
+mod runtime;
 mod filter;
 mod filtertools;
+mod framemat;
 mod noopfilter;
+mod livebackground;
+mod maskpad;
+mod videoformat;
 #[cfg(feature = "rvm")]
 mod rvmfilter;
+#[cfg(feature = "rvm")]
+mod onnxsegfilter;
+#[cfg(feature = "rvm")]
+mod temporalfilter;
 
-fn main() {
-    gstreamer::init().unwrap();
-    plugin::plugin_register_static().unwrap();
+quick_error! {
+    #[derive(Debug)]
+    pub enum AppError {
+        Init(err: gstreamer::glib::Error) {
+            display("Failed to initialize GStreamer: {}", err)
+            from()
+        }
+        Plugin(err: gstreamer::glib::BoolError) {
+            display("Failed to register the fakecam plugin: {}", err)
+        }
+        MissingElement(factory: &'static str) {
+            display("GStreamer element {} is not available, is the plugin providing it installed?", factory)
+        }
+        Pipeline(description: String) {
+            display("Failed to build the pipeline: {}", description)
+        }
+        StateChange(err: gstreamer::StateChangeError) {
+            display("Failed to start the pipeline, check the log above for details: {}", err)
+            from()
+        }
+        Filter(err: FilterError) {
+            display("{}", err)
+            from()
+        }
+        Stream(element: String, error: String, debug: Option<String>) {
+            display("Error from element {}: {}{}", element, error,
+                debug.as_ref().map(|d| format!("\nDebugging information: {}", d)).unwrap_or_default())
+        }
+    }
+}
+
+fn make_element(factory: &'static str, name: &str) -> Result<gstreamer::Element, AppError> {
+    gstreamer::ElementFactory::make_with_name(factory, Some(name))
+        .map_err(|_| AppError::MissingElement(factory))
+}
+
+fn capture_caps(format: &CaptureFormat) -> gstreamer::Caps {
+    let mut caps = gstreamer::Caps::builder("video/x-raw");
+    if let Some(width) = format.width {
+        caps = caps.field("width", width);
+    }
+    if let Some(height) = format.height {
+        caps = caps.field("height", height);
+    }
+    if let Some(framerate) = format.framerate {
+        caps = caps.field("framerate", gstreamer::Fraction::new(framerate, 1));
+    }
+    caps.build()
+}
+
+/// Set the properties of the fakecam element from the command line options.
+fn configure_filter(filter: &gstreamer::Element, options: &FilterOptions) {
+    if let Some(name) = &options.name {
+        filter.set_property("filter", name.as_str());
+    }
+    filter.set_property("mode", options.mode);
+    if let Some(model) = &options.model {
+        filter.set_property("model-location", model.to_string_lossy().as_ref());
+    }
+    if let Some(model_config) = &options.model_config {
+        filter.set_property("model-config", model_config.to_string_lossy().as_ref());
+    }
+    if let Some(background) = &options.background {
+        filter.set_property("background-location", background.to_string_lossy().as_ref());
+    }
+    filter.set_property("background-fit", options.background_fit);
+    filter.set_property("blur-kind", options.blur_kind);
+    if let Some(blur_radius) = options.blur_radius {
+        filter.set_property("blur-radius", blur_radius);
+    }
+    filter.set_property("async-inference", options.async_inference);
+    filter.set_property("max-inference-interval", options.max_inference_interval);
+    if let Some(target_fps) = options.target_fps {
+        filter.set_property("target-fps", target_fps);
+    }
+    if let Some(key_color) = options.key_color {
+        filter.set_property("key-color", key_color);
+    }
+}
 
-    let src = gstreamer::ElementFactory::make_with_name("v4l2src", Some("src")).unwrap();
-    let cvt1 = gstreamer::ElementFactory::make_with_name("videoconvert", Some("cvt1")).unwrap();
-    let filter = gstreamer::ElementFactory::make_with_name("fakecam", Some("filter")).unwrap();
-    let cvt2 = gstreamer::ElementFactory::make_with_name("videoconvert", Some("cvt2")).unwrap();
-    let sink = gstreamer::ElementFactory::make_with_name("v4l2sink", Some("sink")).unwrap();
+fn build_pipeline(options: &Options) -> Result<gstreamer::Pipeline, AppError> {
+    let src = make_element("v4l2src", "src")?;
+    let capsfilter = make_element("capsfilter", "capsfilter")?;
+    let cvt1 = make_element("videoconvert", "cvt1")?;
+    let filter = make_element("fakecam", "filter")?;
+    let cvt2 = make_element("videoconvert", "cvt2")?;
+    let sink = make_element("v4l2sink", "sink")?;
 
-    let pipeline = gstreamer::Pipeline::new(Some("testpipeline"));
+    let pipeline = gstreamer::Pipeline::new(Some("fakecam"));
     pipeline
-        .add_many(&[&src, &cvt1, &filter, &cvt2, &sink])
-        .unwrap();
+        .add_many(&[&src, &capsfilter, &cvt1, &filter, &cvt2, &sink])
+        .map_err(|e| AppError::Pipeline(e.to_string()))?;
+
+    src.set_property("device", options.input.to_string_lossy().as_ref());
+    sink.set_property("device", options.output.to_string_lossy().as_ref());
+    capsfilter.set_property("caps", capture_caps(&options.format));
+    configure_filter(&filter, &options.filter);
 
-    src.set_property_from_str("device", "/dev/video0");
-    sink.set_property_from_str("device", "/dev/video4");
+    gstreamer::Element::link_many(&[&src, &capsfilter, &cvt1, &filter, &cvt2, &sink]).map_err(
+        |_| {
+            AppError::Pipeline(String::from(
+                "Failed to link the elements, does the camera support the requested format?",
+            ))
+        },
+    )?;
 
-    src.link(&cvt1).unwrap();
-    cvt1.link(&filter).unwrap();
-    filter.link(&cvt2).unwrap();
-    cvt2.link(&sink).unwrap();
+    Ok(pipeline)
+}
 
-    pipeline.set_state(gstreamer::State::Playing).unwrap();
+/// AAC encoders in order of preference, which ones are installed depends on the distribution.
+const AAC_ENCODERS: &[&str] = &["fdkaacenc", "avenc_aac", "voaacenc"];
+
+/// Encode the decoded audio `pad` and add it to the muxer. If no AAC encoder is installed the
+/// audio is dropped with a warning.
+fn link_audio(
+    pipeline: &gstreamer::Pipeline,
+    pad: &gstreamer::Pad,
+    mux: &gstreamer::Element,
+) -> Result<(), AppError> {
+    let encoder = match AAC_ENCODERS
+        .iter()
+        .find_map(|factory| gstreamer::ElementFactory::make(factory).build().ok())
+    {
+        Some(encoder) => encoder,
+        None => {
+            eprintln!(
+                "Warning: No AAC encoder found (tried {}), the output will have no audio",
+                AAC_ENCODERS.join(", ")
+            );
+            return Ok(());
+        }
+    };
+    let convert = make_element("audioconvert", "audio_convert")?;
+    let resample = make_element("audioresample", "audio_resample")?;
+    // The video encoder buffers a lot of frames, the audio has to wait for them in the muxer
+    let queue = make_element("queue", "audio_queue")?;
+    queue.set_property("max-size-buffers", 0u32);
+    queue.set_property("max-size-bytes", 0u32);
+    queue.set_property("max-size-time", 0u64);
 
-    let bus = pipeline.bus().unwrap();
-    for msg in bus.iter_timed(gstreamer::ClockTime::NONE) {
+    let elements = [&convert, &resample, &encoder, &queue];
+    pipeline
+        .add_many(&elements)
+        .map_err(|e| AppError::Pipeline(e.to_string()))?;
+    gstreamer::Element::link_many(&[&convert, &resample, &encoder, &queue, mux])
+        .map_err(|e| AppError::Pipeline(format!("Failed to link the audio encoder: {}", e)))?;
+    for element in elements {
+        element
+            .sync_state_with_parent()
+            .map_err(|e| AppError::Pipeline(e.to_string()))?;
+    }
+    let sink_pad = convert.static_pad("sink").expect("audioconvert without sink pad");
+    pad.link(&sink_pad)
+        .map_err(|e| AppError::Pipeline(format!("Failed to link the decoded audio: {}", e)))?;
+    Ok(())
+}
+
+/// Link a pad created by decodebin. The first video stream is filtered, the first audio
+/// stream is passed through and all other streams are ignored.
+fn link_decoded_pad(
+    pipeline: &gstreamer::Pipeline,
+    pad: &gstreamer::Pad,
+    video_sink: &gstreamer::Element,
+    mux: &gstreamer::Element,
+) -> Result<(), AppError> {
+    let caps = pad.current_caps().unwrap_or_else(|| pad.query_caps(None));
+    let media_type = match caps.structure(0) {
+        Some(structure) => structure.name().to_string(),
+        None => return Ok(()),
+    };
+    if media_type.starts_with("video/") {
+        let sink_pad = video_sink.static_pad("sink").expect("videoconvert without sink pad");
+        if !sink_pad.is_linked() {
+            pad.link(&sink_pad).map_err(|e| {
+                AppError::Pipeline(format!("Failed to link the decoded video: {}", e))
+            })?;
+        }
+    } else if media_type.starts_with("audio/") && pipeline.by_name("audio_convert").is_none() {
+        link_audio(pipeline, pad, mux)?;
+    }
+    Ok(())
+}
+
+fn build_process_pipeline(options: &ProcessOptions) -> Result<gstreamer::Pipeline, AppError> {
+    let src = make_element("filesrc", "src")?;
+    let decode = make_element("decodebin", "decode")?;
+    let cvt1 = make_element("videoconvert", "cvt1")?;
+    let filter = make_element("fakecam", "filter")?;
+    let cvt2 = make_element("videoconvert", "cvt2")?;
+    let encode = make_element("x264enc", "encode")?;
+    let queue = make_element("queue", "video_queue")?;
+    let mux = make_element("mp4mux", "mux")?;
+    let sink = make_element("filesink", "sink")?;
+
+    let pipeline = gstreamer::Pipeline::new(Some("fakecam-process"));
+    pipeline
+        .add_many(&[&src, &decode, &cvt1, &filter, &cvt2, &encode, &queue, &mux, &sink])
+        .map_err(|e| AppError::Pipeline(e.to_string()))?;
+
+    src.set_property("location", options.input.to_string_lossy().as_ref());
+    sink.set_property("location", options.output.to_string_lossy().as_ref());
+    configure_filter(&filter, &options.filter);
+
+    src.link(&decode)
+        .map_err(|e| AppError::Pipeline(format!("Failed to link the decoder: {}", e)))?;
+    gstreamer::Element::link_many(&[&cvt1, &filter, &cvt2, &encode, &queue, &mux, &sink])
+        .map_err(|e| AppError::Pipeline(format!("Failed to link the encoder: {}", e)))?;
+
+    // decodebin only creates its pads once it found the streams in the file
+    let pipeline_weak = pipeline.downgrade();
+    decode.connect_pad_added(move |decode, pad| {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        if let Err(e) = link_decoded_pad(&pipeline, pad, &cvt1, &mux) {
+            gstreamer::element_error!(decode, gstreamer::CoreError::Negotiation, ("{}", e));
+        }
+    });
+
+    Ok(pipeline)
+}
+
+/// How often the progress of the process subcommand is printed.
+const PROGRESS_INTERVAL: gstreamer::ClockTime = gstreamer::ClockTime::from_mseconds(500);
+
+fn print_progress(pipeline: &gstreamer::Pipeline) {
+    let position = match pipeline.query_position::<gstreamer::ClockTime>() {
+        Some(position) => position,
+        None => return,
+    };
+    match pipeline.query_duration::<gstreamer::ClockTime>() {
+        Some(duration) if duration > gstreamer::ClockTime::ZERO => print!(
+            "\rProcessed {:.0} of {:.0} ({:.1}%)",
+            position,
+            duration,
+            position.nseconds() as f64 / duration.nseconds() as f64 * 100.0
+        ),
+        _ => print!("\rProcessed {:.0}", position),
+    }
+    let _ = std::io::stdout().flush();
+}
+
+/// Play `pipeline` until it ends or fails. If `report_progress` is set the position is printed
+/// regularly.
+fn run_pipeline(pipeline: &gstreamer::Pipeline, report_progress: bool) -> Result<(), AppError> {
+    pipeline.set_state(gstreamer::State::Playing)?;
+
+    let bus = pipeline.bus().expect("Pipeline without bus");
+    let timeout = if report_progress {
+        Some(PROGRESS_INTERVAL)
+    } else {
+        gstreamer::ClockTime::NONE
+    };
+    let mut result = Ok(());
+    loop {
         use gstreamer::MessageView;
 
+        let msg = match bus.timed_pop(timeout) {
+            Some(msg) => msg,
+            None => {
+                print_progress(pipeline);
+                continue;
+            }
+        };
         match msg.view() {
             MessageView::Error(err) => {
-                eprintln!(
-                    "Error received from element {:?}: {}",
-                    err.src().map(|s| s.path_string()),
-                    err.error()
-                );
-                eprintln!("Debugging information: {:?}", err.debug());
+                result = Err(AppError::Stream(
+                    err.src()
+                        .map(|s| s.path_string().to_string())
+                        .unwrap_or_else(|| String::from("unknown")),
+                    err.error().to_string(),
+                    err.debug().map(|d| d.to_string()),
+                ));
                 break;
             }
             MessageView::Eos(..) => break,
             _ => (),
         }
     }
+    if report_progress {
+        println!();
+    }
 
-    pipeline
-        .set_state(gstreamer::State::Null)
-        .expect("Unable to set the pipeline to the `Null` state");
+    if pipeline.set_state(gstreamer::State::Null).is_err() {
+        eprintln!("Unable to set the pipeline to the `Null` state");
+    }
+    result
+}
+
+fn run(options: &Options) -> Result<(), AppError> {
+    gstreamer::init()?;
+    plugin::plugin_register_static().map_err(AppError::Plugin)?;
+
+    let pipeline = build_pipeline(options)?;
+    run_pipeline(&pipeline, false)
+}
+
+fn process(options: &ProcessOptions) -> Result<(), AppError> {
+    gstreamer::init()?;
+    plugin::plugin_register_static().map_err(AppError::Plugin)?;
+
+    let pipeline = build_process_pipeline(options)?;
+    run_pipeline(&pipeline, true)?;
+    println!("Wrote {}", options.output.display());
+    Ok(())
+}
+
+fn bench(options: &BenchOptions) -> Result<(), AppError> {
+    bench::run(options)?;
+    Ok(())
+}
+
+fn list_filters() {
+    for info in registry::filters() {
+        println!("{:<14} {}", info.name, info.description);
+    }
+    if !cfg!(feature = "rvm") {
+        println!(
+            "\nFilters using a matting model need the rvm feature, which is not compiled in."
+        );
+    }
+}
+
+fn main() -> ExitCode {
+    let result = match cli::parse(std::env::args().skip(1)) {
+        Ok(Command::Help) => {
+            print!("{}", cli::USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Ok(Command::ListFilters) => {
+            list_filters();
+            return ExitCode::SUCCESS;
+        }
+        Ok(Command::Run(options)) => run(&options),
+        Ok(Command::Process(options)) => process(&options),
+        Ok(Command::Bench(options)) => bench(&options),
+        Err(e) => {
+            eprintln!("Error: {}\n\n{}", e, cli::USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::background::FitMode;
+    use crate::blurfilter::BlurKind;
+    use crate::plugin::Mode;
+
+    #[test]
+    fn configure_filter_sets_properties() {
+        gstreamer::init().unwrap();
+        plugin::plugin_register_static().unwrap();
+        let filter = make_element("fakecam", "filter").unwrap();
+        let options = FilterOptions {
+            name: Some(String::from("noop")),
+            mode: Mode::Blur,
+            background_fit: FitMode::Contain,
+            blur_kind: BlurKind::Bokeh,
+            blur_radius: Some(21),
+            async_inference: true,
+            max_inference_interval: 3,
+            target_fps: Some(24.0),
+            key_color: Some(0x0000ff),
+            ..Default::default()
+        };
+        configure_filter(&filter, &options);
+
+        assert_eq!(
+            filter.property::<Option<String>>("filter").as_deref(),
+            Some("noop")
+        );
+        assert_eq!(filter.property::<Mode>("mode"), Mode::Blur);
+        assert_eq!(
+            filter.property::<FitMode>("background-fit"),
+            FitMode::Contain
+        );
+        assert_eq!(filter.property::<BlurKind>("blur-kind"), BlurKind::Bokeh);
+        assert_eq!(filter.property::<u32>("blur-radius"), 21);
+        assert!(filter.property::<bool>("async-inference"));
+        assert_eq!(filter.property::<u32>("max-inference-interval"), 3);
+        assert_eq!(filter.property::<f64>("target-fps"), 24.0);
+        assert_eq!(filter.property::<u32>("key-color"), 0x0000ff);
+    }
 }
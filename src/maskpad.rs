@@ -0,0 +1,241 @@
+//! This module drives the optional `mask_src` pad of the element, which outputs the alpha matte
+//! estimated for each frame next to the composited video. The matte is pushed either as GRAY8
+//! or, if downstream asks for it, as unscaled floats in `video/x-fakecam-matte` with one packed
+//! native-endian f32 per pixel.
+
+use crate::filter::FilterError;
+use crate::plugin::FILTER_ERROR_CAT;
+use gstreamer::prelude::*;
+use gstreamer::{Buffer, Caps, ClockTime, Event, EventView, FlowError, FlowSuccess, Pad};
+use gstreamer_video::{VideoFormat, VideoInfo};
+use opencv::core::{Scalar, CV_32FC1, CV_8UC1};
+use opencv::prelude::*;
+
+/// Media type of mattes with float values, GStreamer has no raw video format for this.
+pub const FLOAT_MEDIA_TYPE: &str = "video/x-fakecam-matte";
+const FLOAT_FORMAT: &str = "GRAY32F";
+
+/// The format the matte is pushed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskFormat {
+    /// 0 for background to 255 for foreground, rows padded to four bytes like GStreamer does
+    Gray8,
+    /// 0.0 for background to 1.0 for foreground, rows are not padded
+    Gray32F,
+}
+
+impl MaskFormat {
+    fn caps_builder(&self) -> gstreamer::caps::Builder<gstreamer::caps::NoFeature> {
+        match self {
+            MaskFormat::Gray8 => {
+                Caps::builder("video/x-raw").field("format", VideoFormat::Gray8.to_str())
+            }
+            MaskFormat::Gray32F => Caps::builder(FLOAT_MEDIA_TYPE).field("format", FLOAT_FORMAT),
+        }
+    }
+
+    /// Caps of mattes for frames described by `info`.
+    fn caps(&self, info: &VideoInfo) -> Caps {
+        self.caps_builder()
+            .field("width", info.width() as i32)
+            .field("height", info.height() as i32)
+            .field("framerate", info.fps())
+            .build()
+    }
+
+    fn stride(&self, width: usize) -> usize {
+        match self {
+            MaskFormat::Gray8 => (width + 3) & !3,
+            MaskFormat::Gray32F => width * std::mem::size_of::<f32>(),
+        }
+    }
+}
+
+/// The caps of the `mask_src` pad template, in order of preference.
+pub fn template_caps() -> Caps {
+    let mut caps = Caps::new_empty();
+    for format in &[MaskFormat::Gray8, MaskFormat::Gray32F] {
+        caps.merge(
+            format
+                .caps_builder()
+                .field("width", gstreamer::IntRange::new(1, i32::MAX))
+                .field("height", gstreamer::IntRange::new(1, i32::MAX))
+                .field(
+                    "framerate",
+                    gstreamer::FractionRange::new(
+                        gstreamer::Fraction::new(0, 1),
+                        gstreamer::Fraction::new(i32::MAX, 1),
+                    ),
+                )
+                .build(),
+        );
+    }
+    caps
+}
+
+/// Convert `matte`, a CV_32FC1 matte of the given size, to the bytes of a buffer in `format`.
+/// Without a matte the whole frame is treated as foreground.
+pub fn matte_to_bytes(
+    matte: Option<&Mat>,
+    format: MaskFormat,
+    width: i32,
+    height: i32,
+) -> Result<Vec<u8>, FilterError> {
+    let (typ, scale) = match format {
+        MaskFormat::Gray8 => (CV_8UC1, 255.0),
+        MaskFormat::Gray32F => (CV_32FC1, 1.0),
+    };
+    let mut mask = Mat::default();
+    match matte {
+        Some(pha) if pha.rows() == height && pha.cols() == width => {
+            pha.convert_to(&mut mask, typ, scale, 0.0)?
+        }
+        Some(pha) => {
+            return Err(FilterError::Other(format!(
+                "Matte has size {}x{} but frames are {}x{}",
+                pha.cols(),
+                pha.rows(),
+                width,
+                height
+            )))
+        }
+        None => mask = Mat::new_rows_cols_with_default(height, width, typ, Scalar::all(scale))?,
+    }
+    // convert_to allocates a new continuous Mat, so rows directly follow each other
+    let data = mask.data_bytes()?;
+    let row_len = data.len() / height.max(1) as usize;
+    let stride = format.stride(width as usize);
+    if stride == row_len {
+        return Ok(data.to_vec());
+    }
+    let mut bytes = vec![0; stride * height as usize];
+    for (dst, src) in bytes.chunks_exact_mut(stride).zip(data.chunks_exact(row_len)) {
+        dst[..row_len].copy_from_slice(src);
+    }
+    Ok(bytes)
+}
+
+/// State of the `mask_src` pad. The sticky events of the sink pad are replayed on it before
+/// the first matte, since the pad may be requested while the element is already streaming.
+#[derive(Debug)]
+pub struct MaskPad {
+    pad: Pad,
+    stream_id: Option<String>,
+    segment: Option<Event>,
+    /// Format and frame info of the caps last pushed
+    negotiated: Option<(MaskFormat, VideoInfo)>,
+    need_stream_start: bool,
+    need_segment: bool,
+}
+
+impl MaskPad {
+    pub fn new(pad: Pad) -> MaskPad {
+        MaskPad {
+            pad,
+            stream_id: None,
+            segment: None,
+            negotiated: None,
+            need_stream_start: true,
+            need_segment: true,
+        }
+    }
+
+    pub fn pad(&self) -> &Pad {
+        &self.pad
+    }
+
+    /// Track or forward an event received on the sink pad.
+    pub fn sink_event(&mut self, event: &Event) {
+        match event.view() {
+            EventView::StreamStart(e) => {
+                self.stream_id = Some(format!("{}/mask", e.stream_id()));
+                self.need_stream_start = true;
+            }
+            EventView::Segment(e) => {
+                self.segment = Some(gstreamer::event::Segment::new(e.segment()));
+                self.need_segment = true;
+            }
+            EventView::FlushStart(_) | EventView::FlushStop(_) | EventView::Eos(_) => {
+                self.pad.push_event(event.clone());
+            }
+            _ => (),
+        }
+    }
+
+    /// Negotiate the format for mattes of frames described by `info` and push the sticky
+    /// events downstream still has to see. Returns `None` if downstream accepts no format.
+    pub fn prepare(&mut self, info: &VideoInfo) -> Option<MaskFormat> {
+        if self.need_stream_start {
+            let stream_id = match &self.stream_id {
+                Some(stream_id) => stream_id.clone(),
+                None => self.pad.create_stream_id(&self.element()?, None).to_string(),
+            };
+            self.pad
+                .push_event(gstreamer::event::StreamStart::new(&stream_id));
+            self.need_stream_start = false;
+            self.negotiated = None;
+        }
+        let format = match &self.negotiated {
+            Some((format, negotiated)) if negotiated == info => *format,
+            _ => {
+                let format = self.negotiate(info)?;
+                self.negotiated = Some((format, info.clone()));
+                format
+            }
+        };
+        if self.need_segment {
+            if let Some(segment) = &self.segment {
+                self.pad.push_event(segment.clone());
+            }
+            self.need_segment = false;
+        }
+        Some(format)
+    }
+
+    fn negotiate(&self, info: &VideoInfo) -> Option<MaskFormat> {
+        // An unlinked pad returns the template caps, so GRAY8 is used until something links
+        let peer_caps = self.pad.peer_query_caps(Some(&self.pad.pad_template_caps()));
+        let format = [MaskFormat::Gray8, MaskFormat::Gray32F]
+            .iter()
+            .copied()
+            .find(|format| peer_caps.can_intersect(&format.caps(info)))?;
+        if self.pad.push_event(gstreamer::event::Caps::new(&format.caps(info))) {
+            Some(format)
+        } else {
+            None
+        }
+    }
+
+    fn element(&self) -> Option<gstreamer::Element> {
+        self.pad
+            .parent()
+            .and_then(|parent| parent.downcast::<gstreamer::Element>().ok())
+    }
+}
+
+/// Push a matte with the timestamps of the frame it was estimated from. A pad which is not
+/// linked, flushing or already received EOS is not an error, the matte is simply dropped. The
+/// video itself goes on either way.
+pub fn push_matte(
+    pad: &Pad,
+    bytes: Vec<u8>,
+    pts: Option<ClockTime>,
+    dts: Option<ClockTime>,
+    duration: Option<ClockTime>,
+) -> Result<FlowSuccess, FlowError> {
+    let mut buffer = Buffer::from_mut_slice(bytes);
+    {
+        let buffer = buffer.get_mut().expect("Newly created buffer is writable");
+        buffer.set_pts(pts);
+        buffer.set_dts(dts);
+        buffer.set_duration(duration);
+    }
+    match pad.push(buffer) {
+        Err(FlowError::NotLinked) => Ok(FlowSuccess::Ok),
+        Err(e @ FlowError::Flushing) | Err(e @ FlowError::Eos) => {
+            gstreamer::debug!(&*FILTER_ERROR_CAT, obj: pad, "Dropped matte: {:?}", e);
+            Ok(FlowSuccess::Ok)
+        }
+        other => other,
+    }
+}
@@ -0,0 +1,498 @@
+//! This filter runs single-shot person segmentation models such as MediaPipe Selfie
+//! Segmentation, MODNet or PP-HumanSeg. Unlike RVM these models take a single image of a fixed
+//! size and output a matte or segmentation mask for it, without recurrent state.
+//!
+//! As the models differ in their preprocessing, it is described in a small config file next to
+//! the model, with one `key = value` pair per line and `#` starting a comment:
+//!
+//! ```text
+//! # MODNet
+//! width = 512
+//! height = 512
+//! mean = 0.5, 0.5, 0.5
+//! std = 0.5, 0.5, 0.5
+//! ```
+//!
+//! The supported keys are
+//! * `width`, `height`: The size of the model input, only needed if the model does not fix it
+//! * `mean`, `std`: Per channel normalization applied to values in 0..1 [default: 0 and 1]
+//! * `channel_order`: `rgb` or `bgr` [default: rgb]
+//! * `layout`: `nchw` or `nhwc`, detected from the input shape if not given
+//! * `output`: Name of the output holding the matte [default: the first output]
+//! * `output_channel`: The channel of the output holding the person [default: the last one]
+//! * `activation`: `none`, `sigmoid` or `softmax` over the channels [default: none]
+
+use crate::filter::{FilterError, Matte, MatteEstimator, StageTimings};
+use crate::runtime::{new_session, RuntimeOptions};
+use onnxruntime::ndarray::{self, IxDyn};
+use onnxruntime::session::Session;
+use opencv::core::Size;
+use opencv::imgproc::{resize, INTER_AREA, INTER_LINEAR};
+use opencv::prelude::*;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelOrder {
+    Rgb,
+    Bgr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Channels first, as used by most PyTorch exports
+    Nchw,
+    /// Channels last, as used by TensorFlow exports
+    Nhwc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activation {
+    /// The model outputs probabilities already
+    None,
+    Sigmoid,
+    /// Softmax over the output channels, for models with one channel per class
+    Softmax,
+}
+
+impl FromStr for ChannelOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rgb" => Ok(ChannelOrder::Rgb),
+            "bgr" => Ok(ChannelOrder::Bgr),
+            other => Err(format!("Unknown channel_order '{}', expected rgb or bgr", other)),
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nchw" => Ok(Layout::Nchw),
+            "nhwc" => Ok(Layout::Nhwc),
+            other => Err(format!("Unknown layout '{}', expected nchw or nhwc", other)),
+        }
+    }
+}
+
+impl FromStr for Activation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Activation::None),
+            "sigmoid" => Ok(Activation::Sigmoid),
+            "softmax" => Ok(Activation::Softmax),
+            other => Err(format!(
+                "Unknown activation '{}', expected none, sigmoid or softmax",
+                other
+            )),
+        }
+    }
+}
+
+/// The preprocessing and postprocessing of a segmentation model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentationConfig {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+    pub channel_order: ChannelOrder,
+    pub layout: Option<Layout>,
+    pub output: Option<String>,
+    pub output_channel: Option<usize>,
+    pub activation: Activation,
+}
+
+impl Default for SegmentationConfig {
+    fn default() -> Self {
+        SegmentationConfig {
+            width: None,
+            height: None,
+            mean: [0.0; 3],
+            std: [1.0; 3],
+            channel_order: ChannelOrder::Rgb,
+            layout: None,
+            output: None,
+            output_channel: None,
+            activation: Activation::None,
+        }
+    }
+}
+
+fn parse_triple(key: &str, value: &str) -> Result<[f32; 3], String> {
+    let values: Vec<f32> = value
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Invalid {} '{}': {}", key, value, e))?;
+    match values.as_slice() {
+        [v] => Ok([*v; 3]),
+        [r, g, b] => Ok([*r, *g, *b]),
+        _ => Err(format!("Expected one or three values for {}, got '{}'", key, value)),
+    }
+}
+
+fn parse_number<T: FromStr>(key: &str, value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("Invalid {} '{}': {}", key, value, e))
+}
+
+impl FromStr for SegmentationConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = SegmentationConfig::default();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| format!("Line {}: expected 'key = value'", number + 1))?;
+            let result = match key {
+                "width" => parse_number(key, value).map(|v| config.width = Some(v)),
+                "height" => parse_number(key, value).map(|v| config.height = Some(v)),
+                "mean" => parse_triple(key, value).map(|v| config.mean = v),
+                "std" => parse_triple(key, value).and_then(|v| {
+                    if v.iter().any(|std| *std == 0.0) {
+                        return Err(String::from("std must not be 0"));
+                    }
+                    config.std = v;
+                    Ok(())
+                }),
+                "channel_order" => value.parse().map(|v| config.channel_order = v),
+                "layout" => value.parse().map(|v| config.layout = Some(v)),
+                "output" => {
+                    config.output = Some(String::from(value));
+                    Ok(())
+                }
+                "output_channel" => {
+                    parse_number(key, value).map(|v| config.output_channel = Some(v))
+                }
+                "activation" => value.parse().map(|v| config.activation = v),
+                other => Err(format!("Unknown key '{}'", other)),
+            };
+            result.map_err(|e| format!("Line {}: {}", number + 1, e))?;
+        }
+        Ok(config)
+    }
+}
+
+impl SegmentationConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SegmentationConfig, FilterError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            FilterError::Other(format!("Failed to read model config {}: {}", path.display(), e))
+        })?;
+        contents.parse().map_err(|e| {
+            FilterError::Other(format!("Invalid model config {}: {}", path.display(), e))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct OnnxSegFilter {
+    session: Session<'static>,
+    config: SegmentationConfig,
+    layout: Layout,
+    /// Size of the model input
+    size: Size,
+    /// Index of the output holding the matte
+    output: usize,
+    /// The resized input frame
+    resized: Mat,
+    timings: StageTimings,
+}
+
+// Session does not implement Send, see RVMFilter
+unsafe impl Send for OnnxSegFilter {}
+
+impl OnnxSegFilter {
+    /// Load the segmentation model from `model_file`, checking its inputs and outputs against
+    /// `config`. `runtime` configures ONNX Runtime.
+    pub fn new<P: AsRef<Path> + 'static>(
+        model_file: P,
+        config: SegmentationConfig,
+        runtime: &RuntimeOptions,
+    ) -> Result<OnnxSegFilter, FilterError> {
+        let session = new_session(model_file, runtime)?;
+
+        let input = match session.inputs.as_slice() {
+            [input] => input,
+            inputs => {
+                return Err(FilterError::Other(format!(
+                    "Expected a model with a single image input, got {} inputs",
+                    inputs.len()
+                )))
+            }
+        };
+        if input.dimensions.len() != 4 {
+            return Err(FilterError::Other(format!(
+                "Expected a four dimensional input, got {:?}",
+                input.dimensions
+            )));
+        }
+        let layout = match (config.layout, input.dimensions[1], input.dimensions[3]) {
+            (Some(layout), _, _) => layout,
+            (None, Some(3), _) => Layout::Nchw,
+            (None, _, Some(3)) => Layout::Nhwc,
+            (None, _, _) => {
+                return Err(FilterError::Other(format!(
+                    "Cannot detect the layout of input {:?}, set it in the model config",
+                    input.dimensions
+                )))
+            }
+        };
+        let (height, width) = match layout {
+            Layout::Nchw => (input.dimensions[2], input.dimensions[3]),
+            Layout::Nhwc => (input.dimensions[1], input.dimensions[2]),
+        };
+        let width = width.or(config.width).ok_or_else(|| {
+            FilterError::Other(String::from("The model has no fixed width, set it in the config"))
+        })?;
+        let height = height.or(config.height).ok_or_else(|| {
+            FilterError::Other(String::from("The model has no fixed height, set it in the config"))
+        })?;
+
+        if session.outputs.is_empty() {
+            return Err(FilterError::Other(String::from("The model has no outputs")));
+        }
+        let output = match &config.output {
+            Some(name) => session
+                .outputs
+                .iter()
+                .position(|output| &output.name == name)
+                .ok_or_else(|| FilterError::Other(format!("The model has no output {}", name)))?,
+            None => 0,
+        };
+
+        Ok(OnnxSegFilter {
+            session,
+            config,
+            layout,
+            size: Size::new(width as i32, height as i32),
+            output,
+            resized: Mat::default(),
+            timings: StageTimings::default(),
+        })
+    }
+
+    /// Resize and normalize `src_image` into the input tensor of the model.
+    fn preprocess(&mut self, src_image: &Mat) -> Result<ndarray::ArrayD<f32>, FilterError> {
+        resize(src_image, &mut self.resized, self.size, 0.0, 0.0, INTER_AREA)?;
+        let pixels = self.resized.data_bytes()?;
+        let (width, height) = (self.size.width as usize, self.size.height as usize);
+        let channels: [usize; 3] = match self.config.channel_order {
+            ChannelOrder::Rgb => [0, 1, 2],
+            ChannelOrder::Bgr => [2, 1, 0],
+        };
+        let shape = match self.layout {
+            Layout::Nchw => IxDyn(&[1, 3, height, width]),
+            Layout::Nhwc => IxDyn(&[1, height, width, 3]),
+        };
+        let mut tensor = ndarray::ArrayD::<f32>::zeros(shape);
+        let data = tensor
+            .as_slice_mut()
+            .expect("Newly created arrays are contiguous");
+        for (i, pixel) in pixels.chunks_exact(3).enumerate() {
+            for (c, &src_c) in channels.iter().enumerate() {
+                let value = (pixel[src_c] as f32 / 255.0 - self.config.mean[c]) / self.config.std[c];
+                let index = match self.layout {
+                    Layout::Nchw => c * width * height + i,
+                    Layout::Nhwc => i * 3 + c,
+                };
+                data[index] = value;
+            }
+        }
+        Ok(tensor)
+    }
+
+    /// Extract the person channel from the model output as a matte of the model size.
+    fn postprocess(&self, output: &ndarray::ArrayViewD<f32>) -> Result<Mat, FilterError> {
+        let shape = output.shape();
+        // Outputs are either [N, H, W], or [N, C, H, W] / [N, H, W, C] depending on the layout
+        let (channels, height, width) = match (shape, self.layout) {
+            ([_, h, w], _) => (1, *h, *w),
+            ([_, c, h, w], Layout::Nchw) => (*c, *h, *w),
+            ([_, h, w, c], Layout::Nhwc) => (*c, *h, *w),
+            _ => {
+                return Err(FilterError::Other(format!(
+                    "Unsupported output shape {:?}",
+                    shape
+                )))
+            }
+        };
+        let channel = self.config.output_channel.unwrap_or(channels - 1);
+        if channel >= channels {
+            return Err(FilterError::Other(format!(
+                "Output channel {} does not exist, the output has {} channels",
+                channel, channels
+            )));
+        }
+        let data = output
+            .as_slice()
+            .ok_or_else(|| FilterError::Other(String::from("Output tensor is not contiguous")))?;
+        let value = |c: usize, i: usize| match self.layout {
+            Layout::Nchw => data[c * width * height + i],
+            Layout::Nhwc => data[i * channels + c],
+        };
+        let pha: Vec<f32> = (0..width * height)
+            .map(|i| match self.config.activation {
+                Activation::None => value(channel, i),
+                Activation::Sigmoid => 1.0 / (1.0 + (-value(channel, i)).exp()),
+                Activation::Softmax => {
+                    let max = (0..channels)
+                        .map(|c| value(c, i))
+                        .fold(f32::NEG_INFINITY, f32::max);
+                    let sum: f32 = (0..channels).map(|c| (value(c, i) - max).exp()).sum();
+                    (value(channel, i) - max).exp() / sum
+                }
+            })
+            .map(|v| v.clamp(0.0, 1.0))
+            .collect();
+        Ok(Mat::from_slice(&pha)?.reshape(1, height as i32)?.try_clone()?)
+    }
+}
+
+impl MatteEstimator for OnnxSegFilter {
+    fn estimate(&mut self, src_image: &Mat) -> Result<Matte, FilterError> {
+        if src_image.dims() != 2 || src_image.channels() != 3 {
+            return Err(FilterError::Other(format!(
+                "Expected a WHC source image (where C=3), got {:?} with {} channels",
+                src_image.mat_size(),
+                src_image.channels()
+            )));
+        }
+        self.timings.clear();
+        let start = Instant::now();
+        let input = self.preprocess(src_image)?;
+        self.timings.record("preprocess", start);
+
+        let start = Instant::now();
+        let outputs = self.session.run::<f32, f32, IxDyn>(vec![input])?;
+        self.timings.record("inference", start);
+
+        let start = Instant::now();
+        let output = outputs.get(self.output).ok_or_else(|| {
+            FilterError::Other(format!("The model returned only {} outputs", outputs.len()))
+        })?;
+        let small_pha = self.postprocess(&output.view())?;
+        let mut pha = Mat::default();
+        resize(&small_pha, &mut pha, src_image.size()?, 0.0, 0.0, INTER_LINEAR)?;
+        self.timings.record("postprocess", start);
+        Ok(Matte { fgr: None, pha })
+    }
+
+    fn timings(&self) -> Option<&StageTimings> {
+        Some(&self.timings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_configs() {
+        let modnet = SegmentationConfig {
+            width: Some(512),
+            height: Some(512),
+            mean: [0.5; 3],
+            std: [0.5; 3],
+            ..SegmentationConfig::default()
+        };
+        let cases: &[(&str, Result<SegmentationConfig, ()>)] = &[
+            ("", Ok(SegmentationConfig::default())),
+            // Missing keys keep their defaults, comments and blank lines are skipped
+            ("# nothing\n\n   \n", Ok(SegmentationConfig::default())),
+            (
+                "# MODNet\nwidth = 512\nheight = 512\nmean = 0.5, 0.5, 0.5\nstd = 0.5\n",
+                Ok(modnet),
+            ),
+            (
+                "width=256 # inline comment\nchannel_order = bgr\nlayout = nhwc",
+                Ok(SegmentationConfig {
+                    width: Some(256),
+                    channel_order: ChannelOrder::Bgr,
+                    layout: Some(Layout::Nhwc),
+                    ..SegmentationConfig::default()
+                }),
+            ),
+            (
+                "output = segment_back\noutput_channel = 0\nactivation = softmax",
+                Ok(SegmentationConfig {
+                    output: Some(String::from("segment_back")),
+                    output_channel: Some(0),
+                    activation: Activation::Softmax,
+                    ..SegmentationConfig::default()
+                }),
+            ),
+            (
+                "activation = sigmoid",
+                Ok(SegmentationConfig {
+                    activation: Activation::Sigmoid,
+                    ..SegmentationConfig::default()
+                }),
+            ),
+            ("activation = none", Ok(SegmentationConfig::default())),
+            // Unknown keys and values
+            ("size = 512", Err(())),
+            ("Width = 512", Err(())),
+            ("layout = chw", Err(())),
+            ("layout = NCHW", Err(())),
+            ("channel_order = rbg", Err(())),
+            ("activation = relu", Err(())),
+            ("activation =", Err(())),
+            // Malformed values
+            ("width", Err(())),
+            ("width = -1", Err(())),
+            ("height = 1.5", Err(())),
+            ("mean = 0.5, 0.5", Err(())),
+            ("mean = a, b, c", Err(())),
+            ("std = 0.5, 0, 0.5", Err(())),
+            ("output_channel = last", Err(())),
+        ];
+        for (text, expected) in cases {
+            let parsed = text.parse::<SegmentationConfig>().map_err(|_| ());
+            assert_eq!(&parsed, expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = "width = 512\n# comment\nlayout = chw"
+            .parse::<SegmentationConfig>()
+            .unwrap_err();
+        assert!(error.starts_with("Line 3:"), "{}", error);
+    }
+
+    #[test]
+    fn load_config_files() {
+        let dir = std::env::temp_dir();
+        let valid = dir.join("fakecam-onnxseg-test-valid.conf");
+        std::fs::write(&valid, "width = 256\nheight = 144\n").unwrap();
+        let config = SegmentationConfig::load(&valid).unwrap();
+        assert_eq!((config.width, config.height), (Some(256), Some(144)));
+
+        let invalid = dir.join("fakecam-onnxseg-test-invalid.conf");
+        std::fs::write(&invalid, "layout = chw\n").unwrap();
+        assert!(SegmentationConfig::load(&invalid).is_err());
+        assert!(SegmentationConfig::load(dir.join("fakecam-onnxseg-test-missing.conf")).is_err());
+
+        std::fs::remove_file(valid).unwrap();
+        std::fs::remove_file(invalid).unwrap();
+    }
+}
@@ -1,23 +1,42 @@
-use crate::filter::Filter;
+use crate::background::{Background, FitMode};
+use crate::blurfilter::BlurKind;
+use crate::chromakeyfilter::ChromaKey;
+use crate::composite::Blending;
 use crate::filter::FilterError;
+use crate::filter::{Filter, FrameMeta, PostProcessing};
+use crate::framemat::FrameMat;
+use crate::livebackground::{BackgroundInput, FrameQueue};
+use crate::maskpad::{self, MaskFormat, MaskPad};
 use crate::noopfilter::NoopFilter;
-use crate::rvmfilter::RVMFilter;
-use core::ffi::c_void;
+use crate::preview::Preview;
+use crate::quality::QualityController;
+use crate::refinefilter::Refinement;
+use crate::registry;
+use crate::runtime::{ExecutionProvider, RuntimeOptions};
+use crate::videoformat::{FormatConverter, ALPHA_FORMATS, SUPPORTED_FORMATS};
 use gstreamer::glib;
 use gstreamer::prelude::*;
 use gstreamer::subclass::prelude::*;
 use gstreamer::subclass::ElementMetadata;
 use gstreamer::Caps;
 use gstreamer::FlowError;
+use gstreamer_base::prelude::*;
 use gstreamer_base::subclass::base_transform::BaseTransformMode;
 use gstreamer_base::subclass::prelude::*;
 use gstreamer_video::{VideoFormat, VideoFrameRef, VideoInfo};
 use once_cell::sync::Lazy;
-use opencv::core::{Scalar, CV_8UC3};
+use opencv::core::{Scalar, Size};
+use opencv::imgproc::{
+    cvt_color, COLOR_BGRA2BGR, COLOR_RGB2BGR, COLOR_RGBA2BGR, COLOR_RGBA2BGRA,
+};
 use opencv::prelude::*;
-use std::sync::Mutex;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
-static FILTER_ERROR_CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
+pub(crate) static FILTER_ERROR_CAT: Lazy<gstreamer::DebugCategory> = Lazy::new(|| {
     gstreamer::DebugCategory::new(
         "fakecam",
         gstreamer::DebugColorFlags::empty() | gstreamer::DebugColorFlags::FG_RED,
@@ -31,38 +50,669 @@ impl Into<gstreamer::LoggableError> for FilterError {
     }
 }
 
+/// What the element does with the background behind the person.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, glib::Enum)]
+#[enum_type(name = "FakecamMode")]
+pub enum Mode {
+    /// Pass the frames through unmodified
+    #[enum_value(name = "Pass the frames through unmodified", nick = "noop")]
+    Noop,
+    /// Blur the background
+    #[enum_value(name = "Blur the background", nick = "blur")]
+    Blur,
+    /// Replace the background with the configured background image
+    #[enum_value(name = "Replace the background", nick = "replace")]
+    Replace,
+    /// Output RGBA with the estimated matte as alpha channel, leaving the compositing to
+    /// downstream elements
+    #[enum_value(name = "Output the matte as alpha channel", nick = "alpha")]
+    Alpha,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Noop => "noop",
+            Mode::Blur => "blur",
+            Mode::Replace => "replace",
+            Mode::Alpha => "alpha",
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "noop" => Ok(Mode::Noop),
+            "blur" => Ok(Mode::Blur),
+            "replace" => Ok(Mode::Replace),
+            "alpha" => Ok(Mode::Alpha),
+            other => Err(format!(
+                "Unknown mode '{}', expected one of noop, blur, replace or alpha",
+                other
+            )),
+        }
+    }
+}
+
+const DEFAULT_MODE: Mode = Mode::Replace;
+const DEFAULT_BACKGROUND_FIT: FitMode = FitMode::Cover;
+const DEFAULT_DOWNSAMPLE_RATIO: f64 = 0.25;
+const DEFAULT_BLUR_KIND: BlurKind = BlurKind::Gaussian;
+const DEFAULT_BLUR_RADIUS: u32 = 15;
+const DEFAULT_ASYNC_INFERENCE: bool = false;
+const DEFAULT_MAX_INFERENCE_INTERVAL: u32 = 1;
+const DEFAULT_TARGET_FPS: f64 = 0.0;
+const DEFAULT_KEY_COLOR: u32 = 0x00ff00;
+const DEFAULT_KEY_TOLERANCE: f64 = 25.0;
+const DEFAULT_KEY_SOFTNESS: f64 = 15.0;
+const DEFAULT_SPILL_SUPPRESSION: f64 = 0.5;
+
+/// The configuration of the element as set through its properties. This is also used by the
+/// benchmark to build filters the same way the element does.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Name of the filter in the [`registry`], derived from the mode if it is `None`
+    pub filter: Option<String>,
+    pub model_location: Option<String>,
+    /// Config of a segmentation model, if the model is not RVM
+    pub model_config: Option<String>,
+    pub mode: Mode,
+    pub background_location: Option<String>,
+    pub background_fit: FitMode,
+    pub downsample_ratio: f64,
+    /// How ONNX Runtime runs the model
+    pub runtime: RuntimeOptions,
+    pub blur_kind: BlurKind,
+    pub blur_radius: u32,
+    /// Run the model on a worker thread and composite each frame with the newest matte
+    pub async_inference: bool,
+    /// Run the model at least every this many frames and warp the matte in between
+    pub max_inference_interval: u32,
+    /// Framerate the quality controller aims for, 0 disables it
+    pub target_fps: f64,
+    /// Colour keyed out by the chroma filter as 0xRRGGBB
+    pub key_color: u32,
+    /// Hue distance in degrees up to which the chroma filter keys out pixels completely
+    pub key_tolerance: f64,
+    /// Hue distance in degrees beyond the tolerance over which the chroma key fades out
+    pub key_softness: f64,
+    /// How much of the key colour reflected onto the person is removed, in 0..1
+    pub spill_suppression: f64,
+    /// How the matte is refined before compositing
+    pub refinement: Refinement,
+    /// How the person is made to blend in with the background
+    pub blending: Blending,
+    /// Show the output and the matte in windows, for debugging
+    pub debug_preview: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            filter: None,
+            model_location: None,
+            model_config: None,
+            mode: DEFAULT_MODE,
+            background_location: None,
+            background_fit: DEFAULT_BACKGROUND_FIT,
+            downsample_ratio: DEFAULT_DOWNSAMPLE_RATIO,
+            runtime: RuntimeOptions::default(),
+            blur_kind: DEFAULT_BLUR_KIND,
+            blur_radius: DEFAULT_BLUR_RADIUS,
+            async_inference: DEFAULT_ASYNC_INFERENCE,
+            max_inference_interval: DEFAULT_MAX_INFERENCE_INTERVAL,
+            target_fps: DEFAULT_TARGET_FPS,
+            key_color: DEFAULT_KEY_COLOR,
+            key_tolerance: DEFAULT_KEY_TOLERANCE,
+            key_softness: DEFAULT_KEY_SOFTNESS,
+            spill_suppression: DEFAULT_SPILL_SUPPRESSION,
+            refinement: Refinement::default(),
+            blending: Blending::default(),
+            debug_preview: false,
+        }
+    }
+}
+
+impl Settings {
+    /// The colour keyed out by the chroma filter.
+    pub fn chroma_key(&self) -> ChromaKey {
+        ChromaKey {
+            color: self.key_color,
+            tolerance: self.key_tolerance,
+            softness: self.key_softness,
+            spill_suppression: self.spill_suppression,
+        }
+    }
+
+    /// The settings the running filter can change, see [`Filter::set_post_processing`].
+    pub fn post_processing(&self) -> PostProcessing {
+        PostProcessing {
+            key: self.chroma_key(),
+            refinement: self.refinement,
+            blending: self.blending,
+        }
+    }
+
+    /// The name of the filter to build.
+    pub fn filter_name(&self) -> &str {
+        match &self.filter {
+            Some(filter) => filter,
+            None => registry::default_filter(self.mode, self.model_config.is_some()),
+        }
+    }
+}
+
+/// Pts, dts and duration of a buffer.
+type Timestamps = (
+    Option<gstreamer::ClockTime>,
+    Option<gstreamer::ClockTime>,
+    Option<gstreamer::ClockTime>,
+);
+
+/// Describe the frame in `buffer` for the filters.
+fn frame_meta(buffer: &gstreamer::BufferRef) -> FrameMeta {
+    FrameMeta {
+        pts: buffer.pts().map(|pts| Duration::from_nanos(pts.nseconds())),
+        duration: buffer
+            .duration()
+            .map(|duration| Duration::from_nanos(duration.nseconds())),
+        discont: buffer.flags().contains(gstreamer::BufferFlags::DISCONT),
+    }
+}
+
+static GREEN: Lazy<Scalar> = Lazy::new(|| Scalar::new(0.0, 255.0, 0.0, 255.0));
+
+/// Construct the filter described by `settings`.
+pub fn build_filter(settings: &Settings) -> Result<Box<dyn Filter>, FilterError> {
+    registry::build(settings.filter_name(), settings)
+}
+
+/// Load the background described by `settings` for frames of the given size. If no
+/// background-location is set a green background is used.
+pub fn build_background(settings: &Settings, size: Size) -> Result<Background, FilterError> {
+    match &settings.background_location {
+        Some(location) => Background::open(location, settings.background_fit, size),
+        None => Background::solid(*GREEN, size),
+    }
+}
+
 mod imp {
     use super::*;
 
     #[derive(Debug)]
     pub struct FakecamTransform {
+        settings: Mutex<Settings>,
+        /// Info of the input frames
         video_info: Mutex<VideoInfo>,
+        /// Info of the output frames, only differs from the input in alpha mode
+        out_info: Mutex<VideoInfo>,
         filter: Mutex<Box<dyn Filter>>,
-        bg_frame: Mutex<Mat>,
+        /// Set when a property changed which requires the filter to be rebuilt
+        filter_dirty: AtomicBool,
+        /// Set when a property changed which the running filter can apply, see
+        /// [`Filter::set_post_processing`]
+        post_processing_dirty: AtomicBool,
+        /// Set when the filter has to forget previous frames, e.g. after a seek
+        filter_reset: AtomicBool,
+        background: Mutex<Background>,
+        /// Converts frames which are not RGB for the filter
+        converter: Mutex<FormatConverter>,
+        /// The `mask_src` pad, if one was requested
+        mask: Mutex<Option<MaskPad>>,
+        /// The `background` pad, if one was requested
+        background_pad: Mutex<Option<gstreamer::Pad>>,
+        background_input: Mutex<BackgroundInput>,
+        /// Frames received on the background pad, used instead of `background` once the first
+        /// one arrived
+        background_queue: FrameQueue,
+        preview: Mutex<Preview>,
+        quality: Mutex<QualityController>,
+        /// Holds the BGRA output before it is copied to the frame, kept to reuse its buffer
+        bgra: Mutex<Mat>,
     }
 
-    const GREEN: Lazy<Scalar> = Lazy::new(|| Scalar::new(0.0, 255.0, 0.0, 255.0));
-
     impl Default for FakecamTransform {
         fn default() -> Self {
             let fmt = VideoFormat::Rgb;
             let width: u32 = 1920;
             let height: u32 = 1080;
+            let info = VideoInfo::builder(fmt, width, height)
+                .build()
+                .expect("Default video info for transform was invalid.");
             FakecamTransform {
-                video_info: Mutex::new(
-                    VideoInfo::builder(fmt, width, height)
-                        .build()
-                        .expect("Default video info for transform was invalid."),
-                ),
-                //filter: Mutex::new(Box::new(NoopFilter::default())),
-                filter: Mutex::new(Box::new(
-                    RVMFilter::new("/home/xeef/Projekte/fakecam2/models/rvm_mobilenetv3_fp32.onnx")
-                        .unwrap(),
-                )),
-                bg_frame: Mutex::new(
-                    Mat::new_rows_cols_with_default(height as i32, width as i32, CV_8UC3, *GREEN)
+                settings: Mutex::new(Settings::default()),
+                video_info: Mutex::new(info.clone()),
+                out_info: Mutex::new(info),
+                filter: Mutex::new(Box::new(NoopFilter::default())),
+                filter_dirty: AtomicBool::new(true),
+                post_processing_dirty: AtomicBool::new(false),
+                filter_reset: AtomicBool::new(false),
+                background: Mutex::new(
+                    Background::solid(*GREEN, Size::new(width as i32, height as i32))
                         .expect("Failed to create default background"),
                 ),
+                converter: Mutex::new(
+                    FormatConverter::new(fmt).expect("Default video format is not supported"),
+                ),
+                mask: Mutex::new(None),
+                background_pad: Mutex::new(None),
+                background_input: Mutex::new(BackgroundInput::default()),
+                background_queue: FrameQueue::default(),
+                preview: Mutex::new(Preview::default()),
+                quality: Mutex::new(QualityController::default()),
+                bgra: Mutex::new(Mat::default()),
+            }
+        }
+    }
+
+    impl FakecamTransform {
+        /// Build the filter described by `settings` at the current quality.
+        fn new_filter(&self, settings: &Settings) -> Result<Box<dyn Filter>, FilterError> {
+            let mut filter = build_filter(settings)?;
+            let quality = self.quality.lock().unwrap();
+            if quality.enabled() {
+                filter.set_quality(&quality.quality());
+            }
+            Ok(filter)
+        }
+
+        /// Replace `filter` with one built from the current settings. If building fails the
+        /// frames are passed through unmodified.
+        fn rebuild_filter(&self, filter: &mut Box<dyn Filter>) {
+            let settings = self.settings.lock().unwrap().clone();
+            *filter = self.new_filter(&settings).unwrap_or_else(|e| {
+                gstreamer::warning!(
+                    &*FILTER_ERROR_CAT,
+                    imp: self,
+                    "Failed to build {} filter, passing frames through: {}",
+                    settings.filter_name(),
+                    e
+                );
+                Box::new(NoopFilter::default())
+            });
+        }
+
+        /// Pass the time filtering a frame took to the quality controller, and apply the new
+        /// quality if it changed. With async inference the frame only waits for the newest
+        /// matte, so the controller is given the time the inference thread took instead.
+        fn update_quality(&self, filter: &mut dyn Filter, latency: Duration) {
+            let latency = if self.settings.lock().unwrap().async_inference {
+                // Frames for which the model did not run say nothing about its speed
+                match filter.timings().and_then(|timings| timings.duration("inference")) {
+                    Some(inference) => inference,
+                    None => return,
+                }
+            } else {
+                latency
+            };
+            let mut controller = self.quality.lock().unwrap();
+            let quality = match controller.record(latency) {
+                Some(quality) => quality,
+                None => return,
+            };
+            filter.set_quality(&quality);
+            gstreamer::info!(
+                &*FILTER_ERROR_CAT,
+                imp: self,
+                "Changed to quality level {}: {:?}",
+                controller.level(),
+                quality
+            );
+            let structure = gstreamer::Structure::builder("fakecam-quality")
+                .field("level", controller.level() as u32)
+                .field("target-fps", controller.target_fps())
+                .field("downsample-ratio", quality.downsample_ratio)
+                .field("inference-scale", quality.inference_scale)
+                .field("inference-interval", quality.inference_interval)
+                .build();
+            drop(controller);
+            let message = gstreamer::message::Element::builder(structure)
+                .src(&*self.obj())
+                .build();
+            let _ = self.obj().post_message(message);
+        }
+
+        /// Lock the filter, rebuilding or resetting it and applying changed post-processing
+        /// settings first if necessary.
+        fn lock_filter(&self) -> Result<MutexGuard<Box<dyn Filter>>, FlowError> {
+            let mut filter = self.filter.lock().or_else(|e| {
+                gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to obtain filter lock: {}", e);
+                Err(FlowError::Error)
+            })?;
+            let reset = self.filter_reset.swap(false, Ordering::SeqCst);
+            let post_processing = self.post_processing_dirty.swap(false, Ordering::SeqCst);
+            if self.filter_dirty.swap(false, Ordering::SeqCst) {
+                // The rebuilt filter already uses the current settings
+                self.rebuild_filter(&mut *filter);
+                return Ok(filter);
+            }
+            if reset {
+                filter.reset();
+            }
+            if post_processing {
+                let post_processing = self.settings.lock().unwrap().post_processing();
+                if let Err(e) = filter.set_post_processing(&post_processing) {
+                    gstreamer::error!(
+                        &*FILTER_ERROR_CAT,
+                        imp: self,
+                        "Failed to apply the post-processing settings: {}",
+                        e
+                    );
+                }
+            }
+            Ok(filter)
+        }
+
+        /// Set the property `name` if it only changes how the matte is post-processed, see
+        /// [`Settings::post_processing`]. Returns whether it was such a property.
+        fn set_post_processing_property(
+            settings: &mut Settings,
+            name: &str,
+            value: &glib::Value,
+        ) -> bool {
+            match name {
+                "key-color" => {
+                    settings.key_color = value.get().expect("type checked upstream");
+                }
+                "key-tolerance" => {
+                    settings.key_tolerance = value.get().expect("type checked upstream");
+                }
+                "key-softness" => {
+                    settings.key_softness = value.get().expect("type checked upstream");
+                }
+                "spill-suppression" => {
+                    settings.spill_suppression = value.get().expect("type checked upstream");
+                }
+                "guided-filter-radius" => {
+                    settings.refinement.guided_radius = value.get().expect("type checked upstream");
+                }
+                "guided-filter-eps" => {
+                    settings.refinement.guided_eps = value.get().expect("type checked upstream");
+                }
+                "matte-grow" => {
+                    settings.refinement.grow = value.get().expect("type checked upstream");
+                }
+                "matte-black-point" => {
+                    settings.refinement.black_point = value.get().expect("type checked upstream");
+                }
+                "matte-white-point" => {
+                    settings.refinement.white_point = value.get().expect("type checked upstream");
+                }
+                "matte-gamma" => {
+                    settings.refinement.gamma = value.get().expect("type checked upstream");
+                }
+                "matte-feather" => {
+                    settings.refinement.feather = value.get().expect("type checked upstream");
+                }
+                "light-wrap" => {
+                    settings.blending.light_wrap = value.get().expect("type checked upstream");
+                }
+                "light-wrap-radius" => {
+                    settings.blending.light_wrap_radius =
+                        value.get().expect("type checked upstream");
+                }
+                "harmonize" => {
+                    settings.blending.harmonize = value.get().expect("type checked upstream");
+                }
+                _ => return false,
+            }
+            true
+        }
+
+        /// Make the filter forget previous frames before the next one. This is done by the
+        /// streaming thread, so the thread requesting it does not wait for a frame to finish.
+        fn reset_filter(&self) {
+            self.filter_reset.store(true, Ordering::SeqCst);
+        }
+
+        /// Reload the background from the current settings for the currently negotiated frame
+        /// size. If loading fails a green background is used.
+        fn rebuild_background(&self) {
+            let settings = self.settings.lock().unwrap().clone();
+            let size = {
+                let info = self.video_info.lock().unwrap();
+                Size::new(info.width() as i32, info.height() as i32)
+            };
+            let background = build_background(&settings, size).or_else(|e| {
+                gstreamer::warning!(
+                    &*FILTER_ERROR_CAT,
+                    imp: self,
+                    "Failed to load background, using green: {}",
+                    e
+                );
+                Background::solid(*GREEN, size)
+            });
+            match background {
+                Ok(background) => *self.background.lock().unwrap() = background,
+                Err(e) => gstreamer::error!(
+                    &*FILTER_ERROR_CAT,
+                    imp: self,
+                    "Failed to create background: {}",
+                    e
+                ),
+            }
+        }
+
+        /// If a mask pad was requested, prepare it for mattes of frames described by `info` and
+        /// return it together with the format the mattes have to be pushed in.
+        fn prepare_mask(&self, info: &VideoInfo) -> Option<(gstreamer::Pad, MaskFormat)> {
+            let mut mask = self.mask.lock().unwrap();
+            let mask = mask.as_mut()?;
+            match mask.prepare(info) {
+                Some(format) => Some((mask.pad().clone(), format)),
+                None => {
+                    gstreamer::warning!(
+                        &*FILTER_ERROR_CAT,
+                        imp: self,
+                        "Failed to negotiate the mask format, dropping mattes"
+                    );
+                    None
+                }
+            }
+        }
+
+        /// Show the filtered frame in the debug preview if it is enabled, or close the preview
+        /// if it was disabled. This has to happen on the streaming thread, as the windows
+        /// belong to the thread which created them.
+        fn update_preview(&self, frame: &Mat, code: i32, matte: Option<&Mat>) {
+            let enabled = self.settings.lock().unwrap().debug_preview;
+            let mut preview = self.preview.lock().unwrap();
+            let result = if enabled {
+                preview.show(frame, code, matte)
+            } else {
+                preview.close()
+            };
+            if let Err(e) = result {
+                gstreamer::warning!(
+                    &*FILTER_ERROR_CAT,
+                    imp: self,
+                    "Debug preview failed, disabling it: {}",
+                    e
+                );
+                self.settings.lock().unwrap().debug_preview = false;
+            }
+        }
+
+        /// Add a requested pad to the element, activating it if the element is already running.
+        fn add_request_pad(&self, pad: &gstreamer::Pad) -> Option<()> {
+            self.obj().add_pad(pad).ok()?;
+            // Pads added before the element starts are activated along with the others. The
+            // pad functions expect the element as parent, so the pad is only activated once it
+            // has been added.
+            let running = self.obj().current_state() > gstreamer::State::Ready;
+            if running && pad.set_active(true).is_err() {
+                gstreamer::warning!(
+                    &*FILTER_ERROR_CAT,
+                    imp: self,
+                    "Failed to activate pad {}",
+                    pad.name()
+                );
+                let _ = self.obj().remove_pad(pad);
+                return None;
+            }
+            Some(())
+        }
+
+        fn request_mask_pad(&self, templ: &gstreamer::PadTemplate) -> Option<gstreamer::Pad> {
+            let mut mask = self.mask.lock().unwrap();
+            if mask.is_some() {
+                gstreamer::warning!(&*FILTER_ERROR_CAT, imp: self, "Only one mask pad is supported");
+                return None;
+            }
+            let pad = gstreamer::Pad::from_template(templ, Some("mask_src"));
+            self.add_request_pad(&pad)?;
+            *mask = Some(MaskPad::new(pad.clone()));
+            Some(pad)
+        }
+
+        fn request_background_pad(
+            &self,
+            templ: &gstreamer::PadTemplate,
+        ) -> Option<gstreamer::Pad> {
+            let mut background_pad = self.background_pad.lock().unwrap();
+            if background_pad.is_some() {
+                gstreamer::warning!(
+                    &*FILTER_ERROR_CAT,
+                    imp: self,
+                    "Only one background pad is supported"
+                );
+                return None;
+            }
+            let pad = gstreamer::Pad::builder_with_template(templ, Some("background"))
+                .chain_function(|_pad, parent, buffer| {
+                    FakecamTransform::catch_panic_pad_function(
+                        parent,
+                        || Err(FlowError::Error),
+                        |imp| imp.background_chain(buffer),
+                    )
+                })
+                .event_function(|pad, parent, event| {
+                    FakecamTransform::catch_panic_pad_function(
+                        parent,
+                        || false,
+                        |imp| imp.background_event(pad, event),
+                    )
+                })
+                .query_function(|pad, parent, query| {
+                    FakecamTransform::catch_panic_pad_function(
+                        parent,
+                        || false,
+                        |imp| imp.background_query(pad, query),
+                    )
+                })
+                .activatemode_function(|_pad, parent, _mode, active| {
+                    FakecamTransform::catch_panic_pad_function(
+                        parent,
+                        || {
+                            Err(gstreamer::loggable_error!(
+                                &*FILTER_ERROR_CAT,
+                                "Panic activating background pad"
+                            ))
+                        },
+                        |imp| {
+                            // Unblocks the chain function, which may wait for the camera
+                            imp.background_queue.set_flushing(!active);
+                            Ok(())
+                        },
+                    )
+                })
+                .build();
+            self.add_request_pad(&pad)?;
+            *background_pad = Some(pad.clone());
+            Some(pad)
+        }
+
+        /// Scale a frame received on the background pad to the camera frame size and queue it.
+        fn background_chain(
+            &self,
+            buffer: gstreamer::Buffer,
+        ) -> Result<gstreamer::FlowSuccess, FlowError> {
+            let size = self.background_queue.size();
+            if size.width == 0 || size.height == 0 {
+                // The camera caps are not known yet, so there is nothing to scale to
+                return Ok(gstreamer::FlowSuccess::Ok);
+            }
+            let fit = self.settings.lock().unwrap().background_fit;
+            let mut input = self.background_input.lock().unwrap();
+            let info = input.info().cloned().ok_or(FlowError::NotNegotiated)?;
+            let running_time = input.running_time(buffer.pts());
+            let frame = VideoFrameRef::from_buffer_ref_readable(buffer.as_ref(), &info)
+                .or_else(|e| {
+                    gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to extract video frame: {}", e);
+                    Err(FlowError::Error)
+                })?;
+            // The Mat is dropped before the frame
+            let mat = unsafe { FrameMat::readable(&frame) }.or_else(map_error)?;
+            let background = input.prepare(mat.mat(), size, fit).or_else(|e| {
+                gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to scale background: {}", e);
+                Err(FlowError::Error)
+            })?;
+            drop(input);
+            self.background_queue.push(running_time, background)
+        }
+
+        fn background_event(&self, pad: &gstreamer::Pad, event: gstreamer::Event) -> bool {
+            use gstreamer::EventView;
+
+            match event.view() {
+                EventView::Caps(e) => {
+                    match self.background_input.lock().unwrap().set_caps(e.caps()) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            gstreamer::warning!(&*FILTER_ERROR_CAT, obj: pad, "{}", e);
+                            false
+                        }
+                    }
+                }
+                EventView::Segment(e) => {
+                    self.background_input.lock().unwrap().set_segment(e.segment());
+                    true
+                }
+                EventView::FlushStart(_) => {
+                    self.background_queue.set_flushing(true);
+                    true
+                }
+                EventView::FlushStop(_) => {
+                    self.background_queue.set_flushing(false);
+                    true
+                }
+                // The background stream ending does not end the output, the last frame is
+                // kept. Everything else only concerns the background stream, forwarding it
+                // would mix it up with the camera stream.
+                _ => true,
+            }
+        }
+
+        fn background_query(&self, pad: &gstreamer::Pad, query: &mut gstreamer::QueryRef) -> bool {
+            use gstreamer::QueryView;
+
+            // The default handler answers these from the pad template, everything else would be
+            // forwarded to the source pads which have nothing to do with the background stream
+            let handled = matches!(query.view(), QueryView::Caps(_) | QueryView::AcceptCaps(_));
+            handled && gstreamer::Pad::query_default(pad, Some(&*self.obj()), query)
+        }
+
+        /// Push the matte of the last frame with the pts, dts and duration of its frame.
+        fn push_mask(
+            &self,
+            mask: Option<(gstreamer::Pad, Vec<u8>)>,
+            timestamps: Timestamps,
+        ) -> Result<gstreamer::FlowSuccess, FlowError> {
+            match mask {
+                Some((pad, bytes)) => {
+                    let (pts, dts, duration) = timestamps;
+                    maskpad::push_matte(&pad, bytes, pts, dts, duration)
+                }
+                None => Ok(gstreamer::FlowSuccess::Ok),
             }
         }
     }
@@ -74,10 +724,453 @@ mod imp {
         type ParentType = gstreamer_base::BaseTransform;
     }
 
-    impl ObjectImpl for FakecamTransform {}
+    impl ObjectImpl for FakecamTransform {
+        fn constructed(&self) {
+            self.parent_constructed();
+            // Frames which would arrive late at the sink are dropped before filtering them, so
+            // a slow model lowers the framerate instead of adding latency
+            self.obj().set_qos_enabled(true);
+        }
+
+        fn properties() -> &'static [glib::ParamSpec] {
+            static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+                vec![
+                    glib::ParamSpecString::builder("filter")
+                        .nick("Filter")
+                        .blurb("Name of the filter to use, e.g. rvm or blur. Leave unset to choose it from mode and model-config")
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecString::builder("model-location")
+                        .nick("Model location")
+                        .blurb("Path to the ONNX matting model")
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecString::builder("model-config")
+                        .nick("Model config")
+                        .blurb("Path to the config of a segmentation model, leave unset for RVM")
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecEnum::builder_with_default("mode", DEFAULT_MODE)
+                        .nick("Mode")
+                        .blurb("What to do with the background")
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecString::builder("background-location")
+                        .nick("Background location")
+                        .blurb("Path to the image or video used as background in replace mode")
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecEnum::builder_with_default("background-fit", DEFAULT_BACKGROUND_FIT)
+                        .nick("Background fit")
+                        .blurb("How the background is scaled to the frame")
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecDouble::builder("downsample-ratio")
+                        .nick("Downsample ratio")
+                        .blurb("Resolution the model works at relative to the frame size")
+                        .minimum(0.01)
+                        .maximum(1.0)
+                        .default_value(DEFAULT_DOWNSAMPLE_RATIO)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt::builder("num-threads")
+                        .nick("Number of threads")
+                        .blurb("Number of threads used for inference, 0 uses one per physical core")
+                        .minimum(0)
+                        .maximum(i16::MAX as u32)
+                        .default_value(RuntimeOptions::default().num_threads)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecEnum::builder_with_default("optimization-level", RuntimeOptions::default().optimization)
+                        .nick("Optimization level")
+                        .blurb("How much ONNX Runtime optimises the model")
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecEnum::builder_with_default("ort-log-level", RuntimeOptions::default().log_level)
+                        .nick("ONNX Runtime log level")
+                        .blurb("Least severe messages logged by ONNX Runtime. Only takes effect for the first model loaded by the process")
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecString::builder("execution-providers")
+                        .nick("Execution providers")
+                        .blurb("Comma separated execution providers to run the model on in order of preference, e.g. cuda,cpu. Only cpu is available in this build, so the others have no effect")
+                        .default_value(Some(
+                            ExecutionProvider::format_list(&RuntimeOptions::default().execution_providers)
+                                .as_str(),
+                        ))
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecString::builder("model-cache")
+                        .nick("Model cache")
+                        .blurb("Directory to save optimised models to and load them from, so each model is only optimised once. Leave unset to optimise models on every start")
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecEnum::builder_with_default("blur-kind", DEFAULT_BLUR_KIND)
+                        .nick("Blur kind")
+                        .blurb("How the background is blurred in blur mode")
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt::builder("blur-radius")
+                        .nick("Blur radius")
+                        .blurb("Radius of the background blur in pixels")
+                        .minimum(1)
+                        .maximum(255)
+                        .default_value(DEFAULT_BLUR_RADIUS)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecBoolean::builder("async-inference")
+                        .nick("Asynchronous inference")
+                        .blurb("Run the model on a worker thread and apply the newest matte to each frame")
+                        .default_value(DEFAULT_ASYNC_INFERENCE)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt::builder("max-inference-interval")
+                        .nick("Maximum inference interval")
+                        .blurb("Run the model at least every this many frames and warp the matte along the optical flow in between, 1 runs it on every frame")
+                        .minimum(1)
+                        .maximum(30)
+                        .default_value(DEFAULT_MAX_INFERENCE_INTERVAL)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecDouble::builder("target-fps")
+                        .nick("Target framerate")
+                        .blurb("Lower the quality of the matte while filtering is too slow for this framerate, overriding downsample-ratio and max-inference-interval. 0 disables this")
+                        .minimum(0.0)
+                        .maximum(240.0)
+                        .default_value(DEFAULT_TARGET_FPS)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt::builder("key-color")
+                        .nick("Key colour")
+                        .blurb("Colour of the green screen keyed out by the chroma filter, as 0xRRGGBB")
+                        .maximum(0xffffff)
+                        .default_value(DEFAULT_KEY_COLOR)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecDouble::builder("key-tolerance")
+                        .nick("Key tolerance")
+                        .blurb("Hue difference in degrees up to which the chroma filter keys out pixels completely")
+                        .minimum(0.0)
+                        .maximum(180.0)
+                        .default_value(DEFAULT_KEY_TOLERANCE)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecDouble::builder("key-softness")
+                        .nick("Key softness")
+                        .blurb("Hue difference in degrees beyond key-tolerance over which the chroma key fades out")
+                        .minimum(1.0)
+                        .maximum(180.0)
+                        .default_value(DEFAULT_KEY_SOFTNESS)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecDouble::builder("spill-suppression")
+                        .nick("Spill suppression")
+                        .blurb("How much of the key colour reflected onto the person the chroma filter removes, 0 disables this")
+                        .minimum(0.0)
+                        .maximum(1.0)
+                        .default_value(DEFAULT_SPILL_SUPPRESSION)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt::builder("guided-filter-radius")
+                        .nick("Guided filter radius")
+                        .blurb("Radius in pixels of the guided filter aligning the edges of the matte with the frame, 0 disables it")
+                        .maximum(64)
+                        .default_value(Refinement::default().guided_radius)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecDouble::builder("guided-filter-eps")
+                        .nick("Guided filter regularisation")
+                        .blurb("How much the guided filter smooths the matte instead of following the edges of the frame")
+                        .minimum(1e-6)
+                        .maximum(1.0)
+                        .default_value(Refinement::default().guided_eps)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecInt::builder("matte-grow")
+                        .nick("Matte grow")
+                        .blurb("Pixels by which the matte is grown, or shrunk if this is negative")
+                        .minimum(-32)
+                        .maximum(32)
+                        .default_value(Refinement::default().grow)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecDouble::builder("matte-black-point")
+                        .nick("Matte black point")
+                        .blurb("Values of the matte at and below which a pixel is background")
+                        .minimum(0.0)
+                        .maximum(1.0)
+                        .default_value(Refinement::default().black_point)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecDouble::builder("matte-white-point")
+                        .nick("Matte white point")
+                        .blurb("Values of the matte at and above which a pixel is foreground")
+                        .minimum(0.0)
+                        .maximum(1.0)
+                        .default_value(Refinement::default().white_point)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecDouble::builder("matte-gamma")
+                        .nick("Matte gamma")
+                        .blurb("Exponent applied to the matte between the black and white point, above 1 shrinks soft edges")
+                        .minimum(0.1)
+                        .maximum(10.0)
+                        .default_value(Refinement::default().gamma)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt::builder("matte-feather")
+                        .nick("Matte feather")
+                        .blurb("Radius in pixels of the blur softening the edge of the matte, 0 disables it")
+                        .maximum(64)
+                        .default_value(Refinement::default().feather)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecDouble::builder("light-wrap")
+                        .nick("Light wrap")
+                        .blurb("How strongly the background bleeds onto the edges of the person, 0 disables this")
+                        .minimum(0.0)
+                        .maximum(1.0)
+                        .default_value(Blending::default().light_wrap)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecUInt::builder("light-wrap-radius")
+                        .nick("Light wrap radius")
+                        .blurb("How far in pixels the background bleeds onto the person")
+                        .minimum(1)
+                        .maximum(64)
+                        .default_value(Blending::default().light_wrap_radius)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecDouble::builder("harmonize")
+                        .nick("Harmonize")
+                        .blurb("How strongly the brightness and colours of the person are matched to the background, 0 disables this")
+                        .minimum(0.0)
+                        .maximum(1.0)
+                        .default_value(Blending::default().harmonize)
+                        .mutable_playing()
+                        .build(),
+                    glib::ParamSpecBoolean::builder("debug-preview")
+                        .nick("Debug preview")
+                        .blurb("Show the output and the matte in windows, needs a display")
+                        .default_value(false)
+                        .mutable_playing()
+                        .build(),
+                ]
+            });
+
+            PROPERTIES.as_ref()
+        }
+
+        fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+            let mut settings = self.settings.lock().unwrap();
+            if Self::set_post_processing_property(&mut settings, pspec.name(), value) {
+                // Applied to the running filter by the streaming thread before the next frame,
+                // without rebuilding it
+                self.post_processing_dirty.store(true, Ordering::SeqCst);
+                return;
+            }
+            match pspec.name() {
+                "filter" => {
+                    let filter: Option<String> = value.get().expect("type checked upstream");
+                    match filter {
+                        Some(name) if registry::find(&name).is_none() => {
+                            gstreamer::error!(
+                                &*FILTER_ERROR_CAT,
+                                imp: self,
+                                "Unknown filter '{}', the filters available are {}",
+                                name,
+                                registry::filter_names()
+                            );
+                            return;
+                        }
+                        filter => settings.filter = filter,
+                    }
+                }
+                "model-location" => {
+                    settings.model_location = value.get().expect("type checked upstream");
+                }
+                "model-config" => {
+                    settings.model_config = value.get().expect("type checked upstream");
+                }
+                "mode" => {
+                    let mode: Mode = value.get().expect("type checked upstream");
+                    // Switching to or from alpha mode changes the output format
+                    if (mode == Mode::Alpha) != (settings.mode == Mode::Alpha) {
+                        self.obj().reconfigure_src();
+                    }
+                    settings.mode = mode;
+                }
+                "background-location" => {
+                    settings.background_location = value.get().expect("type checked upstream");
+                    drop(settings);
+                    self.rebuild_background();
+                    return;
+                }
+                "background-fit" => {
+                    let fit = value.get().expect("type checked upstream");
+                    settings.background_fit = fit;
+                    drop(settings);
+                    if let Err(e) = self.background.lock().unwrap().set_fit(fit) {
+                        gstreamer::error!(&*FILTER_ERROR_CAT, imp: self, "{}", e);
+                    }
+                    return;
+                }
+                "downsample-ratio" => {
+                    settings.downsample_ratio = value.get().expect("type checked upstream");
+                }
+                "num-threads" => {
+                    settings.runtime.num_threads = value.get().expect("type checked upstream");
+                }
+                "optimization-level" => {
+                    settings.runtime.optimization = value.get().expect("type checked upstream");
+                }
+                "ort-log-level" => {
+                    settings.runtime.log_level = value.get().expect("type checked upstream");
+                    #[cfg(feature = "rvm")]
+                    match crate::runtime::environment_log_level() {
+                        Some(level) if level != settings.runtime.log_level => {
+                            gstreamer::warning!(
+                                &*FILTER_ERROR_CAT,
+                                imp: self,
+                                "ONNX Runtime already logs at level {}, the log level can only \
+                                 be set before the first model is loaded",
+                                level
+                            );
+                        }
+                        _ => {}
+                    }
+                }
+                "execution-providers" => {
+                    let list: Option<String> = value.get().expect("type checked upstream");
+                    let providers = match list {
+                        Some(list) => ExecutionProvider::parse_list(&list),
+                        None => Ok(RuntimeOptions::default().execution_providers),
+                    };
+                    match providers {
+                        Ok(providers) => settings.runtime.execution_providers = providers,
+                        Err(e) => {
+                            gstreamer::error!(&*FILTER_ERROR_CAT, imp: self, "{}", e);
+                            return;
+                        }
+                    }
+                    for provider in &settings.runtime.execution_providers {
+                        if !provider.is_available() {
+                            gstreamer::warning!(
+                                &*FILTER_ERROR_CAT,
+                                imp: self,
+                                "Execution provider {} is not available in this build, running \
+                                 on {}",
+                                provider,
+                                settings.runtime.execution_provider()
+                            );
+                        }
+                    }
+                }
+                "model-cache" => {
+                    settings.runtime.model_cache = value.get().expect("type checked upstream");
+                }
+                "blur-kind" => {
+                    settings.blur_kind = value.get().expect("type checked upstream");
+                }
+                "blur-radius" => {
+                    settings.blur_radius = value.get().expect("type checked upstream");
+                }
+                "async-inference" => {
+                    settings.async_inference = value.get().expect("type checked upstream");
+                }
+                "max-inference-interval" => {
+                    settings.max_inference_interval = value.get().expect("type checked upstream");
+                }
+                "target-fps" => {
+                    settings.target_fps = value.get().expect("type checked upstream");
+                    let mut controller = self.quality.lock().unwrap();
+                    let was_enabled = controller.enabled();
+                    controller.set_target_fps(settings.target_fps);
+                    // The controller adjusts the running filter, so it is only rebuilt when the
+                    // controller is turned on or off. Only filters built while a target is set
+                    // can skip inference on some frames, and once the controller is off the
+                    // filter goes back to the quality of the settings.
+                    if was_enabled == controller.enabled() {
+                        return;
+                    }
+                }
+                "debug-preview" => {
+                    // Picked up by the streaming thread with the next frame
+                    settings.debug_preview = value.get().expect("type checked upstream");
+                    return;
+                }
+                _ => unimplemented!(),
+            }
+            // The filter is rebuilt by the streaming thread before the next frame, so that
+            // setting several properties in a row only loads the model once.
+            self.filter_dirty.store(true, Ordering::SeqCst);
+        }
+
+        fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+            let settings = self.settings.lock().unwrap();
+            match pspec.name() {
+                "filter" => settings.filter.to_value(),
+                "model-location" => settings.model_location.to_value(),
+                "model-config" => settings.model_config.to_value(),
+                "mode" => settings.mode.to_value(),
+                "background-location" => settings.background_location.to_value(),
+                "background-fit" => settings.background_fit.to_value(),
+                "downsample-ratio" => settings.downsample_ratio.to_value(),
+                "num-threads" => settings.runtime.num_threads.to_value(),
+                "optimization-level" => settings.runtime.optimization.to_value(),
+                "ort-log-level" => settings.runtime.log_level.to_value(),
+                "execution-providers" => {
+                    ExecutionProvider::format_list(&settings.runtime.execution_providers)
+                        .to_value()
+                }
+                "model-cache" => settings.runtime.model_cache.to_value(),
+                "blur-kind" => settings.blur_kind.to_value(),
+                "blur-radius" => settings.blur_radius.to_value(),
+                "async-inference" => settings.async_inference.to_value(),
+                "max-inference-interval" => settings.max_inference_interval.to_value(),
+                "target-fps" => settings.target_fps.to_value(),
+                "key-color" => settings.key_color.to_value(),
+                "key-tolerance" => settings.key_tolerance.to_value(),
+                "key-softness" => settings.key_softness.to_value(),
+                "spill-suppression" => settings.spill_suppression.to_value(),
+                "guided-filter-radius" => settings.refinement.guided_radius.to_value(),
+                "guided-filter-eps" => settings.refinement.guided_eps.to_value(),
+                "matte-grow" => settings.refinement.grow.to_value(),
+                "matte-black-point" => settings.refinement.black_point.to_value(),
+                "matte-white-point" => settings.refinement.white_point.to_value(),
+                "matte-gamma" => settings.refinement.gamma.to_value(),
+                "matte-feather" => settings.refinement.feather.to_value(),
+                "light-wrap" => settings.blending.light_wrap.to_value(),
+                "light-wrap-radius" => settings.blending.light_wrap_radius.to_value(),
+                "harmonize" => settings.blending.harmonize.to_value(),
+                "debug-preview" => settings.debug_preview.to_value(),
+                _ => unimplemented!(),
+            }
+        }
+    }
 
     impl GstObjectImpl for FakecamTransform {}
 
+    /// Convert the matte of the last frame for the mask pad, while the filter is still locked.
+    fn mask_bytes(
+        filter: &dyn Filter,
+        mask: &Option<(gstreamer::Pad, MaskFormat)>,
+        info: &VideoInfo,
+    ) -> Result<Option<(gstreamer::Pad, Vec<u8>)>, FilterError> {
+        match mask {
+            Some((pad, format)) => {
+                let bytes = maskpad::matte_to_bytes(
+                    filter.matte(),
+                    *format,
+                    info.width() as i32,
+                    info.height() as i32,
+                )?;
+                Ok(Some((pad.clone(), bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
     impl ElementImpl for FakecamTransform {
         fn metadata() -> Option<&'static ElementMetadata> {
             static METADATA: Lazy<ElementMetadata> = Lazy::new(|| {
@@ -99,7 +1192,9 @@ mod imp {
                     &[
                         (
                             "format",
-                            &gstreamer::List::new(&[&gstreamer_video::VideoFormat::Rgb.to_str()]),
+                            &gstreamer::List::new(
+                                SUPPORTED_FORMATS.iter().map(|format| format.to_str().to_string()),
+                            ),
                         ),
                         ("interlace-mode", &"progressive"),
                     ],
@@ -121,18 +1216,133 @@ mod imp {
                 )
                 .unwrap();
 
-                vec![src_template, sink_template]
+                let mask_template = gstreamer::PadTemplate::new(
+                    "mask_src",
+                    gstreamer::PadDirection::Src,
+                    gstreamer::PadPresence::Request,
+                    &maskpad::template_caps(),
+                )
+                .unwrap();
+
+                let background_template = gstreamer::PadTemplate::new(
+                    "background",
+                    gstreamer::PadDirection::Sink,
+                    gstreamer::PadPresence::Request,
+                    &caps,
+                )
+                .unwrap();
+
+                vec![src_template, sink_template, mask_template, background_template]
             });
 
             TEMPLATES.as_ref()
         }
+
+        fn request_new_pad(
+            &self,
+            templ: &gstreamer::PadTemplate,
+            _name: Option<&str>,
+            _caps: Option<&Caps>,
+        ) -> Option<gstreamer::Pad> {
+            match templ.name_template() {
+                "mask_src" => self.request_mask_pad(templ),
+                "background" => self.request_background_pad(templ),
+                _ => None,
+            }
+        }
+
+        fn release_pad(&self, pad: &gstreamer::Pad) {
+            let mut mask = self.mask.lock().unwrap();
+            if mask.as_ref().map_or(false, |mask| mask.pad() == pad) {
+                *mask = None;
+            }
+            drop(mask);
+            let mut background_pad = self.background_pad.lock().unwrap();
+            let is_background = background_pad.as_ref() == Some(pad);
+            if is_background {
+                *background_pad = None;
+            }
+            drop(background_pad);
+            let _ = pad.set_active(false);
+            let _ = self.obj().remove_pad(pad);
+            if is_background {
+                // Fall back to the configured background
+                self.background_queue.clear();
+                *self.background_input.lock().unwrap() = BackgroundInput::default();
+            }
+        }
     }
 
     impl BaseTransformImpl for FakecamTransform {
-        const MODE: BaseTransformMode = BaseTransformMode::AlwaysInPlace;
+        // Frames are filtered in place unless the alpha mode changes the format
+        const MODE: BaseTransformMode = BaseTransformMode::Both;
         const PASSTHROUGH_ON_SAME_CAPS: bool = false;
         const TRANSFORM_IP_ON_PASSTHROUGH: bool = false;
 
+        /// Build the filter before the first frame, so a filter which cannot be built, e.g.
+        /// because replace mode has no model-location, is an error instead of silently passing
+        /// the frames through. Property changes while running only fall back to passing the
+        /// frames through.
+        fn start(&self) -> Result<(), gstreamer::ErrorMessage> {
+            self.filter_dirty.store(false, Ordering::SeqCst);
+            self.post_processing_dirty.store(false, Ordering::SeqCst);
+            // Like a later rebuild, a filter which cannot be built passes the frames through
+            self.rebuild_filter(&mut self.filter.lock().unwrap());
+            Ok(())
+        }
+
+        fn transform_caps(
+            &self,
+            direction: gstreamer::PadDirection,
+            caps: &Caps,
+            filter: Option<&Caps>,
+        ) -> Option<Caps> {
+            let alpha = self.settings.lock().unwrap().mode == Mode::Alpha;
+            let mut other_caps = caps.clone();
+            if alpha {
+                // In alpha mode any supported input format is turned into RGBA or BGRA
+                let formats: &[VideoFormat] = if direction == gstreamer::PadDirection::Sink {
+                    ALPHA_FORMATS
+                } else {
+                    SUPPORTED_FORMATS
+                };
+                let formats = gstreamer::List::new(
+                    formats.iter().map(|format| format.to_str().to_string()),
+                );
+                for structure in other_caps.make_mut().iter_mut() {
+                    structure.set("format", formats.clone());
+                }
+            }
+            match filter {
+                Some(filter) => Some(
+                    filter.intersect_with_mode(&other_caps, gstreamer::CapsIntersectMode::First),
+                ),
+                None => Some(other_caps),
+            }
+        }
+
+        fn sink_event(&self, event: gstreamer::Event) -> bool {
+            if let gstreamer::EventView::FlushStop(_) = event.view() {
+                self.reset_filter();
+            }
+            if let Some(mask) = self.mask.lock().unwrap().as_mut() {
+                mask.sink_event(&event);
+            }
+            self.parent_sink_event(event)
+        }
+
+        fn src_event(&self, event: gstreamer::Event) -> bool {
+            // Frames after a seek do not continue the frames before it
+            if let gstreamer::EventView::Seek(_) = event.view() {
+                self.reset_filter();
+            }
+            self.parent_src_event(event)
+        }
+
+        fn unit_size(&self, caps: &Caps) -> Option<usize> {
+            VideoInfo::from_caps(caps).ok().map(|info| info.size())
+        }
+
         fn set_caps(
             &self,
             incaps: &Caps,
@@ -141,7 +1351,16 @@ mod imp {
             let mut info = self.video_info.lock().unwrap();
             let info_in = VideoInfo::from_caps(incaps)?;
             let info_out = VideoInfo::from_caps(outcaps)?;
-            if info_in != info_out {
+            let alpha = self.settings.lock().unwrap().mode == Mode::Alpha;
+            if info_in.width() != info_out.width() || info_in.height() != info_out.height() {
+                return Err(gstreamer::loggable_error!(
+                    &*FILTER_ERROR_CAT,
+                    format!(
+                        "Input and output size different. Input {:?}, Output {:?}",
+                        info_in, info_out
+                    )
+                ));
+            } else if info_in != info_out && !alpha {
                 return Err(gstreamer::loggable_error!(
                     &*FILTER_ERROR_CAT,
                     format!(
@@ -151,20 +1370,105 @@ mod imp {
                 ));
             } else {
                 *info = info_in.clone();
+                *self.out_info.lock().unwrap() = info_out.clone();
             }
-            // Resize green frame to new size
-            let mut bg_frame = self.bg_frame.lock().unwrap();
-            bg_frame
-                .resize_with_default(info_in.height() as usize, *GREEN)
-                .unwrap();
-            bg_frame.set_cols(info_in.width() as i32);
+            drop(info);
+            self.obj().set_in_place(!alpha);
+            let converter = FormatConverter::new(info_in.format())
+                .map_err(|e| -> gstreamer::LoggableError { e.into() })?;
+            *self.converter.lock().unwrap() = converter;
+            // Scale background to new size
+            let size = Size::new(info_in.width() as i32, info_in.height() as i32);
+            self.background
+                .lock()
+                .unwrap()
+                .set_size(size)
+                .map_err(|e| -> gstreamer::LoggableError { e.into() })?;
+            self.background_queue.set_size(size);
+            // The state of the filter was computed from frames of the old caps
+            self.reset_filter();
 
             Ok(())
         }
+
+        fn transform(
+            &self,
+            inbuf: &gstreamer::Buffer,
+            outbuf: &mut gstreamer::BufferRef,
+        ) -> Result<gstreamer::FlowSuccess, FlowError> {
+            let info_in = self.video_info.lock().unwrap().clone();
+            let info_out = self.out_info.lock().unwrap().clone();
+            let in_frame = VideoFrameRef::from_buffer_ref_readable(inbuf.as_ref(), &info_in)
+                .or_else(|e| {
+                    gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to extract video frame: {}", e);
+                    Err(FlowError::Error)
+                })?;
+            let mut out_frame = VideoFrameRef::from_buffer_ref_writable(outbuf, &info_out)
+                .or_else(|e| {
+                    gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to extract video frame: {}", e);
+                    Err(FlowError::Error)
+                })?;
+            // The Mats are dropped before the frames
+            let in_frame_mat = unsafe { FrameMat::readable(&in_frame) }.or_else(map_error)?;
+            let in_mat = in_frame_mat.mat();
+            let mut out_frame_mat =
+                unsafe { FrameMat::writable(&mut out_frame) }.or_else(map_error)?;
+
+            let mask = self.prepare_mask(&info_in);
+            let mut filter = self.lock_filter()?;
+            filter.set_frame_meta(&frame_meta(inbuf));
+            let mut converter = self.converter.lock().or_else(|e| {
+                gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to obtain converter lock: {}", e);
+                Err(FlowError::Error)
+            })?;
+            let start = Instant::now();
+            let result = if converter.is_native() {
+                filter.filter_alpha(in_mat, out_frame_mat.mat_mut())
+            } else {
+                converter.to_rgb(in_mat).and_then(|_| {
+                    filter.filter_alpha(converter.rgb_mut(), out_frame_mat.mat_mut())
+                })
+            };
+            self.update_quality(&mut **filter, start.elapsed());
+            let result = result.and_then(|_| {
+                // Filters always produce RGBA
+                if out_frame.format() == VideoFormat::Bgra {
+                    let mut bgra = self.bgra.lock().unwrap();
+                    cvt_color(out_frame_mat.mat(), &mut *bgra, COLOR_RGBA2BGRA, 0)?;
+                    bgra.copy_to(out_frame_mat.mat_mut())?;
+                }
+                out_frame_mat.write_back(&mut out_frame)?;
+                mask_bytes(&**filter, &mask, &info_in)
+            });
+            let mask = result.or_else(|e| {
+                gstreamer::error!(&*FILTER_ERROR_CAT, imp: self, "Filtering failed: {}", e);
+                Err(FlowError::Error)
+            })?;
+            let preview_code = if out_frame.format() == VideoFormat::Bgra {
+                COLOR_BGRA2BGR
+            } else {
+                COLOR_RGBA2BGR
+            };
+            self.update_preview(out_frame_mat.mat(), preview_code, filter.matte());
+            drop(converter);
+            drop(filter);
+
+            self.push_mask(mask, (inbuf.pts(), inbuf.dts(), inbuf.duration()))
+        }
+
         fn transform_ip(
             &self,
             buf: &mut gstreamer::BufferRef,
         ) -> Result<gstreamer::FlowSuccess, FlowError> {
+            // The frame borrows the buffer, so keep the timestamps for the matte
+            let timestamps = (buf.pts(), buf.dts(), buf.duration());
+            let meta = frame_meta(buf);
+            let running_time = buf.pts().and_then(|pts| {
+                self.obj()
+                    .segment()
+                    .downcast_ref::<gstreamer::ClockTime>()
+                    .and_then(|segment| segment.to_running_time(pts))
+            });
             // Obtain lock on video info
             let info = self.video_info.lock().or_else(|e| {
                 gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to obtain mutex lock");
@@ -175,72 +1479,83 @@ mod imp {
                 gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to extract video frame: {}", e);
                 Err(FlowError::Error)
             })?;
-            if frame.n_planes() != 1 {
-                gstreamer::error!(
-                    &*FILTER_ERROR_CAT,
-                    "Extracted frame has {} planes, should have 1",
-                    frame.n_planes()
-                );
-                return Err(FlowError::Error);
-            }
-            // Obtain mutable pointer to the single plane containing the image data
-            let frame_data_ptr: *mut u8 = frame
-                .plane_data_mut(0)
-                .map(|data| data.as_mut_ptr())
-                .or_else(|e| {
+            // The Mat is dropped before the frame
+            let mut frame_mat = unsafe { FrameMat::writable(&mut frame) }.or_else(map_error)?;
+
+            let mask = self.prepare_mask(&info);
+            let mask = {
+                let mut filter = self.lock_filter()?;
+                filter.set_frame_meta(&meta);
+                let mut background = self.background.lock().or_else(|e| {
                     gstreamer::error!(
                         &*FILTER_ERROR_CAT,
-                        "Failed to read out frame plane: {}",
+                        "Failed to obtain background lock: {}",
                         e
                     );
                     Err(FlowError::Error)
                 })?;
-            let mut frame_mat = unsafe {
-                opencv::core::Mat::new_rows_cols_with_data(
-                    frame.height() as i32,
-                    frame.width() as i32,
-                    opencv::core::CV_8UC3,
-                    frame_data_ptr as *mut c_void,
-                    opencv::core::Mat_AUTO_STEP,
-                )
-            }
-            .or_else(|e| {
-                gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to decode as CV Mat: {}", e);
-                Err(FlowError::Error)
-            })?;
-
-            {
-                let mut filter = self.filter.lock().or_else(|e| {
-                    gstreamer::error!(
-                        &*FILTER_ERROR_CAT,
-                        "Failed to obtain filter lock: {}",
-                        e
-                    );
+                let live_bg = self.background_queue.select(running_time).or_else(|e| {
+                    gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to get background: {}", e);
                     Err(FlowError::Error)
                 })?;
-                let bg = self.bg_frame.lock().or_else(|e| {
+                let bg = match &live_bg {
+                    Some(bg) => bg,
+                    None => background.next_frame().or_else(|e| {
+                        gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to get background: {}", e);
+                        Err(FlowError::Error)
+                    })?,
+                };
+                let mut converter = self.converter.lock().or_else(|e| {
                     gstreamer::error!(
                         &*FILTER_ERROR_CAT,
-                        "Failed to obtain BG frame lock: {}",
+                        "Failed to obtain converter lock: {}",
                         e
                     );
                     Err(FlowError::Error)
                 })?;
 
-                (*filter)
-                    .filter_inplace(&mut frame_mat, &*bg)
+                let start = Instant::now();
+                let result = if converter.is_native() {
+                    (*filter).filter_inplace(frame_mat.mat_mut(), bg)
+                } else {
+                    converter
+                        .to_rgb(frame_mat.mat())
+                        .and_then(|_| (*filter).filter_inplace(converter.rgb_mut(), bg))
+                        .and_then(|_| converter.from_rgb(frame_mat.mat_mut()))
+                };
+                let result = result.and_then(|_| frame_mat.write_back(&mut frame));
+                self.update_quality(&mut **filter, start.elapsed());
+                let mask = result
+                    .and_then(|_| mask_bytes(&**filter, &mask, &info))
                     .or_else(|e| {
-                        println!("Filter failed: {}", e);
-                        gstreamer::error!(&*FILTER_ERROR_CAT, "Filtering failed: {}", e);
+                        gstreamer::error!(
+                            &*FILTER_ERROR_CAT,
+                            imp: self,
+                            "Filtering failed: {}",
+                            e
+                        );
                         Err(FlowError::Error)
                     })?;
-            }
+                let rgb = if converter.is_native() {
+                    frame_mat.mat()
+                } else {
+                    &*converter.rgb_mut()
+                };
+                self.update_preview(rgb, COLOR_RGB2BGR, filter.matte());
+                mask
+            };
 
-            Ok(gstreamer::FlowSuccess::Ok)
+            self.push_mask(mask, timestamps)
         }
     }
 }
 
+/// Log a failure to map a frame as a Mat.
+fn map_error<T>(e: FilterError) -> Result<T, FlowError> {
+    gstreamer::error!(&*FILTER_ERROR_CAT, "Failed to map frame: {}", e);
+    Err(FlowError::Error)
+}
+
 glib::wrapper! {
     pub struct FakecamTransform(ObjectSubclass<imp::FakecamTransform>) @extends gstreamer_base::BaseTransform, gstreamer::Element, gstreamer::Object;
 }
@@ -0,0 +1,45 @@
+//! A debug preview showing the filtered frames and the estimated matte in OpenCV windows. It is
+//! only used when enabled through the `debug-preview` property, as it needs a display and slows
+//! down processing.
+
+use crate::filter::FilterError;
+use opencv::highgui::{destroy_all_windows, imshow, wait_key};
+use opencv::imgproc::cvt_color;
+use opencv::prelude::*;
+
+const FRAME_WINDOW: &str = "fakecam output";
+const MATTE_WINDOW: &str = "fakecam matte";
+
+#[derive(Debug, Default)]
+pub struct Preview {
+    /// Whether the windows are currently open
+    open: bool,
+    /// The frame converted to BGR for display
+    bgr: Mat,
+}
+
+impl Preview {
+    /// Show `frame`, converted to BGR with the colour conversion `code`, and the `matte` if the
+    /// filter estimated one.
+    pub fn show(&mut self, frame: &Mat, code: i32, matte: Option<&Mat>) -> Result<(), FilterError> {
+        cvt_color(frame, &mut self.bgr, code, 0)?;
+        self.open = true;
+        imshow(FRAME_WINDOW, &self.bgr)?;
+        if let Some(matte) = matte {
+            // Float images are displayed with 0..1 mapped to black..white
+            imshow(MATTE_WINDOW, matte)?;
+        }
+        // The windows are only drawn while processing events
+        wait_key(1)?;
+        Ok(())
+    }
+
+    /// Close the windows if they are open.
+    pub fn close(&mut self) -> Result<(), FilterError> {
+        if self.open {
+            self.open = false;
+            destroy_all_windows()?;
+        }
+        Ok(())
+    }
+}
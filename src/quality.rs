@@ -0,0 +1,140 @@
+//! The quality controller lowers the quality of the matte when filtering a frame takes longer
+//! than the target framerate allows, and raises it again once there is time to spare. The
+//! quality levels combine a lower RVM downsample ratio, a lower resolution of the frames passed
+//! to the model and running the model on fewer frames.
+
+use crate::filter::Quality;
+use std::time::Duration;
+
+/// The quality levels from best to fastest.
+const LEVELS: &[Quality] = &[
+    Quality {
+        downsample_ratio: 0.5,
+        inference_scale: 1.0,
+        inference_interval: 1,
+    },
+    Quality {
+        downsample_ratio: 0.375,
+        inference_scale: 1.0,
+        inference_interval: 1,
+    },
+    Quality {
+        downsample_ratio: 0.25,
+        inference_scale: 1.0,
+        inference_interval: 1,
+    },
+    Quality {
+        downsample_ratio: 0.25,
+        inference_scale: 0.75,
+        inference_interval: 2,
+    },
+    Quality {
+        downsample_ratio: 0.25,
+        inference_scale: 0.5,
+        inference_interval: 3,
+    },
+    Quality {
+        downsample_ratio: 0.125,
+        inference_scale: 0.5,
+        inference_interval: 4,
+    },
+];
+/// The level the controller starts at, the same as the element's defaults.
+const START_LEVEL: usize = 2;
+/// Frames measured after a change before the level is changed again.
+const SETTLE_FRAMES: u32 = 30;
+/// Share of the frame period above which the quality is lowered.
+const HIGH_LOAD: f64 = 0.9;
+/// Share of the frame period below which the quality is raised. The gap to `HIGH_LOAD` keeps
+/// the controller from switching back and forth between two levels.
+const LOW_LOAD: f64 = 0.5;
+/// Weight of a new measurement in the moving average of the latency.
+const AVERAGE_WEIGHT: f64 = 0.1;
+
+#[derive(Debug)]
+pub struct QualityController {
+    /// The framerate to reach, 0 disables the controller
+    target_fps: f64,
+    level: usize,
+    /// Average time filtering a frame took at the current level, in seconds
+    latency: Option<f64>,
+    frames_at_level: u32,
+    /// Set when the filter does not use the quality of the current level yet
+    pending: bool,
+}
+
+impl Default for QualityController {
+    fn default() -> Self {
+        QualityController {
+            target_fps: 0.0,
+            level: START_LEVEL,
+            latency: None,
+            frames_at_level: 0,
+            pending: false,
+        }
+    }
+}
+
+impl QualityController {
+    pub fn enabled(&self) -> bool {
+        self.target_fps > 0.0
+    }
+
+    pub fn target_fps(&self) -> f64 {
+        self.target_fps
+    }
+
+    /// Change the framerate to reach, 0 disables the controller.
+    pub fn set_target_fps(&mut self, target_fps: f64) {
+        self.target_fps = target_fps;
+        self.level = START_LEVEL;
+        self.latency = None;
+        self.frames_at_level = 0;
+        self.pending = self.enabled();
+    }
+
+    /// The current level, 0 being the best quality.
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    pub fn quality(&self) -> Quality {
+        LEVELS[self.level]
+    }
+
+    /// Record that filtering a frame took `latency`. Returns the new quality if the level
+    /// changed, or if the target changed since the last frame.
+    pub fn record(&mut self, latency: Duration) -> Option<Quality> {
+        if !self.enabled() {
+            return None;
+        }
+        if self.pending {
+            // The frame was filtered at a different quality, so its latency is not counted
+            self.pending = false;
+            return Some(self.quality());
+        }
+        let latency = latency.as_secs_f64();
+        let average = match self.latency {
+            Some(average) => average + AVERAGE_WEIGHT * (latency - average),
+            None => latency,
+        };
+        self.latency = Some(average);
+        self.frames_at_level += 1;
+        if self.frames_at_level < SETTLE_FRAMES {
+            return None;
+        }
+
+        let load = average * self.target_fps;
+        let level = if load > HIGH_LOAD && self.level + 1 < LEVELS.len() {
+            self.level + 1
+        } else if load < LOW_LOAD && self.level > 0 {
+            self.level - 1
+        } else {
+            return None;
+        };
+        self.level = level;
+        self.latency = None;
+        self.frames_at_level = 0;
+        Some(LEVELS[level])
+    }
+}
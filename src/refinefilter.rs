@@ -0,0 +1,298 @@
+//! This filter refines the matte estimated by an earlier filter of a
+//! [`crate::chain::FilterChain`] before it is composited. Matting models work at a fraction of
+//! the frame resolution, so their mattes are blurry at the edges and flicker at fine details
+//! such as hair. The refinement steps run in this order, each can be disabled on its own:
+//!
+//! 1. A fast guided filter, which snaps the edges of the matte to the edges of the
+//!    full-resolution frame. See He and Sun, "Fast Guided Filter", 2015.
+//! 2. Growing or shrinking the matte, to remove a halo of background around the person or to
+//!    keep a model from cutting off e.g. the ears.
+//! 3. A levels curve: values below the black point become background, values above the white
+//!    point foreground and the gamma bends the curve in between.
+//! 4. Feathering the edge of the matte.
+
+use crate::filter::{Filter, FilterError, FrameContext, PostProcessing, StageTimings};
+use opencv::core::{Point, Scalar, Size, BORDER_DEFAULT, BORDER_REFLECT, CV_32F};
+use opencv::imgproc::{
+    box_filter, cvt_color, dilate, erode, gaussian_blur, get_structuring_element, resize,
+    COLOR_RGB2GRAY, INTER_AREA, INTER_LINEAR, MORPH_ELLIPSE,
+};
+use opencv::prelude::*;
+use std::time::Instant;
+
+/// The fast guided filter computes its coefficients on frames scaled down by this factor.
+const GUIDED_FILTER_SCALE: i32 = 4;
+
+/// How the matte is refined. The default leaves the matte as it is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Refinement {
+    /// Radius of the guided filter in pixels of the frame, 0 disables it
+    pub guided_radius: u32,
+    /// Regularisation of the guided filter, the larger the smoother the matte
+    pub guided_eps: f64,
+    /// Pixels by which the matte is grown, or shrunk if this is negative
+    pub grow: i32,
+    /// Values of the matte at and below which a pixel is background
+    pub black_point: f64,
+    /// Values of the matte at and above which a pixel is foreground
+    pub white_point: f64,
+    /// Exponent applied to the matte between the black and white point
+    pub gamma: f64,
+    /// Radius of the blur softening the edge of the matte in pixels, 0 disables it
+    pub feather: u32,
+}
+
+impl Default for Refinement {
+    fn default() -> Self {
+        Refinement {
+            guided_radius: 0,
+            guided_eps: 1e-3,
+            grow: 0,
+            black_point: 0.0,
+            white_point: 1.0,
+            gamma: 1.0,
+            feather: 0,
+        }
+    }
+}
+
+impl Refinement {
+    /// Whether refining changes the matte at all.
+    pub fn is_active(&self) -> bool {
+        self.guided_radius > 0
+            || self.grow != 0
+            || self.black_point > 0.0
+            || self.white_point < 1.0
+            || self.gamma != 1.0
+            || self.feather > 0
+    }
+}
+
+fn box_mean(src: &Mat, radius: i32) -> Result<Mat, FilterError> {
+    let size = 2 * radius + 1;
+    let mut mean = Mat::default();
+    box_filter(
+        src,
+        &mut mean,
+        -1,
+        Size::new(size, size),
+        Point::new(-1, -1),
+        true,
+        BORDER_REFLECT,
+    )?;
+    Ok(mean)
+}
+
+fn multiply(src1: &Mat, src2: &Mat) -> Result<Mat, FilterError> {
+    let mut product = Mat::default();
+    opencv::core::multiply(src1, src2, &mut product, 1.0, -1)?;
+    Ok(product)
+}
+
+fn subtract(src1: &Mat, src2: &Mat) -> Result<Mat, FilterError> {
+    let mut difference = Mat::default();
+    opencv::core::subtract(src1, src2, &mut difference, &opencv::core::no_array(), -1)?;
+    Ok(difference)
+}
+
+fn clamp_unit(src: &Mat) -> Result<Mat, FilterError> {
+    let mut upper = Mat::default();
+    opencv::core::min(src, &Scalar::all(1.0), &mut upper)?;
+    let mut clamped = Mat::default();
+    opencv::core::max(&upper, &Scalar::all(0.0), &mut clamped)?;
+    Ok(clamped)
+}
+
+#[derive(Debug)]
+pub struct RefineFilter {
+    refinement: Refinement,
+    /// Structuring element growing or shrinking the matte
+    kernel: Mat,
+    /// The refined matte of the last frame
+    last_pha: Option<Mat>,
+    timings: StageTimings,
+}
+
+impl RefineFilter {
+    pub fn new(refinement: Refinement) -> Result<RefineFilter, FilterError> {
+        let mut filter = RefineFilter {
+            refinement: Refinement::default(),
+            kernel: Mat::default(),
+            last_pha: None,
+            timings: StageTimings::default(),
+        };
+        filter.set_refinement(refinement)?;
+        Ok(filter)
+    }
+
+    /// Refine the matte as described by `refinement` from the next frame on.
+    pub fn set_refinement(&mut self, refinement: Refinement) -> Result<(), FilterError> {
+        if refinement.grow != self.refinement.grow || self.kernel.empty() {
+            let size = 2 * refinement.grow.abs() + 1;
+            self.kernel =
+                get_structuring_element(MORPH_ELLIPSE, Size::new(size, size), Point::new(-1, -1))?;
+        }
+        self.refinement = refinement;
+        Ok(())
+    }
+
+    /// Filter `pha` guided by the edges of `frame`.
+    fn guided_filter(&self, frame: &Mat, pha: &Mat) -> Result<Mat, FilterError> {
+        let size = frame.size()?;
+        let small_size = Size::new(
+            (size.width / GUIDED_FILTER_SCALE).max(1),
+            (size.height / GUIDED_FILTER_SCALE).max(1),
+        );
+        let radius = (self.refinement.guided_radius as i32 / GUIDED_FILTER_SCALE).max(1);
+
+        let mut gray = Mat::default();
+        cvt_color(frame, &mut gray, COLOR_RGB2GRAY, 0)?;
+        let mut guide = Mat::default();
+        gray.convert_to(&mut guide, CV_32F, 1.0 / 255.0, 0.0)?;
+        let mut guide_small = Mat::default();
+        resize(&guide, &mut guide_small, small_size, 0.0, 0.0, INTER_AREA)?;
+        let mut pha_small = Mat::default();
+        resize(pha, &mut pha_small, small_size, 0.0, 0.0, INTER_AREA)?;
+
+        // Fit pha = a * guide + b in the window around each pixel
+        let mean_guide = box_mean(&guide_small, radius)?;
+        let mean_pha = box_mean(&pha_small, radius)?;
+        let corr_guide = box_mean(&multiply(&guide_small, &guide_small)?, radius)?;
+        let corr_guide_pha = box_mean(&multiply(&guide_small, &pha_small)?, radius)?;
+        let var_guide = subtract(&corr_guide, &multiply(&mean_guide, &mean_guide)?)?;
+        let cov_guide_pha = subtract(&corr_guide_pha, &multiply(&mean_guide, &mean_pha)?)?;
+        let mut var_eps = Mat::default();
+        opencv::core::add(
+            &var_guide,
+            &Scalar::all(self.refinement.guided_eps),
+            &mut var_eps,
+            &opencv::core::no_array(),
+            -1,
+        )?;
+        let mut a = Mat::default();
+        opencv::core::divide2(&cov_guide_pha, &var_eps, &mut a, 1.0, -1)?;
+        let b = subtract(&mean_pha, &multiply(&a, &mean_guide)?)?;
+
+        // Average the coefficients of all windows covering a pixel and apply them to the
+        // full-resolution guide
+        let mut a_full = Mat::default();
+        resize(&box_mean(&a, radius)?, &mut a_full, size, 0.0, 0.0, INTER_LINEAR)?;
+        let mut b_full = Mat::default();
+        resize(&box_mean(&b, radius)?, &mut b_full, size, 0.0, 0.0, INTER_LINEAR)?;
+        let mut refined = Mat::default();
+        opencv::core::add(
+            &multiply(&a_full, &guide)?,
+            &b_full,
+            &mut refined,
+            &opencv::core::no_array(),
+            -1,
+        )?;
+        clamp_unit(&refined)
+    }
+
+    /// Grow the matte by `refinement.grow` pixels, or shrink it if that is negative.
+    fn grow(&self, pha: &Mat) -> Result<Mat, FilterError> {
+        let mut grown = Mat::default();
+        let border = opencv::imgproc::morphology_default_border_value()?;
+        if self.refinement.grow > 0 {
+            dilate(pha, &mut grown, &self.kernel, Point::new(-1, -1), 1, BORDER_DEFAULT, border)?;
+        } else {
+            erode(pha, &mut grown, &self.kernel, Point::new(-1, -1), 1, BORDER_DEFAULT, border)?;
+        }
+        Ok(grown)
+    }
+
+    /// Apply the black point, white point and gamma.
+    fn levels(&self, pha: &Mat) -> Result<Mat, FilterError> {
+        let black = self.refinement.black_point;
+        // A white point at or below the black point makes this a hard threshold
+        let range = (self.refinement.white_point - black).max(1e-3);
+        let mut stretched = Mat::default();
+        pha.convert_to(&mut stretched, CV_32F, 1.0 / range, -black / range)?;
+        let clamped = clamp_unit(&stretched)?;
+        if self.refinement.gamma == 1.0 {
+            return Ok(clamped);
+        }
+        let mut curved = Mat::default();
+        opencv::core::pow(&clamped, self.refinement.gamma, &mut curved)?;
+        Ok(curved)
+    }
+
+    fn feather(&self, pha: &Mat) -> Result<Mat, FilterError> {
+        let size = (2 * self.refinement.feather + 1) as i32;
+        let sigma = self.refinement.feather as f64 / 3.0;
+        let mut feathered = Mat::default();
+        gaussian_blur(
+            pha,
+            &mut feathered,
+            Size::new(size, size),
+            sigma,
+            sigma,
+            BORDER_DEFAULT,
+        )?;
+        Ok(feathered)
+    }
+
+    /// Refine `pha`, the matte of `frame`.
+    fn refine(&mut self, frame: &Mat, pha: &Mat) -> Result<Mat, FilterError> {
+        let mut pha = Mat::copy(pha)?;
+        if self.refinement.guided_radius > 0 {
+            let start = Instant::now();
+            pha = self.guided_filter(frame, &pha)?;
+            self.timings.record("guided-filter", start);
+        }
+        if self.refinement.grow != 0 {
+            let start = Instant::now();
+            pha = self.grow(&pha)?;
+            self.timings.record("grow", start);
+        }
+        let start = Instant::now();
+        pha = self.levels(&pha)?;
+        self.timings.record("levels", start);
+        if self.refinement.feather > 0 {
+            let start = Instant::now();
+            pha = self.feather(&pha)?;
+            self.timings.record("feather", start);
+        }
+        Ok(pha)
+    }
+}
+
+impl Filter for RefineFilter {
+    /// Without a matte from an earlier filter there is nothing to refine, the frame is left as
+    /// it is.
+    fn filter_inplace(&mut self, src_image: &mut Mat, bg_image: &Mat) -> Result<(), FilterError> {
+        let mut ctx = FrameContext::new(src_image, Some(bg_image));
+        self.process(&mut ctx)?;
+        self.last_pha = ctx.pha.take();
+        Ok(())
+    }
+
+    fn matte(&self) -> Option<&Mat> {
+        self.last_pha.as_ref()
+    }
+
+    fn timings(&self) -> Option<&StageTimings> {
+        Some(&self.timings)
+    }
+
+    fn set_post_processing(&mut self, post_processing: &PostProcessing) -> Result<(), FilterError> {
+        self.set_refinement(post_processing.refinement)
+    }
+
+    fn reset(&mut self) {
+        self.last_pha = None;
+    }
+
+    /// Refine the matte of an earlier filter. The chain always contains this filter so the
+    /// refinement can be turned on while it runs, until then the matte is passed on as it is.
+    fn process(&mut self, ctx: &mut FrameContext) -> Result<(), FilterError> {
+        self.timings.clear();
+        let pha = match &ctx.pha {
+            Some(pha) if self.refinement.is_active() => self.refine(ctx.frame, pha)?,
+            _ => return Ok(()),
+        };
+        ctx.pha = Some(pha);
+        Ok(())
+    }
+}
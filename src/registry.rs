@@ -0,0 +1,380 @@
+//! The filters compiled into the binary, looked up by name. Which filters are available depends
+//! on the cargo features, e.g. all filters using a matting model need the `rvm` feature.
+
+#[cfg(feature = "rvm")]
+use crate::asyncfilter::AsyncFilter;
+use crate::blurfilter::{BlurFilter, BlurKind};
+#[cfg(feature = "rvm")]
+use crate::chain::MatteStage;
+use crate::chain::{CompositeStage, FilterChain};
+use crate::chromakeyfilter::{ChromaKey, ChromaKeyFilter};
+use crate::composite::Blending;
+use crate::filter::{Filter, FilterError};
+#[cfg(feature = "rvm")]
+use crate::filter::MatteEstimator;
+use crate::noopfilter::NoopFilter;
+#[cfg(feature = "rvm")]
+use crate::onnxsegfilter::{OnnxSegFilter, SegmentationConfig};
+use crate::plugin::{Mode, Settings};
+use crate::refinefilter::{RefineFilter, Refinement};
+#[cfg(feature = "rvm")]
+use crate::runtime::RuntimeOptions;
+#[cfg(feature = "rvm")]
+use crate::rvmfilter::RVMFilter;
+#[cfg(feature = "rvm")]
+use crate::temporalfilter::TemporalFilter;
+
+/// A filter which can be selected by name.
+pub struct FilterInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Whether the filter needs the model-location setting
+    pub needs_model: bool,
+    build: fn(&FilterConfig) -> Result<Box<dyn Filter>, FilterError>,
+}
+
+/// How the model of a filter is loaded and run.
+#[cfg(feature = "rvm")]
+#[derive(Debug, Clone)]
+pub struct ModelOptions {
+    pub location: Option<String>,
+    /// Config of a segmentation model, RVM is used if it is `None`
+    pub config: Option<String>,
+    pub downsample_ratio: f32,
+    pub runtime: RuntimeOptions,
+    /// Run the model at least every this many frames and warp the matte in between
+    pub max_inference_interval: u32,
+    /// Whether the quality controller adjusts the model while it runs
+    pub quality_control: bool,
+    /// Run the model on a worker thread
+    pub async_inference: bool,
+}
+
+/// How the person is composited onto the background.
+#[derive(Debug, Clone, Copy)]
+pub struct CompositeOptions {
+    pub refinement: Refinement,
+    pub blending: Blending,
+    /// Whether filters which can do both blur the background instead of replacing it
+    pub blur: bool,
+    pub blur_kind: BlurKind,
+    pub blur_radius: u32,
+}
+
+/// The options the filters are built with, taken from the [`Settings`] in [`build`].
+#[derive(Debug, Clone)]
+pub struct FilterConfig {
+    #[cfg(feature = "rvm")]
+    pub model: ModelOptions,
+    pub chroma_key: ChromaKey,
+    pub composite: CompositeOptions,
+}
+
+impl FilterConfig {
+    pub fn new(settings: &Settings) -> FilterConfig {
+        FilterConfig {
+            #[cfg(feature = "rvm")]
+            model: ModelOptions {
+                location: settings.model_location.clone(),
+                config: settings.model_config.clone(),
+                downsample_ratio: settings.downsample_ratio as f32,
+                runtime: settings.runtime.clone(),
+                max_inference_interval: settings.max_inference_interval,
+                quality_control: settings.target_fps > 0.0,
+                async_inference: settings.async_inference,
+            },
+            chroma_key: settings.chroma_key(),
+            composite: CompositeOptions {
+                refinement: settings.refinement,
+                blending: settings.blending,
+                blur: settings.mode == Mode::Blur,
+                blur_kind: settings.blur_kind,
+                blur_radius: settings.blur_radius,
+            },
+        }
+    }
+}
+
+static FILTERS: &[FilterInfo] = &[
+    FilterInfo {
+        name: "noop",
+        description: "Pass the frames through unmodified",
+        needs_model: false,
+        build: |_| build_noop(),
+    },
+    FilterInfo {
+        name: "chroma",
+        description: "Key out the colour of a green screen, no model needed",
+        needs_model: false,
+        build: |config| build_chroma(&config.chroma_key, &config.composite),
+    },
+    #[cfg(feature = "rvm")]
+    FilterInfo {
+        name: "rvm",
+        description: "Replace the background using Robust Video Matting",
+        needs_model: true,
+        build: |config| build_rvm(&config.model, &config.composite),
+    },
+    #[cfg(feature = "rvm")]
+    FilterInfo {
+        name: "segmentation",
+        description: "Replace the background using a segmentation model described by model-config",
+        needs_model: true,
+        build: |config| build_segmentation(&config.model, &config.composite),
+    },
+    #[cfg(feature = "rvm")]
+    FilterInfo {
+        name: "blur",
+        description: "Blur the background, using RVM or the model described by model-config",
+        needs_model: true,
+        build: |config| build_blur(&config.model, &config.composite),
+    },
+];
+
+/// All filters compiled in.
+pub fn filters() -> &'static [FilterInfo] {
+    FILTERS
+}
+
+/// The names of all filters compiled in, separated by commas.
+pub fn filter_names() -> String {
+    FILTERS
+        .iter()
+        .map(|info| info.name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn find(name: &str) -> Option<&'static FilterInfo> {
+    FILTERS.iter().find(|info| info.name == name)
+}
+
+/// The filter used if none is selected explicitly, which depends on the mode and whether a
+/// model config is given. Without the `rvm` feature only the chroma key can separate the person
+/// from the background.
+pub fn default_filter(mode: Mode, has_model_config: bool) -> &'static str {
+    match mode {
+        Mode::Noop => "noop",
+        _ if !cfg!(feature = "rvm") => "chroma",
+        Mode::Blur => "blur",
+        Mode::Replace | Mode::Alpha if has_model_config => "segmentation",
+        Mode::Replace | Mode::Alpha => "rvm",
+    }
+}
+
+/// Construct the filter called `name` configured by `settings`.
+pub fn build(name: &str, settings: &Settings) -> Result<Box<dyn Filter>, FilterError> {
+    match find(name) {
+        Some(info) => (info.build)(&FilterConfig::new(settings)),
+        None => Err(FilterError::Other(format!(
+            "Unknown filter '{}', the filters available are {}",
+            name,
+            filter_names()
+        ))),
+    }
+}
+
+fn build_noop() -> Result<Box<dyn Filter>, FilterError> {
+    Ok(Box::new(NoopFilter::default()))
+}
+
+fn build_chroma(
+    key: &ChromaKey,
+    composite: &CompositeOptions,
+) -> Result<Box<dyn Filter>, FilterError> {
+    let chroma = ChromaKeyFilter::new(key);
+    background_chain(Box::new(chroma), composite, composite.blur)
+}
+
+/// The chain compositing the frame onto the background, or onto a blurred copy of the frame if
+/// `blur` is set, using the matte estimated by `matte` and refined as `composite` asks for.
+/// The refinement is part of the chain even if it is off, so it can be turned on while the
+/// chain runs.
+fn background_chain(
+    matte: Box<dyn Filter>,
+    composite: &CompositeOptions,
+    blur: bool,
+) -> Result<Box<dyn Filter>, FilterError> {
+    let mut filters = vec![matte];
+    filters.push(Box::new(RefineFilter::new(composite.refinement)?));
+    if blur {
+        filters.push(Box::new(BlurFilter::new(
+            composite.blur_kind,
+            composite.blur_radius,
+        )?));
+    }
+    filters.push(Box::new(CompositeStage::new(composite.blending)));
+    Ok(Box::new(FilterChain::new(filters)))
+}
+
+#[cfg(feature = "rvm")]
+fn model_location(model: &ModelOptions) -> Result<String, FilterError> {
+    model
+        .location
+        .clone()
+        .ok_or_else(|| FilterError::Other(String::from("No model-location configured")))
+}
+
+#[cfg(feature = "rvm")]
+fn rvm_model(model: &ModelOptions) -> Result<RVMFilter, FilterError> {
+    RVMFilter::new(
+        model_location(model)?,
+        model.downsample_ratio,
+        &model.runtime,
+    )
+}
+
+#[cfg(feature = "rvm")]
+fn segmentation_model(model: &ModelOptions) -> Result<OnnxSegFilter, FilterError> {
+    let model_config = model.config.as_ref().ok_or_else(|| {
+        FilterError::Other(String::from(
+            "The segmentation filter needs a model-config describing the model",
+        ))
+    })?;
+    let config = SegmentationConfig::load(model_config)?;
+    OnnxSegFilter::new(model_location(model)?, config, &model.runtime)
+}
+
+#[cfg(feature = "rvm")]
+fn build_rvm(
+    model: &ModelOptions,
+    composite: &CompositeOptions,
+) -> Result<Box<dyn Filter>, FilterError> {
+    let estimator = wrap_estimator(Box::new(rvm_model(model)?), model)?;
+    background_chain(Box::new(MatteStage::new(estimator)), composite, false)
+}
+
+#[cfg(feature = "rvm")]
+fn build_segmentation(
+    model: &ModelOptions,
+    composite: &CompositeOptions,
+) -> Result<Box<dyn Filter>, FilterError> {
+    let estimator = wrap_estimator(Box::new(segmentation_model(model)?), model)?;
+    background_chain(Box::new(MatteStage::new(estimator)), composite, false)
+}
+
+#[cfg(feature = "rvm")]
+fn build_blur(
+    model: &ModelOptions,
+    composite: &CompositeOptions,
+) -> Result<Box<dyn Filter>, FilterError> {
+    let estimator: Box<dyn MatteEstimator> = if model.config.is_some() {
+        Box::new(segmentation_model(model)?)
+    } else {
+        Box::new(rvm_model(model)?)
+    };
+    let estimator = wrap_estimator(estimator, model)?;
+    background_chain(Box::new(MatteStage::new(estimator)), composite, true)
+}
+
+/// Use `estimator` directly, or wrap it in the estimators doing what `model` asks for.
+#[cfg(feature = "rvm")]
+fn wrap_estimator(
+    estimator: Box<dyn MatteEstimator>,
+    model: &ModelOptions,
+) -> Result<Box<dyn MatteEstimator>, FilterError> {
+    let mut estimator = estimator;
+    // The quality controller changes the interval, so it needs the temporal filter
+    if model.max_inference_interval > 1 || model.quality_control {
+        estimator = Box::new(TemporalFilter::new(estimator, model.max_inference_interval));
+    }
+    if model.async_inference {
+        estimator = Box::new(AsyncFilter::new(estimator)?);
+    }
+    Ok(estimator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_filters() {
+        let model_filter = |name: &'static str| {
+            if cfg!(feature = "rvm") {
+                name
+            } else {
+                "chroma"
+            }
+        };
+        let cases = [
+            (Mode::Noop, false, "noop"),
+            (Mode::Noop, true, "noop"),
+            (Mode::Blur, false, model_filter("blur")),
+            (Mode::Blur, true, model_filter("blur")),
+            (Mode::Replace, false, model_filter("rvm")),
+            (Mode::Replace, true, model_filter("segmentation")),
+            (Mode::Alpha, false, model_filter("rvm")),
+            (Mode::Alpha, true, model_filter("segmentation")),
+        ];
+        for &(mode, has_model_config, expected) in &cases {
+            let name = default_filter(mode, has_model_config);
+            assert_eq!(name, expected, "{} {}", mode, has_model_config);
+            assert!(find(name).is_some(), "{} is not compiled in", name);
+        }
+    }
+
+    #[test]
+    fn find_filters() {
+        let cases = [
+            ("noop", true),
+            ("chroma", true),
+            ("rvm", cfg!(feature = "rvm")),
+            ("segmentation", cfg!(feature = "rvm")),
+            ("blur", cfg!(feature = "rvm")),
+            ("", false),
+            ("Noop", false),
+            ("magic", false),
+        ];
+        for &(name, available) in &cases {
+            assert_eq!(find(name).is_some(), available, "{:?}", name);
+            assert_eq!(
+                filter_names().split(", ").any(|n| n == name),
+                available,
+                "{:?}",
+                name
+            );
+        }
+        for info in filters() {
+            assert_eq!(find(info.name).map(|found| found.name), Some(info.name));
+        }
+    }
+
+    #[test]
+    fn filter_config() {
+        for &mode in &[Mode::Noop, Mode::Blur, Mode::Replace, Mode::Alpha] {
+            let settings = Settings {
+                mode,
+                blur_radius: 21,
+                ..Default::default()
+            };
+            let config = FilterConfig::new(&settings);
+            assert_eq!(config.composite.blur, mode == Mode::Blur, "{}", mode);
+            assert_eq!(config.composite.blur_radius, 21);
+        }
+
+        #[cfg(feature = "rvm")]
+        for &(target_fps, quality_control) in &[(0.0, false), (30.0, true)] {
+            let settings = Settings {
+                model_location: Some(String::from("model.onnx")),
+                target_fps,
+                ..Default::default()
+            };
+            let model = FilterConfig::new(&settings).model;
+            assert_eq!(model.location.as_deref(), Some("model.onnx"));
+            assert_eq!(model.quality_control, quality_control);
+        }
+    }
+
+    #[test]
+    fn build_filters() {
+        let settings = Settings::default();
+        assert!(build("noop", &settings).is_ok());
+        assert!(build("chroma", &settings).is_ok());
+        assert!(build("magic", &settings).is_err());
+        // Filters using a model fail without model-location
+        for info in filters().iter().filter(|info| info.needs_model) {
+            assert!(build(info.name, &settings).is_err(), "{}", info.name);
+        }
+    }
+}
@@ -0,0 +1,1089 @@
+//! How ONNX Runtime runs the models: how many threads it uses, how much it optimises the graph,
+//! how much it logs and on which execution providers it runs.
+//!
+//! The onnxruntime crate only offers the CPU provider, so other providers are recognised but
+//! have no effect, and models always run on the CPU.
+//!
+//! Optimising the graph of a large model takes a while, so the optimised graph can be saved to
+//! a cache directory and loaded from there on later starts without optimising it again. The
+//! onnxruntime crate does not offer to save the optimised graph, so the model is optimised once
+//! in a session created through the C API of ONNX Runtime, which does.
+//!
+//! Models run on every frame are loaded into a [`RawSession`] through the C API as well, as the
+//! sessions of the onnxruntime crate allocate new tensors for the inputs and outputs of every
+//! run.
+
+#[cfg(feature = "rvm")]
+use crate::filter::FilterError;
+#[cfg(feature = "rvm")]
+use core::ffi::c_void;
+use gstreamer::glib;
+#[cfg(feature = "rvm")]
+use once_cell::sync::OnceCell;
+#[cfg(feature = "rvm")]
+use onnxruntime::environment::Environment;
+#[cfg(feature = "rvm")]
+use onnxruntime::ndarray::{self, IxDyn};
+#[cfg(feature = "rvm")]
+use onnxruntime::session::Session;
+#[cfg(feature = "rvm")]
+use onnxruntime::GraphOptimizationLevel;
+#[cfg(feature = "rvm")]
+use onnxruntime_sys as sys;
+#[cfg(feature = "rvm")]
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+#[cfg(feature = "rvm")]
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs;
+#[cfg(feature = "rvm")]
+use std::hash::{Hash, Hasher};
+#[cfg(feature = "rvm")]
+use std::mem;
+#[cfg(feature = "rvm")]
+use std::os::raw::c_char;
+#[cfg(feature = "rvm")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "rvm")]
+use std::ptr;
+use std::str::FromStr;
+use std::thread;
+
+/// How much ONNX Runtime optimises the graph of a model when loading it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, glib::Enum)]
+#[enum_type(name = "FakecamOptimizationLevel")]
+pub enum OptimizationLevel {
+    #[enum_value(name = "No optimisations", nick = "disabled")]
+    Disabled,
+    /// Only optimisations which do not change the semantics, e.g. constant folding
+    #[enum_value(name = "Semantics-preserving optimisations", nick = "basic")]
+    Basic,
+    /// Also fuse nodes into more complex ones
+    #[enum_value(name = "Also fuse nodes", nick = "extended")]
+    Extended,
+    /// Also change the layout of the data
+    #[enum_value(name = "Also change the data layout", nick = "all")]
+    All,
+}
+
+impl OptimizationLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OptimizationLevel::Disabled => "disabled",
+            OptimizationLevel::Basic => "basic",
+            OptimizationLevel::Extended => "extended",
+            OptimizationLevel::All => "all",
+        }
+    }
+}
+
+impl fmt::Display for OptimizationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for OptimizationLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(OptimizationLevel::Disabled),
+            "basic" => Ok(OptimizationLevel::Basic),
+            "extended" => Ok(OptimizationLevel::Extended),
+            "all" => Ok(OptimizationLevel::All),
+            other => Err(format!(
+                "Unknown optimization level '{}', expected one of disabled, basic, extended or all",
+                other
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "rvm")]
+impl From<OptimizationLevel> for onnxruntime::GraphOptimizationLevel {
+    fn from(level: OptimizationLevel) -> Self {
+        match level {
+            OptimizationLevel::Disabled => onnxruntime::GraphOptimizationLevel::DisableAll,
+            OptimizationLevel::Basic => onnxruntime::GraphOptimizationLevel::Basic,
+            OptimizationLevel::Extended => onnxruntime::GraphOptimizationLevel::Extended,
+            OptimizationLevel::All => onnxruntime::GraphOptimizationLevel::All,
+        }
+    }
+}
+
+/// The least severe messages ONNX Runtime logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, glib::Enum)]
+#[enum_type(name = "FakecamLogLevel")]
+pub enum LogLevel {
+    #[enum_value(name = "Verbose", nick = "verbose")]
+    Verbose,
+    #[enum_value(name = "Info", nick = "info")]
+    Info,
+    #[enum_value(name = "Warning", nick = "warning")]
+    Warning,
+    #[enum_value(name = "Error", nick = "error")]
+    Error,
+    #[enum_value(name = "Fatal", nick = "fatal")]
+    Fatal,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Verbose => "verbose",
+            LogLevel::Info => "info",
+            LogLevel::Warning => "warning",
+            LogLevel::Error => "error",
+            LogLevel::Fatal => "fatal",
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "verbose" => Ok(LogLevel::Verbose),
+            "info" => Ok(LogLevel::Info),
+            "warning" => Ok(LogLevel::Warning),
+            "error" => Ok(LogLevel::Error),
+            "fatal" => Ok(LogLevel::Fatal),
+            other => Err(format!(
+                "Unknown log level '{}', expected one of verbose, info, warning, error or fatal",
+                other
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "rvm")]
+impl From<LogLevel> for onnxruntime::LoggingLevel {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Verbose => onnxruntime::LoggingLevel::Verbose,
+            LogLevel::Info => onnxruntime::LoggingLevel::Info,
+            LogLevel::Warning => onnxruntime::LoggingLevel::Warning,
+            LogLevel::Error => onnxruntime::LoggingLevel::Error,
+            LogLevel::Fatal => onnxruntime::LoggingLevel::Fatal,
+        }
+    }
+}
+
+/// A device ONNX Runtime can run a model on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionProvider {
+    Cpu,
+    Cuda,
+    TensorRt,
+    Rocm,
+    OpenVino,
+    CoreMl,
+}
+
+impl ExecutionProvider {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExecutionProvider::Cpu => "cpu",
+            ExecutionProvider::Cuda => "cuda",
+            ExecutionProvider::TensorRt => "tensorrt",
+            ExecutionProvider::Rocm => "rocm",
+            ExecutionProvider::OpenVino => "openvino",
+            ExecutionProvider::CoreMl => "coreml",
+        }
+    }
+
+    /// Whether models can run on this provider in this build. Only the CPU provider is, the
+    /// others are accepted so configurations keep working once they are.
+    pub fn is_available(&self) -> bool {
+        *self == ExecutionProvider::Cpu
+    }
+
+    /// Parse a comma separated list of providers, e.g. `cuda,cpu`.
+    pub fn parse_list(list: &str) -> Result<Vec<ExecutionProvider>, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    /// Format `providers` as a comma separated list.
+    pub fn format_list(providers: &[ExecutionProvider]) -> String {
+        providers
+            .iter()
+            .map(|provider| provider.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl fmt::Display for ExecutionProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ExecutionProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpu" => Ok(ExecutionProvider::Cpu),
+            "cuda" => Ok(ExecutionProvider::Cuda),
+            "tensorrt" => Ok(ExecutionProvider::TensorRt),
+            "rocm" => Ok(ExecutionProvider::Rocm),
+            "openvino" => Ok(ExecutionProvider::OpenVino),
+            "coreml" => Ok(ExecutionProvider::CoreMl),
+            other => Err(format!(
+                "Unknown execution provider '{}', expected one of cpu, cuda, tensorrt, rocm, \
+                 openvino or coreml",
+                other
+            )),
+        }
+    }
+}
+
+/// The number of physical cores, counted from /proc/cpuinfo. Hyperthreads hardly speed up
+/// inference, so this is the default number of threads. Falls back to the number of logical
+/// CPUs if /proc/cpuinfo is not available or does not list the cores, e.g. on ARM.
+pub fn physical_cores() -> usize {
+    let cores = fs::read_to_string("/proc/cpuinfo")
+        .map(|cpuinfo| count_cores(&cpuinfo))
+        .unwrap_or(0);
+    if cores > 0 {
+        return cores;
+    }
+    thread::available_parallelism()
+        .map(|cpus| cpus.get())
+        .unwrap_or(1)
+}
+
+/// Count the distinct pairs of physical id and core id in the contents of /proc/cpuinfo.
+fn count_cores(cpuinfo: &str) -> usize {
+    let mut cores = HashSet::new();
+    let mut physical_id = "";
+    for line in cpuinfo.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        match key {
+            // Each logical CPU starts a new block
+            "processor" => physical_id = "",
+            "physical id" => physical_id = value,
+            "core id" => {
+                cores.insert((physical_id, value));
+            }
+            _ => {}
+        }
+    }
+    cores.len()
+}
+
+/// How ONNX Runtime runs the models.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeOptions {
+    /// Number of threads used for inference, 0 uses one per physical core
+    pub num_threads: u32,
+    pub optimization: OptimizationLevel,
+    /// ONNX Runtime only has one environment per process, so this only takes effect for the
+    /// first model loaded
+    pub log_level: LogLevel,
+    /// The providers to run the models on in order of preference. Only the CPU provider is
+    /// available, so this has no effect yet.
+    pub execution_providers: Vec<ExecutionProvider>,
+    /// Directory the optimised models are saved to and loaded from, models are optimised on
+    /// every start if this is `None`
+    pub model_cache: Option<String>,
+}
+
+impl Default for RuntimeOptions {
+    fn default() -> Self {
+        RuntimeOptions {
+            num_threads: 0,
+            optimization: OptimizationLevel::Basic,
+            log_level: LogLevel::Warning,
+            execution_providers: vec![ExecutionProvider::Cpu],
+            model_cache: None,
+        }
+    }
+}
+
+impl RuntimeOptions {
+    /// The number of threads used for inference.
+    pub fn threads(&self) -> i16 {
+        let threads = match self.num_threads {
+            0 => physical_cores(),
+            threads => threads as usize,
+        };
+        threads.min(i16::MAX as usize) as i16
+    }
+
+    /// The first of the execution providers available in this build, or the CPU provider.
+    pub fn execution_provider(&self) -> ExecutionProvider {
+        self.execution_providers
+            .iter()
+            .copied()
+            .find(ExecutionProvider::is_available)
+            .unwrap_or(ExecutionProvider::Cpu)
+    }
+}
+
+#[cfg(feature = "rvm")]
+static ORT_ENV: OnceCell<(Environment, LogLevel)> = OnceCell::new();
+
+/// The log level of the ONNX Runtime environment, if it has been created already.
+#[cfg(feature = "rvm")]
+pub fn environment_log_level() -> Option<LogLevel> {
+    ORT_ENV.get().map(|(_, log_level)| *log_level)
+}
+
+/// The ONNX Runtime environment of the process, created with `log_level` if it does not exist
+/// yet.
+#[cfg(feature = "rvm")]
+fn environment(log_level: LogLevel) -> Result<&'static Environment, FilterError> {
+    let (env, _) = ORT_ENV.get_or_try_init(|| -> Result<_, FilterError> {
+        let env = Environment::builder()
+            .with_log_level(log_level.into())
+            .with_name("rvmruntime")
+            .build()?;
+        Ok((env, log_level))
+    })?;
+    Ok(env)
+}
+
+/// Load the model from `model_file` into a session configured by `options`. If there is a
+/// model cache, the optimised model is loaded from it, after saving it there first if it is
+/// missing or older than `model_file`.
+#[cfg(feature = "rvm")]
+pub fn new_session<'a, P: AsRef<Path> + 'a>(
+    model_file: P,
+    options: &RuntimeOptions,
+) -> Result<Session<'a>, FilterError> {
+    let env: &'a Environment = environment(options.log_level)?;
+    let (model_file, optimization) = prepare_model(model_file.as_ref(), options)?;
+    let session = env
+        .new_session_builder()?
+        .with_optimization_level(optimization.into())?
+        .with_number_threads(options.threads())?
+        .with_model_from_file(model_file)?;
+    Ok(session)
+}
+
+/// The file to load the model in `model_file` from and how much to optimise it while loading.
+/// With a model cache, this is the cached model, saved first if it is missing or older than
+/// `model_file`, and it is not optimised again.
+#[cfg(feature = "rvm")]
+fn prepare_model(
+    model_file: &Path,
+    options: &RuntimeOptions,
+) -> Result<(PathBuf, OptimizationLevel), FilterError> {
+    let cache_file = match &options.model_cache {
+        Some(cache) if options.optimization != OptimizationLevel::Disabled => {
+            cached_model_path(Path::new(cache), model_file, options.optimization)
+        }
+        _ => return Ok((model_file.to_path_buf(), options.optimization)),
+    };
+    if !is_up_to_date(&cache_file, model_file) {
+        fs::create_dir_all(cache_file.parent().unwrap_or_else(|| Path::new(".")))
+            .map_err(|e| FilterError::Other(format!("Failed to create the model cache: {}", e)))?;
+        save_optimized_model(model_file, &cache_file, options)?;
+    }
+    Ok((cache_file, OptimizationLevel::Disabled))
+}
+
+/// The file in the cache directory `cache` holding `model_file` optimised at `level`. Besides
+/// the file stem, the name has a hash of the canonical path, the size and the modification time
+/// of the model, so models with the same name in different directories do not overwrite each
+/// other's cached graph.
+#[cfg(feature = "rvm")]
+fn cached_model_path(cache: &Path, model_file: &Path, level: OptimizationLevel) -> PathBuf {
+    let stem = model_file.file_stem().map_or_else(
+        || String::from("model"),
+        |stem| stem.to_string_lossy().into_owned(),
+    );
+    let mut hasher = DefaultHasher::new();
+    fs::canonicalize(model_file)
+        .unwrap_or_else(|_| model_file.to_path_buf())
+        .hash(&mut hasher);
+    if let Ok(metadata) = fs::metadata(model_file) {
+        metadata.len().hash(&mut hasher);
+        metadata.modified().ok().hash(&mut hasher);
+    }
+    cache.join(format!("{}.{:016x}.{}.onnx", stem, hasher.finish(), level))
+}
+
+/// Whether `cache_file` exists and was saved after `model_file` was last modified.
+#[cfg(feature = "rvm")]
+fn is_up_to_date(cache_file: &Path, model_file: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    match (modified(cache_file), modified(model_file)) {
+        (Ok(cached), Ok(model)) => cached >= model,
+        _ => false,
+    }
+}
+
+/// The C API of ONNX Runtime.
+#[cfg(feature = "rvm")]
+fn ort_api() -> Result<&'static sys::OrtApi, FilterError> {
+    let api = unsafe {
+        let base = sys::OrtGetApiBase();
+        if base.is_null() {
+            None
+        } else {
+            (*base)
+                .GetApi
+                .and_then(|get_api| get_api(sys::ORT_API_VERSION).as_ref())
+        }
+    };
+    api.ok_or_else(|| FilterError::Other(String::from("ONNX Runtime API is not available")))
+}
+
+/// Turn a status returned by the C API into an error, releasing it.
+#[cfg(feature = "rvm")]
+unsafe fn check_status(api: &sys::OrtApi, status: sys::OrtStatusPtr) -> Result<(), FilterError> {
+    if status.is_null() {
+        return Ok(());
+    }
+    let message = match api.GetErrorMessage {
+        Some(get_message) => CStr::from_ptr(get_message(status))
+            .to_string_lossy()
+            .into_owned(),
+        None => String::from("unknown error"),
+    };
+    if let Some(release) = api.ReleaseStatus {
+        release(status);
+    }
+    Err(FilterError::Other(format!(
+        "ONNX Runtime failed: {}",
+        message
+    )))
+}
+
+/// An object of the C API, released when dropped.
+#[cfg(feature = "rvm")]
+struct Owned<T> {
+    ptr: *mut T,
+    release: Option<unsafe extern "C" fn(*mut T)>,
+}
+
+#[cfg(feature = "rvm")]
+impl<T> Drop for Owned<T> {
+    fn drop(&mut self) {
+        if let (false, Some(release)) = (self.ptr.is_null(), self.release) {
+            unsafe { release(self.ptr) };
+        }
+    }
+}
+
+/// A function of the C API, or an error if this version of ONNX Runtime lacks it.
+#[cfg(feature = "rvm")]
+fn api_fn<F>(function: Option<F>, name: &str) -> Result<F, FilterError> {
+    function.ok_or_else(|| FilterError::Other(format!("ONNX Runtime does not offer {}", name)))
+}
+
+/// `path` as a C string.
+#[cfg(feature = "rvm")]
+fn path_string(path: &Path) -> Result<CString, FilterError> {
+    path.to_str()
+        .and_then(|path| CString::new(path).ok())
+        .ok_or_else(|| FilterError::Other(format!("Unsupported model path {:?}", path)))
+}
+
+/// A reference to the ONNX Runtime environment of the process. There is only one, so this is
+/// the one of `environment` if that exists already.
+#[cfg(feature = "rvm")]
+unsafe fn create_env(
+    api: &sys::OrtApi,
+    options: &RuntimeOptions,
+) -> Result<Owned<sys::OrtEnv>, FilterError> {
+    let name = CString::new("rvmruntime").expect("The name does not contain NUL");
+    let log_level: sys::OrtLoggingLevel =
+        onnxruntime::LoggingLevel::from(environment_log_level().unwrap_or(options.log_level))
+            .into();
+    let mut env = Owned {
+        ptr: ptr::null_mut(),
+        release: api.ReleaseEnv,
+    };
+    let create_env = api_fn(api.CreateEnv, "CreateEnv")?;
+    check_status(api, create_env(log_level, name.as_ptr(), &mut env.ptr))?;
+    Ok(env)
+}
+
+/// Session options optimising the graph at `optimization` and running on the threads of
+/// `options`.
+#[cfg(feature = "rvm")]
+unsafe fn session_options(
+    api: &sys::OrtApi,
+    optimization: OptimizationLevel,
+    options: &RuntimeOptions,
+) -> Result<Owned<sys::OrtSessionOptions>, FilterError> {
+    let optimization: sys::GraphOptimizationLevel =
+        GraphOptimizationLevel::from(optimization).into();
+    let mut session_options = Owned {
+        ptr: ptr::null_mut(),
+        release: api.ReleaseSessionOptions,
+    };
+    let create_options = api_fn(api.CreateSessionOptions, "CreateSessionOptions")?;
+    check_status(api, create_options(&mut session_options.ptr))?;
+    let set_level = api_fn(
+        api.SetSessionGraphOptimizationLevel,
+        "SetSessionGraphOptimizationLevel",
+    )?;
+    check_status(api, set_level(session_options.ptr, optimization))?;
+    let set_threads = api_fn(api.SetIntraOpNumThreads, "SetIntraOpNumThreads")?;
+    check_status(
+        api,
+        set_threads(session_options.ptr, options.threads() as i32),
+    )?;
+    Ok(session_options)
+}
+
+/// Optimise `model_file` as configured by `options` and save the optimised model to
+/// `cache_file`.
+#[cfg(feature = "rvm")]
+fn save_optimized_model(
+    model_file: &Path,
+    cache_file: &Path,
+    options: &RuntimeOptions,
+) -> Result<(), FilterError> {
+    let model_path = path_string(model_file)?;
+    let cache_path = path_string(cache_file)?;
+    let api = ort_api()?;
+    unsafe {
+        let env = create_env(api, options)?;
+        let session_options = session_options(api, options.optimization, options)?;
+        let set_path = api_fn(api.SetOptimizedModelFilePath, "SetOptimizedModelFilePath")?;
+        check_status(api, set_path(session_options.ptr, cache_path.as_ptr()))?;
+
+        // Creating the session optimises the model and saves it
+        let mut session = Owned {
+            ptr: ptr::null_mut(),
+            release: api.ReleaseSession,
+        };
+        let create_session = api_fn(api.CreateSession, "CreateSession")?;
+        check_status(
+            api,
+            create_session(
+                env.ptr,
+                model_path.as_ptr(),
+                session_options.ptr,
+                &mut session.ptr,
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+/// A tensor of f32 values passed to or returned from [`RawSession::run`]. A tensor created by
+/// [`Tensor::zeros`] has a buffer of its own, which the model reads from, or writes to when it
+/// is passed as an output, on every run. An empty tensor passed as an output is given an output
+/// allocated by ONNX Runtime instead.
+#[cfg(feature = "rvm")]
+#[derive(Debug)]
+pub struct Tensor {
+    value: *mut sys::OrtValue,
+    buffer: Option<ndarray::ArrayD<f32>>,
+}
+
+#[cfg(feature = "rvm")]
+impl Default for Tensor {
+    fn default() -> Self {
+        Tensor {
+            value: ptr::null_mut(),
+            buffer: None,
+        }
+    }
+}
+
+#[cfg(feature = "rvm")]
+impl Drop for Tensor {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(feature = "rvm")]
+impl Tensor {
+    /// A tensor of `shape` filled with zeros, in a buffer of its own.
+    pub fn zeros(shape: &[usize]) -> Result<Tensor, FilterError> {
+        let mut buffer = ndarray::ArrayD::zeros(IxDyn(shape));
+        let dims: Vec<i64> = shape.iter().map(|&dim| dim as i64).collect();
+        let api = ort_api()?;
+        let mut tensor = Tensor::default();
+        unsafe {
+            let mut memory_info = Owned {
+                ptr: ptr::null_mut(),
+                release: api.ReleaseMemoryInfo,
+            };
+            let create_info = api_fn(api.CreateCpuMemoryInfo, "CreateCpuMemoryInfo")?;
+            check_status(
+                api,
+                create_info(
+                    sys::OrtAllocatorType::OrtArenaAllocator,
+                    sys::OrtMemType::OrtMemTypeDefault,
+                    &mut memory_info.ptr,
+                ),
+            )?;
+            // The value keeps a copy of the memory info, and the data of the buffer stays
+            // where it is when the buffer is moved into the tensor
+            let data = buffer
+                .as_slice_mut()
+                .expect("Arrays are created contiguous");
+            let create_tensor = api_fn(
+                api.CreateTensorWithDataAsOrtValue,
+                "CreateTensorWithDataAsOrtValue",
+            )?;
+            check_status(
+                api,
+                create_tensor(
+                    memory_info.ptr,
+                    data.as_mut_ptr() as *mut c_void,
+                    data.len() * mem::size_of::<f32>(),
+                    dims.as_ptr(),
+                    dims.len(),
+                    sys::ONNXTensorElementDataType::ONNX_TENSOR_ELEMENT_DATA_TYPE_FLOAT,
+                    &mut tensor.value,
+                ),
+            )?;
+        }
+        tensor.buffer = Some(buffer);
+        Ok(tensor)
+    }
+
+    /// Whether the tensor has a buffer of its own.
+    pub fn has_buffer(&self) -> bool {
+        self.buffer.is_some()
+    }
+
+    /// The buffer of the tensor, if it has one.
+    pub fn buffer(&self) -> Option<&ndarray::ArrayD<f32>> {
+        self.buffer.as_ref()
+    }
+
+    /// The buffer of the tensor, if it has one. Its shape must not be changed.
+    pub fn buffer_mut(&mut self) -> Option<&mut ndarray::ArrayD<f32>> {
+        self.buffer.as_mut()
+    }
+
+    /// The values of the tensor, whether they are in its own buffer or were allocated by ONNX
+    /// Runtime.
+    pub fn view(&self) -> Result<ndarray::ArrayViewD<f32>, FilterError> {
+        if let Some(buffer) = &self.buffer {
+            return Ok(buffer.view());
+        }
+        if self.value.is_null() {
+            return Err(FilterError::Other(String::from("The tensor has no values")));
+        }
+        let api = ort_api()?;
+        unsafe {
+            let mut info = Owned {
+                ptr: ptr::null_mut(),
+                release: api.ReleaseTensorTypeAndShapeInfo,
+            };
+            let get_info = api_fn(api.GetTensorTypeAndShape, "GetTensorTypeAndShape")?;
+            check_status(api, get_info(self.value, &mut info.ptr))?;
+            let mut element_type =
+                sys::ONNXTensorElementDataType::ONNX_TENSOR_ELEMENT_DATA_TYPE_UNDEFINED;
+            let get_type = api_fn(api.GetTensorElementType, "GetTensorElementType")?;
+            check_status(api, get_type(info.ptr, &mut element_type))?;
+            if element_type != sys::ONNXTensorElementDataType::ONNX_TENSOR_ELEMENT_DATA_TYPE_FLOAT {
+                return Err(FilterError::Other(format!(
+                    "Expected a tensor of floats, got {:?}",
+                    element_type
+                )));
+            }
+            let mut count = 0;
+            let get_count = api_fn(api.GetDimensionsCount, "GetDimensionsCount")?;
+            check_status(api, get_count(info.ptr, &mut count))?;
+            let mut dims = vec![0i64; count];
+            let get_dims = api_fn(api.GetDimensions, "GetDimensions")?;
+            check_status(api, get_dims(info.ptr, dims.as_mut_ptr(), count))?;
+            let shape: Vec<usize> = dims.iter().map(|&dim| dim.max(0) as usize).collect();
+
+            let mut data = ptr::null_mut();
+            let get_data = api_fn(api.GetTensorMutableData, "GetTensorMutableData")?;
+            check_status(api, get_data(self.value, &mut data))?;
+            let values =
+                std::slice::from_raw_parts(data as *const f32, shape.iter().product::<usize>());
+            ndarray::ArrayViewD::from_shape(IxDyn(&shape), values)
+                .map_err(|e| FilterError::Other(format!("Invalid output tensor: {}", e)))
+        }
+    }
+
+    /// Release the value of the tensor and its buffer, leaving it empty.
+    fn release(&mut self) {
+        if !self.value.is_null() {
+            if let Some(release) = ort_api().ok().and_then(|api| api.ReleaseValue) {
+                unsafe { release(self.value) };
+            }
+            self.value = ptr::null_mut();
+        }
+        self.buffer = None;
+    }
+}
+
+/// A model loaded through the C API of ONNX Runtime. Unlike `Session::run` of the onnxruntime
+/// crate, which consumes its inputs and allocates the outputs, [`RawSession::run`] reads and
+/// writes [`Tensor`]s kept by the caller, so they can be reused from run to run.
+#[cfg(feature = "rvm")]
+pub struct RawSession {
+    api: &'static sys::OrtApi,
+    // The session is released before the environment
+    session: Owned<sys::OrtSession>,
+    _env: Owned<sys::OrtEnv>,
+    input_names: Vec<CString>,
+    output_names: Vec<CString>,
+    /// The pointers passed to `Run`, kept so running does not allocate them
+    input_name_ptrs: Vec<*const c_char>,
+    output_name_ptrs: Vec<*const c_char>,
+    input_values: Vec<*const sys::OrtValue>,
+    output_values: Vec<*mut sys::OrtValue>,
+}
+
+#[cfg(feature = "rvm")]
+impl fmt::Debug for RawSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawSession")
+            .field("inputs", &self.input_names)
+            .field("outputs", &self.output_names)
+            .finish()
+    }
+}
+
+#[cfg(feature = "rvm")]
+impl RawSession {
+    /// Load the model from `model_file` configured by `options`, like [`new_session`].
+    pub fn new(model_file: &Path, options: &RuntimeOptions) -> Result<RawSession, FilterError> {
+        // Created first so its log level applies
+        environment(options.log_level)?;
+        let (model_file, optimization) = prepare_model(model_file, options)?;
+        let model_path = path_string(&model_file)?;
+        let api = ort_api()?;
+        unsafe {
+            let env = create_env(api, options)?;
+            let session_options = session_options(api, optimization, options)?;
+            let mut session = Owned {
+                ptr: ptr::null_mut(),
+                release: api.ReleaseSession,
+            };
+            let create_session = api_fn(api.CreateSession, "CreateSession")?;
+            check_status(
+                api,
+                create_session(
+                    env.ptr,
+                    model_path.as_ptr(),
+                    session_options.ptr,
+                    &mut session.ptr,
+                ),
+            )?;
+            let input_names = io_names(
+                api,
+                session.ptr,
+                api_fn(api.SessionGetInputCount, "SessionGetInputCount")?,
+                api_fn(api.SessionGetInputName, "SessionGetInputName")?,
+            )?;
+            let output_names = io_names(
+                api,
+                session.ptr,
+                api_fn(api.SessionGetOutputCount, "SessionGetOutputCount")?,
+                api_fn(api.SessionGetOutputName, "SessionGetOutputName")?,
+            )?;
+            Ok(RawSession {
+                api,
+                session,
+                _env: env,
+                input_name_ptrs: input_names.iter().map(|name| name.as_ptr()).collect(),
+                output_name_ptrs: output_names.iter().map(|name| name.as_ptr()).collect(),
+                input_values: Vec::with_capacity(input_names.len()),
+                output_values: Vec::with_capacity(output_names.len()),
+                input_names,
+                output_names,
+            })
+        }
+    }
+
+    /// Run the model on `inputs`, given in the order of the inputs of the model, and write the
+    /// outputs to `outputs` in the order of its outputs. An output tensor with a buffer of its
+    /// own must have the shape of the output, the others are replaced by the output allocated
+    /// by ONNX Runtime.
+    pub fn run(&mut self, inputs: &[&Tensor], outputs: &mut [Tensor]) -> Result<(), FilterError> {
+        if inputs.len() != self.input_names.len() || outputs.len() != self.output_names.len() {
+            return Err(FilterError::Other(format!(
+                "The model has {} inputs and {} outputs, got {} and {}",
+                self.input_names.len(),
+                self.output_names.len(),
+                inputs.len(),
+                outputs.len()
+            )));
+        }
+        if let Some(input) = inputs.iter().find(|input| input.value.is_null()) {
+            return Err(FilterError::Other(format!(
+                "Input tensor {:?} has no values",
+                input
+            )));
+        }
+        for output in outputs.iter_mut().filter(|output| !output.has_buffer()) {
+            output.release();
+        }
+        self.input_values.clear();
+        self.input_values.extend(
+            inputs
+                .iter()
+                .map(|input| input.value as *const sys::OrtValue),
+        );
+        self.output_values.clear();
+        self.output_values
+            .extend(outputs.iter().map(|output| output.value));
+
+        let api = self.api;
+        unsafe {
+            let run = api_fn(api.Run, "Run")?;
+            let status = run(
+                self.session.ptr,
+                ptr::null(),
+                self.input_name_ptrs.as_ptr(),
+                self.input_values.as_ptr(),
+                self.input_values.len(),
+                self.output_name_ptrs.as_ptr(),
+                self.output_name_ptrs.len(),
+                self.output_values.as_mut_ptr(),
+            );
+            // Outputs allocated by ONNX Runtime are owned by the tensors, even if it failed
+            for (output, value) in outputs.iter_mut().zip(&self.output_values) {
+                output.value = *value;
+            }
+            check_status(api, status)
+        }
+    }
+}
+
+/// The names of the inputs or outputs of `session`, listed by the given functions of the C
+/// API.
+#[cfg(feature = "rvm")]
+unsafe fn io_names(
+    api: &sys::OrtApi,
+    session: *mut sys::OrtSession,
+    count: unsafe extern "C" fn(*const sys::OrtSession, *mut usize) -> sys::OrtStatusPtr,
+    name: unsafe extern "C" fn(
+        *const sys::OrtSession,
+        usize,
+        *mut sys::OrtAllocator,
+        *mut *mut c_char,
+    ) -> sys::OrtStatusPtr,
+) -> Result<Vec<CString>, FilterError> {
+    let mut allocator = ptr::null_mut();
+    let get_allocator = api_fn(
+        api.GetAllocatorWithDefaultOptions,
+        "GetAllocatorWithDefaultOptions",
+    )?;
+    check_status(api, get_allocator(&mut allocator))?;
+    let free = api_fn(api.AllocatorFree, "AllocatorFree")?;
+
+    let mut n = 0;
+    check_status(api, count(session, &mut n))?;
+    let mut names = Vec::with_capacity(n);
+    for i in 0..n {
+        let mut ptr = ptr::null_mut();
+        check_status(api, name(session, i, allocator, &mut ptr))?;
+        names.push(CStr::from_ptr(ptr).to_owned());
+        check_status(api, free(allocator, ptr as *mut c_void))?;
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two sockets with two cores each and two hyperthreads per core, listed like the kernel
+    /// does with the hyperthreads of a core apart from each other.
+    const CPUINFO: &str = "\
+processor\t: 0
+physical id\t: 0
+core id\t\t: 0
+
+processor\t: 1
+physical id\t: 0
+core id\t\t: 1
+
+processor\t: 2
+physical id\t: 1
+core id\t\t: 0
+
+processor\t: 3
+physical id\t: 1
+core id\t\t: 1
+
+processor\t: 4
+physical id\t: 0
+core id\t\t: 0
+
+processor\t: 5
+physical id\t: 0
+core id\t\t: 1
+
+processor\t: 6
+physical id\t: 1
+core id\t\t: 0
+
+processor\t: 7
+physical id\t: 1
+core id\t\t: 1
+";
+
+    #[test]
+    fn cores_of_cpuinfo() {
+        let cases = [
+            (CPUINFO, 4),
+            // Without physical ids, e.g. in some virtual machines
+            (
+                "processor : 0\ncore id : 0\nprocessor : 1\ncore id : 1\n",
+                2,
+            ),
+            // Without core ids, e.g. on ARM
+            ("processor : 0\nprocessor : 1\n", 0),
+            ("", 0),
+        ];
+        for (cpuinfo, cores) in &cases {
+            assert_eq!(count_cores(cpuinfo), *cores, "{:?}", cpuinfo);
+        }
+    }
+
+    #[test]
+    fn parse_provider_lists() {
+        use ExecutionProvider::*;
+        let cases: &[(&str, Result<Vec<ExecutionProvider>, ()>)] = &[
+            ("cpu", Ok(vec![Cpu])),
+            ("cuda,cpu", Ok(vec![Cuda, Cpu])),
+            (
+                " tensorrt , rocm,openvino,coreml ",
+                Ok(vec![TensorRt, Rocm, OpenVino, CoreMl]),
+            ),
+            ("cuda,,cpu,", Ok(vec![Cuda, Cpu])),
+            ("", Ok(vec![])),
+            ("cuda,gpu", Err(())),
+            ("CPU", Err(())),
+        ];
+        for (list, expected) in cases {
+            let parsed = ExecutionProvider::parse_list(list).map_err(|_| ());
+            assert_eq!(&parsed, expected, "{:?}", list);
+            if let Ok(providers) = parsed {
+                let formatted = ExecutionProvider::format_list(&providers);
+                assert_eq!(ExecutionProvider::parse_list(&formatted), Ok(providers));
+            }
+        }
+    }
+
+    #[test]
+    fn parse_names() {
+        for level in &[
+            OptimizationLevel::Disabled,
+            OptimizationLevel::Basic,
+            OptimizationLevel::Extended,
+            OptimizationLevel::All,
+        ] {
+            assert_eq!(level.as_str().parse::<OptimizationLevel>(), Ok(*level));
+        }
+        for level in &[
+            LogLevel::Verbose,
+            LogLevel::Info,
+            LogLevel::Warning,
+            LogLevel::Error,
+            LogLevel::Fatal,
+        ] {
+            assert_eq!(level.to_string().parse::<LogLevel>(), Ok(*level));
+        }
+        assert!("none".parse::<OptimizationLevel>().is_err());
+        assert!("Basic".parse::<OptimizationLevel>().is_err());
+        assert!("debug".parse::<LogLevel>().is_err());
+        assert!("".parse::<LogLevel>().is_err());
+        assert!("gpu".parse::<ExecutionProvider>().is_err());
+    }
+
+    #[test]
+    fn execution_provider_falls_back_to_cpu() {
+        let mut options = RuntimeOptions {
+            execution_providers: vec![ExecutionProvider::Cuda, ExecutionProvider::Cpu],
+            ..RuntimeOptions::default()
+        };
+        assert_eq!(options.execution_provider(), ExecutionProvider::Cpu);
+        options.execution_providers.clear();
+        assert_eq!(options.execution_provider(), ExecutionProvider::Cpu);
+    }
+
+    #[cfg(feature = "rvm")]
+    #[test]
+    fn cached_models_per_optimization_level() {
+        let cache = Path::new("/tmp/cache");
+        let model = Path::new("models/rvm.onnx");
+        let all = cached_model_path(cache, model, OptimizationLevel::All);
+        let basic = cached_model_path(cache, model, OptimizationLevel::Basic);
+        assert_ne!(all, basic);
+        for (path, suffix) in &[(&all, ".all.onnx"), (&basic, ".basic.onnx")] {
+            assert_eq!(path.parent(), Some(cache));
+            let name = path.file_name().unwrap().to_str().unwrap();
+            assert!(name.starts_with("rvm."), "{}", name);
+            assert!(name.ends_with(suffix), "{}", name);
+        }
+        assert_eq!(cached_model_path(cache, model, OptimizationLevel::All), all);
+    }
+
+    #[cfg(feature = "rvm")]
+    #[test]
+    fn cached_models_per_model_file() {
+        let dir = std::env::temp_dir().join("fakecam-runtime-test-models");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::create_dir_all(&a).unwrap();
+        fs::create_dir_all(&b).unwrap();
+        // Written at the same time, so only the path tells them apart
+        fs::write(a.join("rvm.onnx"), b"model").unwrap();
+        fs::write(b.join("rvm.onnx"), b"model").unwrap();
+        let cache = dir.join("cache");
+        let level = OptimizationLevel::All;
+        assert_ne!(
+            cached_model_path(&cache, &a.join("rvm.onnx"), level),
+            cached_model_path(&cache, &b.join("rvm.onnx"), level)
+        );
+        // The same model by another path
+        assert_eq!(
+            cached_model_path(&cache, &a.join("rvm.onnx"), level),
+            cached_model_path(&cache, &a.join("../a/rvm.onnx"), level)
+        );
+
+        // Replacing the model changes its size
+        let cached = cached_model_path(&cache, &a.join("rvm.onnx"), level);
+        fs::write(a.join("rvm.onnx"), b"another model").unwrap();
+        assert_ne!(
+            cached_model_path(&cache, &a.join("rvm.onnx"), level),
+            cached
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "rvm")]
+    #[test]
+    fn missing_cache_is_not_up_to_date() {
+        let dir = std::env::temp_dir();
+        let model = dir.join("fakecam-runtime-test-model.onnx");
+        fs::write(&model, b"model").unwrap();
+        assert!(!is_up_to_date(
+            &dir.join("fakecam-runtime-test-missing.onnx"),
+            &model
+        ));
+        let cached = dir.join("fakecam-runtime-test-model.basic.onnx");
+        fs::write(&cached, b"optimised").unwrap();
+        assert!(is_up_to_date(&cached, &model));
+        fs::remove_file(model).unwrap();
+        fs::remove_file(cached).unwrap();
+    }
+}
@@ -1,106 +1,332 @@
-use crate::filter::{Filter, FilterError};
-use once_cell::sync::Lazy;
-use onnxruntime::environment::Environment;
-use onnxruntime::ndarray::IntoDimension;
-use onnxruntime::ndarray::{self, Dimension, IxDyn};
-use onnxruntime::session::Session;
-use onnxruntime::tensor::OrtOwnedTensor;
-use opencv::core::Vector;
-use opencv::core::{Scalar, Size};
-use opencv::dnn::{blob_from_image, images_from_blob};
+//! This filter runs Robust Video Matting, which estimates both the matte and the foreground
+//! colour and carries a recurrent state from frame to frame.
+//!
+//! The frame is written straight into the input tensor and the outputs are read straight from
+//! the output tensors, without intermediate blobs. The model runs on tensors kept between
+//! frames, see [`RawSession`]: the inputs are rewritten in place, the outputs are written to by
+//! the model, and the recurrent state is swapped between the inputs and the outputs instead of
+//! being copied. Only the first frame of a size allocates them, as the shapes of the outputs are
+//! only known once the model ran. The matte handed on is reused as well, once the caller
+//! dropped it. The allocations of each stage are counted from the buffers actually replaced
+//! and recorded with the timings, see [`StageTimings::allocations`].
+
+use crate::filter::{FilterError, Matte, MatteEstimator, Quality, StageTimings};
+use crate::filtertools::SceneCutDetector;
+use crate::runtime::{RawSession, RuntimeOptions, Tensor};
+use core::ffi::c_void;
+use onnxruntime::ndarray;
+use opencv::core::{Size, Vector, CV_32FC1, CV_32FC3, CV_8UC3};
+use opencv::imgproc::{resize, INTER_AREA, INTER_LINEAR};
 use opencv::prelude::*;
 use std::mem;
 use std::path::Path;
+use std::time::Instant;
 
 #[derive(Debug)]
-pub struct RVMFilter<'a> {
-    session: Session<'a>,
-    downsample_ratio: ndarray::ArrayD<f32>,
-    r1o: ndarray::ArrayD<f32>,
-    r2o: ndarray::ArrayD<f32>,
-    r3o: ndarray::ArrayD<f32>,
-    r4o: ndarray::ArrayD<f32>,
+pub struct RVMFilter {
+    session: RawSession,
+    tensors: Tensors,
+    /// Size of the frames given to the model relative to the frames passed in
+    inference_scale: f64,
+    /// Size of the frames the recurrent state was computed from
+    state_size: Size,
+    scene_cuts: SceneCutDetector,
+    timings: StageTimings,
 }
 
-// This is ugly but we have to do it because Session does not implement Send
-unsafe impl<'a> Send for RVMFilter<'a> {}
-
-static ORT_ENV: Lazy<Environment> = Lazy::new(|| {
-    Environment::builder()
-        .with_log_level(onnxruntime::LoggingLevel::Verbose)
-        .with_name("rvmruntime")
-        .build()
-        .expect("Failed to build ORT environment")
-});
-
-const DOWNSAMPLE_RATIO: f32 = 0.25;
-
-impl<'a> RVMFilter<'a> {
-    pub fn new<P: AsRef<Path> + 'a>(model_file: P) -> Result<RVMFilter<'a>, FilterError> {
-        let session = (&*ORT_ENV)
-            .new_session_builder()?
-            .with_optimization_level(onnxruntime::GraphOptimizationLevel::Basic)?
-            .with_number_threads(4)?
-            .with_model_from_file(model_file)?;
-        // Initialize recurrent values with 1x1x1x1 all-zero array
-        let recurrent_init = ndarray::ArrayD::<f32>::zeros(IxDyn(&[1, 1, 1, 1]));
+// This is ugly but we have to do it because the session and the tensors are raw pointers to
+// ONNX Runtime objects, which are not Send
+unsafe impl Send for RVMFilter {}
+
+impl RVMFilter {
+    /// Load the RVM model from `model_file`. The `downsample_ratio` determines the resolution
+    /// the model works at internally relative to the frame size, lower is faster but less
+    /// accurate. `runtime` configures ONNX Runtime.
+    pub fn new<P: AsRef<Path>>(
+        model_file: P,
+        downsample_ratio: f32,
+        runtime: &RuntimeOptions,
+    ) -> Result<RVMFilter, FilterError> {
+        let session = RawSession::new(model_file.as_ref(), runtime)?;
+        let tensors = Tensors {
+            downsample_ratio,
+            ..Tensors::default()
+        };
 
         Ok(RVMFilter {
             session,
-            downsample_ratio: ndarray::ArrayD::from_elem(IxDyn(&[1]), DOWNSAMPLE_RATIO),
-            r1o: recurrent_init.clone(),
-            r2o: recurrent_init.clone(),
-            r3o: recurrent_init.clone(),
-            r4o: recurrent_init,
+            tensors,
+            inference_scale: 1.0,
+            state_size: Size::default(),
+            scene_cuts: SceneCutDetector::default(),
+            timings: StageTimings::default(),
+        })
+    }
+}
+
+/// The tensors passed to the model and the images they are scaled with, kept between frames.
+#[derive(Debug, Default)]
+struct Tensors {
+    /// The frame as a single NCHW image
+    input: Tensor,
+    downsample_ratio: f32,
+    /// `downsample_ratio` as the tensor passed to the model
+    ratio: Tensor,
+    /// The recurrent state r1 to r4, which RVM uses to carry information from frame to frame
+    recurrent: [Tensor; 4],
+    /// The foreground, the matte and the recurrent state for the next frame as output by the
+    /// model
+    outputs: [Tensor; 6],
+    /// The frame scaled to the inference size
+    resized: Mat,
+    /// The foreground at the inference size
+    small_fgr: Mat,
+    /// The foreground and the matte at the frame size, handed on in the [`Matte`]
+    fgr: Mat,
+    pha: Mat,
+}
+
+impl Tensors {
+    /// Reset the recurrent state, so the next frame is estimated as if it were the first one.
+    fn reset_state(&mut self) {
+        // The model treats an all-zero state as having no previous frame
+        for state in self.recurrent.iter_mut().filter_map(Tensor::buffer_mut) {
+            state.fill(0.0);
+        }
+    }
+
+    /// Write the inputs of the model for `src_image` scaled to `input_size` to the input
+    /// tensors. The allocations are recorded as the "input" stage in `timings`.
+    fn write_inputs(
+        &mut self,
+        src_image: &Mat,
+        input_size: Size,
+        timings: &mut StageTimings,
+    ) -> Result<(), FilterError> {
+        let mut allocations = 0;
+        // Scaled by the quality controller
+        let input_image = if input_size != src_image.size()? {
+            allocations += reallocates(&self.resized, input_size, CV_8UC3)?;
+            resize(src_image, &mut self.resized, input_size, 0.0, 0.0, INTER_AREA)?;
+            &self.resized
+        } else {
+            src_image
+        };
+        let shape = [1, 3, input_size.height as usize, input_size.width as usize];
+        let shape_changed = !has_shape(&self.input, &shape);
+        if shape_changed {
+            self.input = Tensor::zeros(&shape)?;
+            allocations += 1;
+        }
+        let ratio = self
+            .ratio
+            .buffer()
+            .and_then(|ratio| ratio.iter().next().copied());
+        if ratio.is_none() {
+            self.ratio = Tensor::zeros(&[1])?;
+            allocations += 1;
+        }
+        if shape_changed || ratio != Some(self.downsample_ratio) {
+            // The shapes of the recurrent state and the outputs follow from the input size and
+            // the downsample ratio, so they are only known again once the model ran. Until
+            // then, a 1x1x1x1 all-zero state stands for having no previous frame.
+            for state in self.recurrent.iter_mut() {
+                *state = Tensor::zeros(&[1, 1, 1, 1])?;
+                allocations += 1;
+            }
+            self.outputs = Default::default();
+        }
+        write_input(
+            input_image,
+            buffer(&mut self.input)?
+                .as_slice_mut()
+                .expect("Tensors are created contiguous"),
+        )?;
+        buffer(&mut self.ratio)?.fill(self.downsample_ratio);
+        timings.record_allocations("input", allocations);
+        Ok(())
+    }
+
+    /// Run the model on the input tensors, writing its outputs to the output tensors.
+    fn run(&mut self, session: &mut RawSession) -> Result<(), FilterError> {
+        let [r1, r2, r3, r4] = &self.recurrent;
+        session.run(
+            &[&self.input, r1, r2, r3, r4, &self.ratio],
+            &mut self.outputs,
+        )
+    }
+
+    /// The matte for a frame of `src_size` from the outputs of the model, keeping the
+    /// recurrent state for the next frame. The allocations are recorded as the "unpack" stage
+    /// in `timings`.
+    fn unpack(&mut self, src_size: Size, timings: &mut StageTimings) -> Result<Matte, FilterError> {
+        let mut allocations = 0;
+        {
+            let fgr = self.outputs[0].view()?;
+            let pha = self.outputs[1].view()?;
+            // The planes are dropped before the outputs
+            let fgr_planes = unsafe { output_planes(&fgr)? };
+            let pha_planes = unsafe { output_planes(&pha)? };
+            if fgr_planes.len() != 3 || pha_planes.len() != 1 {
+                return Err(FilterError::Other(format!(
+                    "Expected a foreground with 3 channels and a matte with 1, got {} and {}",
+                    fgr_planes.len(),
+                    pha_planes.len()
+                )));
+            }
+            let small_pha = pha_planes.get(0)?;
+            // The matte is handed on, so its buffers are only reused once the caller dropped
+            // the matte of the previous frame
+            for mat in [&mut self.fgr, &mut self.pha] {
+                if is_shared(mat) {
+                    *mat = Mat::default();
+                }
+            }
+            allocations += reallocates(&self.fgr, src_size, CV_32FC3)?
+                + reallocates(&self.pha, src_size, CV_32FC1)?;
+            if small_pha.size()? != src_size {
+                allocations += reallocates(&self.small_fgr, small_pha.size()?, CV_32FC3)?;
+                opencv::core::merge(&fgr_planes, &mut self.small_fgr)?;
+                resize(&self.small_fgr, &mut self.fgr, src_size, 0.0, 0.0, INTER_LINEAR)?;
+                resize(&small_pha, &mut self.pha, src_size, 0.0, 0.0, INTER_LINEAR)?;
+            } else {
+                opencv::core::merge(&fgr_planes, &mut self.fgr)?;
+                small_pha.copy_to(&mut self.pha)?;
+            }
+        }
+
+        // Outputs allocated by ONNX Runtime, i.e. for the first frame of a size, are replaced
+        // by tensors of the same shape the model writes to from the next frame on
+        let (matte, states) = self.outputs.split_at_mut(2);
+        for output in matte.iter_mut().filter(|output| !output.has_buffer()) {
+            let shape = output.view()?.shape().to_vec();
+            *output = Tensor::zeros(&shape)?;
+            allocations += 1;
+        }
+        for (state, output) in self.recurrent.iter_mut().zip(states.iter_mut()) {
+            if !output.has_buffer() {
+                let view = output.view()?;
+                let mut copy = Tensor::zeros(view.shape())?;
+                buffer(&mut copy)?.assign(&view);
+                drop(view);
+                *output = copy;
+                allocations += 1;
+            }
+            // The new state becomes the input for the next frame, and the previous one is
+            // written to next
+            mem::swap(state, output);
+            let shape = buffer(state)?.shape().to_vec();
+            if !has_shape(output, &shape) {
+                *output = Tensor::zeros(&shape)?;
+                allocations += 1;
+            }
+        }
+        timings.record_allocations("unpack", allocations);
+        Ok(Matte {
+            fgr: Some(Mat::copy(&self.fgr)?),
+            pha: Mat::copy(&self.pha)?,
         })
     }
 }
 
-fn tensor2mat<D>(tensor: &OrtOwnedTensor<f32, D>) -> Result<opencv::core::Mat, FilterError>
-where
-    D: Dimension,
-{
-    let tensor_dims: Vec<i32> = (*tensor)
-        .dim()
-        .into_dimension()
-        .as_array_view()
-        .iter()
-        .map(|dim| *dim as i32)
-        .collect();
-    let mat = opencv::core::Mat::from_slice((&*tensor).as_slice().ok_or_else(|| {
-        FilterError::Other(String::from(
-            "Failed to get output tensor array view as slice",
-        ))
-    })?)?;
-    Ok(mat.reshape_nd(1, tensor_dims.as_slice())?)
+/// Whether writing an image of `size` and type `typ` to `mat` allocates a new buffer, as 0 or 1
+/// so it can be added to an allocation count.
+fn reallocates(mat: &Mat, size: Size, typ: i32) -> Result<usize, FilterError> {
+    Ok((mat.size()? != size || mat.typ() != typ) as usize)
+}
+
+/// Whether `tensor` has a buffer of `shape`.
+fn has_shape(tensor: &Tensor, shape: &[usize]) -> bool {
+    tensor
+        .buffer()
+        .map_or(false, |buffer| buffer.shape() == shape)
 }
 
-fn mix_result(bg: &Mat, pha: &Mat, fgr: &Mat, dst: &mut Mat) -> Result<(), FilterError> {
-    let mut pha_bc = Mat::new_size_with_default(
-        pha.size()?,
-        opencv::core::CV_32FC3,
-        Scalar::from((0.0, 0.0, 0.0)),
-    )?;
-    opencv::core::mix_channels(pha, &mut pha_bc, &[0, 0, 0, 1, 0, 2])?;
-    opencv::imgcodecs::imwrite("/tmp/dump.tiff", &pha_bc, &Vector::new())?;
-    let mut bg_f32 = Mat::new_size_with_default(
-        bg.size()?,
-        opencv::core::CV_32FC3,
-        Scalar::from((0.0, 0.0, 0.0)),
-    )?;
-    bg.convert_to(&mut bg_f32, opencv::core::CV_32FC3, 1.0 / 255.0, 0.0)?;
-    let pha_bc_inv = (opencv::core::Scalar::from((1.0, 1.0, 1.0)) - &pha_bc).into_result()?;
-
-    let mixed = (fgr.mul(&pha_bc, 1.0)? + bg_f32.mul(&pha_bc_inv, 1.0)?)
-        .into_result()?
-        .to_mat()?;
-    mixed.convert_to(dst, opencv::core::CV_8UC3, 255.0, 0.0)?;
+/// The buffer of `tensor`, which was created with one.
+fn buffer(tensor: &mut Tensor) -> Result<&mut ndarray::ArrayD<f32>, FilterError> {
+    tensor
+        .buffer_mut()
+        .ok_or_else(|| FilterError::Other(String::from("The tensor has no buffer")))
+}
+
+/// Whether the data of `mat` is referenced by other Mats as well, e.g. by the matte of the
+/// previous frame which the caller still holds.
+fn is_shared(mat: &mut Mat) -> bool {
+    if mat.empty() {
+        return false;
+    }
+    let data = mat.u();
+    let shared = !data.as_raw_UMatData().is_null() && data.refcount() > 1;
+    // The wrapper does not own the reference count of the Mat and must not delete it
+    mem::forget(data);
+    shared
+}
+
+/// Write the CV_8UC3 image `src` to `tensor` as a single NCHW image with values in 0..1.
+///
+/// The channels are not swapped: the filters are given RGB frames, see
+/// [`crate::videoformat::FormatConverter`], and RVM expects RGB input. Swapping red and blue,
+/// as `blob_from_image` with `swap_rb` set did before, feeds the model BGR.
+fn write_input(src: &Mat, tensor: &mut [f32]) -> Result<(), FilterError> {
+    let (rows, cols) = (src.rows() as usize, src.cols() as usize);
+    let plane = rows * cols;
+    if src.typ() != CV_8UC3 || tensor.len() != 3 * plane {
+        return Err(FilterError::Other(format!(
+            "Cannot write an image of {}x{} with type {} to a tensor of {} values",
+            cols,
+            rows,
+            src.typ(),
+            tensor.len()
+        )));
+    }
+    let (red, rest) = tensor.split_at_mut(plane);
+    let (green, blue) = rest.split_at_mut(plane);
+    for row in 0..rows {
+        // Rows may be padded, so the image is read row by row
+        let pixels = unsafe { std::slice::from_raw_parts(src.ptr(row as i32)?, cols * 3) };
+        let offset = row * cols;
+        for (i, pixel) in pixels.chunks_exact(3).enumerate() {
+            red[offset + i] = pixel[0] as f32 / 255.0;
+            green[offset + i] = pixel[1] as f32 / 255.0;
+            blue[offset + i] = pixel[2] as f32 / 255.0;
+        }
+    }
     Ok(())
 }
 
-impl<'a> Filter for RVMFilter<'a> {
-    fn filter_inplace(&mut self, src_image: &mut Mat, bg_image: &Mat) -> Result<(), FilterError> {
-        // Ensure that we have two HWC images with three channels
+/// Wrap the channels of the single NCHW image in `tensor` as CV_32FC1 Mats, without copying.
+///
+/// # Safety
+/// The Mats point into the memory of the tensor and must not be used after it has been dropped.
+unsafe fn output_planes(tensor: &ndarray::ArrayViewD<f32>) -> Result<Vector<Mat>, FilterError> {
+    let (channels, height, width) = match tensor.shape() {
+        [1, c, h, w] => (*c, *h, *w),
+        shape => {
+            return Err(FilterError::Other(format!(
+                "Expected an output tensor of shape [1, C, H, W], got {:?}",
+                shape
+            )))
+        }
+    };
+    let data = tensor
+        .as_slice()
+        .ok_or_else(|| FilterError::Other(String::from("Output tensor is not contiguous")))?;
+    let mut planes = Vector::new();
+    for plane in data.chunks_exact(width * height).take(channels) {
+        planes.push(Mat::new_rows_cols_with_data(
+            height as i32,
+            width as i32,
+            CV_32FC1,
+            plane.as_ptr() as *mut c_void,
+            opencv::core::Mat_AUTO_STEP,
+        )?);
+    }
+    Ok(planes)
+}
+
+impl MatteEstimator for RVMFilter {
+    fn estimate(&mut self, src_image: &Mat) -> Result<Matte, FilterError> {
+        // Ensure that we have a HWC image with three channels
         if src_image.dims() != 2 || src_image.channels() != 3 {
             return Err(FilterError::Other(format!(
                 "Expected a WHC source image (where C=3), got {:?} with {} channels",
@@ -108,90 +334,192 @@ impl<'a> Filter for RVMFilter<'a> {
                 src_image.channels()
             )));
         }
-        if bg_image.dims() != 2 || bg_image.channels() != 3 {
-            return Err(FilterError::Other(format!(
-                "Expected a WHC background image (where C=3), got {:?} with {} channels",
-                bg_image.mat_size(),
-                bg_image.channels()
-            )));
-        }
-        // Ensure that the images have the same dimensions
-        if *(src_image.mat_size()) != *(bg_image.mat_size()) {
-            return Err(FilterError::Other(format!(
-                "Camera image has size {:?} but background image has size {:?}.",
-                src_image.mat_size(),
-                bg_image.mat_size()
-            )));
+
+        self.timings.clear();
+        let start = Instant::now();
+        let src_size = Size::new(src_image.cols(), src_image.rows());
+        // The state of another scene or resolution would only mislead the model
+        let scene_cut = self.scene_cuts.is_cut(src_image)?;
+        if scene_cut || src_size != self.state_size {
+            self.tensors.reset_state();
+            self.state_size = src_size;
         }
+        self.timings.record("scene-cut", start);
 
-        let blob = blob_from_image(
-            src_image,
-            1.0/255.0,                                     // scale 0..255 -> 0..1
-            Size::new(src_image.cols(), src_image.rows()), // Use original image size
-            Scalar::new(0.0, 0.0, 0.0, 0.0),               // Add nothing
-            true,                                          // Swap R and B
-            false,                                         // Don't crop
-            opencv::core::CV_32F,                          // Use f32 values
-        )?;
+        let start = Instant::now();
+        let input_size = Size::new(
+            ((src_size.width as f64 * self.inference_scale).round() as i32).max(1),
+            ((src_size.height as f64 * self.inference_scale).round() as i32).max(1),
+        );
+        self.tensors
+            .write_inputs(src_image, input_size, &mut self.timings)?;
+        self.timings.record("input", start);
 
-        let bs = blob.mat_size();
-        let src_shape = ndarray::IxDyn(&[
-            bs.get(0)? as usize,
-            bs.get(1)? as usize,
-            bs.get(2)? as usize,
-            bs.get(3)? as usize,
-        ]);
-
-        let inputs: Vec<ndarray::ArrayD<f32>> = vec![
-            unsafe {
-                ndarray::ArrayViewD::<f32>::from_shape_ptr(src_shape, blob.data() as *const f32)
-                    .to_owned()
-            },
-            // We use take to replace the recurrent values with a default value and then moves
-            // them out (to the session) in order to save us some copies.
-            mem::take(&mut self.r1o),
-            mem::take(&mut self.r2o),
-            mem::take(&mut self.r3o),
-            mem::take(&mut self.r4o),
-            self.downsample_ratio.clone(),
-        ];
+        let start = Instant::now();
+        self.tensors.run(&mut self.session)?;
+        self.timings.record("inference", start);
 
-        let outputs = self.session.run::<f32, f32, ndarray::IxDyn>(inputs)?;
-
-        match outputs.as_slice() {
-            [fgr, pha, r1o, r2o, r3o, r4o] => {
-                // Set recurrent states to the ones we obtained
-                let mut images: Vector<Mat> = Vector::new();
-                images_from_blob(&tensor2mat(fgr)?, &mut images)?;
-                if images.len() != 1 {
-                    return Err(FilterError::Other(format!(
-                        "Output blob had {} images, should have 1",
-                        images.len()
-                    )));
-                }
-                let fgr_mat = images.get(0)?;
-
-                images_from_blob(&tensor2mat(pha)?, &mut images)?;
-                if images.len() != 1 {
-                    return Err(FilterError::Other(format!(
-                        "Output blob had {} images, should have 1",
-                        images.len()
-                    )));
-                }
-                let pha_mat = images.get(0)?;
-                mix_result(&bg_image, &pha_mat, &fgr_mat, src_image)?;
-                opencv::highgui::imshow("preview", src_image)?;
-                opencv::highgui::wait_key(15)?;
-                self.r1o = (*r1o).to_owned();
-                self.r2o = (*r2o).to_owned();
-                self.r3o = (*r3o).to_owned();
-                self.r4o = (*r4o).to_owned();
-                Ok(())
+        let start = Instant::now();
+        let matte = self.tensors.unpack(src_size, &mut self.timings)?;
+        self.timings.record("unpack", start);
+        Ok(matte)
+    }
+
+    fn timings(&self) -> Option<&StageTimings> {
+        Some(&self.timings)
+    }
+
+    fn set_quality(&mut self, quality: &Quality) {
+        self.tensors.downsample_ratio = quality.downsample_ratio as f32;
+        // The recurrent state has the size of the internal resolution, which just changed, so
+        // it starts over with the next frame
+        self.inference_scale = quality.inference_scale;
+    }
+
+    fn reset(&mut self) {
+        self.tensors.reset_state();
+        self.scene_cuts.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::Scalar;
+
+    /// Stand in for the model with an input of `size`: write the foreground, the matte and the
+    /// recurrent state to the outputs of `tensors`, replacing outputs without a buffer of the
+    /// right shape as ONNX Runtime does.
+    fn run_model(tensors: &mut Tensors, size: Size) {
+        let (height, width) = (size.height as usize, size.width as usize);
+        let outputs = [
+            (3, 0.5),
+            (1, 0.25),
+            (16, 0.0),
+            (20, 0.0),
+            (40, 0.0),
+            (64, 0.0),
+        ];
+        for (output, (channels, value)) in tensors.outputs.iter_mut().zip(&outputs) {
+            let shape = [1, *channels, height, width];
+            if !has_shape(output, &shape) {
+                *output = Tensor::zeros(&shape).unwrap();
             }
-            other => Err(FilterError::Other(format!(
-                "Expected six output tensors, got {}",
-                other.len()
-            ))),
+            output.buffer_mut().unwrap().fill(*value);
         }
     }
+
+    /// Pass a frame through `tensors` and the stand-in model.
+    fn run_frame(
+        tensors: &mut Tensors,
+        src: &Mat,
+        input_size: Size,
+        timings: &mut StageTimings,
+    ) -> Matte {
+        timings.clear();
+        tensors.write_inputs(src, input_size, timings).unwrap();
+        run_model(tensors, input_size);
+        tensors.unpack(src.size().unwrap(), timings).unwrap()
+    }
+
+    #[test]
+    fn steady_state_allocations() {
+        let src = Mat::new_rows_cols_with_default(8, 8, CV_8UC3, Scalar::all(255.0)).unwrap();
+        // Inference at half the frame size, so the frame is scaled down and the outputs up
+        let input_size = Size::new(4, 4);
+        let mut tensors = Tensors::default();
+        let mut timings = StageTimings::default();
+
+        run_frame(&mut tensors, &src, input_size, &mut timings);
+        // The scaled frame, the input tensor, the downsample ratio and the initial state
+        assert_eq!(timings.allocations("input"), 7);
+        // The scaled foreground, the matte and the state the next state is written to
+        assert_eq!(timings.allocations("unpack"), 7);
+        for _ in 0..2 {
+            let matte = run_frame(&mut tensors, &src, input_size, &mut timings);
+            assert_eq!(timings.allocations("input"), 0);
+            assert_eq!(timings.allocations("unpack"), 0);
+            assert_eq!(matte.pha.size().unwrap(), src.size().unwrap());
+            assert_eq!(matte.fgr.unwrap().typ(), CV_32FC3);
+        }
+    }
+
+    #[test]
+    fn held_matte_is_not_overwritten() {
+        let src = Mat::new_rows_cols_with_default(4, 4, CV_8UC3, Scalar::all(255.0)).unwrap();
+        let size = src.size().unwrap();
+        let mut tensors = Tensors::default();
+        let mut timings = StageTimings::default();
+        let held = run_frame(&mut tensors, &src, size, &mut timings);
+
+        // The next frame has another matte, which must not end up in the held one
+        tensors.write_inputs(&src, size, &mut timings).unwrap();
+        run_model(&mut tensors, size);
+        tensors.outputs[1].buffer_mut().unwrap().fill(0.75);
+        timings.clear();
+        let matte = tensors.unpack(size, &mut timings).unwrap();
+        assert_eq!(timings.allocations("unpack"), 2);
+        assert_eq!(*held.pha.at_2d::<f32>(0, 0).unwrap(), 0.25);
+        assert_eq!(*matte.pha.at_2d::<f32>(0, 0).unwrap(), 0.75);
+    }
+
+    #[test]
+    fn state_is_swapped_with_the_outputs() {
+        let src = Mat::new_rows_cols_with_default(4, 4, CV_8UC3, Scalar::all(255.0)).unwrap();
+        let size = src.size().unwrap();
+        let mut tensors = Tensors::default();
+        let mut timings = StageTimings::default();
+        run_frame(&mut tensors, &src, size, &mut timings);
+
+        tensors.write_inputs(&src, size, &mut timings).unwrap();
+        run_model(&mut tensors, size);
+        tensors.outputs[2].buffer_mut().unwrap().fill(1.0);
+        tensors.unpack(size, &mut timings).unwrap();
+        let state = tensors.recurrent[0].buffer().unwrap();
+        assert_eq!(state.shape(), &[1, 16, 4, 4]);
+        assert!(state.iter().all(|&value| value == 1.0));
+
+        tensors.reset_state();
+        assert!(tensors.recurrent[0]
+            .buffer()
+            .unwrap()
+            .iter()
+            .all(|&value| value == 0.0));
+    }
+
+    #[test]
+    fn input_is_planar_rgb() {
+        let mut src = Mat::new_rows_cols_with_default(1, 2, CV_8UC3, Scalar::all(0.0)).unwrap();
+        src.data_bytes_mut()
+            .unwrap()
+            .copy_from_slice(&[10, 20, 30, 40, 50, 60]);
+        let mut tensors = Tensors {
+            downsample_ratio: 0.375,
+            ..Tensors::default()
+        };
+        let mut timings = StageTimings::default();
+        tensors
+            .write_inputs(&src, Size::new(2, 1), &mut timings)
+            .unwrap();
+        let input = tensors.input.buffer().unwrap();
+        assert_eq!(input.shape(), &[1, 3, 1, 2]);
+        let expected: Vec<f32> = [10u8, 40, 20, 50, 30, 60]
+            .iter()
+            .map(|&v| v as f32 / 255.0)
+            .collect();
+        assert_eq!(input.as_slice().unwrap(), expected.as_slice());
+        assert_eq!(
+            tensors.ratio.buffer().unwrap().as_slice().unwrap(),
+            &[0.375]
+        );
+        // Not scaled, so only the input tensor, the downsample ratio and the initial state
+        assert_eq!(timings.allocations("input"), 6);
+    }
+
+    #[test]
+    fn missing_outputs_are_an_error() {
+        let mut tensors = Tensors::default();
+        let result = tensors.unpack(Size::new(2, 2), &mut StageTimings::default());
+        assert!(matches!(result, Err(FilterError::Other(_))));
+    }
 }
@@ -0,0 +1,285 @@
+//! This filter runs a [`MatteEstimator`] only on some frames. For the frames in between, the
+//! last matte is warped along the dense optical flow between the frames, which is computed on
+//! a small grayscale copy and costs a fraction of running the model.
+//!
+//! How often the model runs adapts to the load: the model may take half of the time between two
+//! frames on average, so the slower the model is compared to the camera, the more frames are
+//! warped. Warping errors add up with motion, so after a lot of movement the model runs again
+//! early.
+
+use crate::filter::{FilterError, Matte, MatteEstimator, Quality, StageTimings};
+use opencv::core::{Scalar, Size, Vec2f, BORDER_REPLICATE, CV_32FC2};
+use opencv::imgproc::{cvt_color, remap, resize, COLOR_RGB2GRAY, INTER_AREA, INTER_LINEAR};
+use opencv::prelude::*;
+use opencv::video::calc_optical_flow_farneback;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Width of the frames the optical flow is computed on.
+const FLOW_WIDTH: i32 = 160;
+/// Average motion in pixels of the flow frames after which the model runs again.
+const MAX_MOTION: f64 = 8.0;
+/// Share of the time between two frames the model may use on average.
+const LOAD_SHARE: f64 = 0.5;
+/// Weight of a new measurement in the moving averages of the model and frame times.
+const AVERAGE_WEIGHT: f64 = 0.2;
+
+fn update_average(average: &mut Option<f64>, value: f64) -> f64 {
+    let updated = match *average {
+        Some(average) => average + AVERAGE_WEIGHT * (value - average),
+        None => value,
+    };
+    *average = Some(updated);
+    updated
+}
+
+#[derive(Debug)]
+pub struct TemporalFilter {
+    estimator: Box<dyn MatteEstimator>,
+    /// The model runs at least every `max_interval` frames
+    max_interval: u32,
+    /// The model runs every `interval` frames, unless there is a lot of motion
+    interval: u32,
+    /// Frames warped since the model last ran
+    since_estimate: u32,
+    /// Motion since the model last ran
+    motion: f64,
+    /// Average time the model takes in seconds
+    estimate_time: Option<f64>,
+    /// Average time between two frames in seconds
+    frame_period: Option<f64>,
+    /// When the last frame arrived
+    last_frame: Option<Instant>,
+    /// The matte of the last frame
+    last_pha: Option<Mat>,
+    /// Small grayscale copies of the last and the current frame
+    prev_gray: Mat,
+    gray: Mat,
+    flow: Mat,
+    /// The flow scaled to the frame size
+    flow_full: Mat,
+    /// The coordinates of each pixel, the identity map for `remap`
+    grid: Mat,
+    map: Mat,
+    timings: StageTimings,
+}
+
+impl TemporalFilter {
+    /// Run `estimator` at least every `max_interval` frames and warp the matte in between.
+    pub fn new(estimator: Box<dyn MatteEstimator>, max_interval: u32) -> TemporalFilter {
+        let max_interval = max_interval.max(1);
+        TemporalFilter {
+            estimator,
+            max_interval,
+            // Lowered once the load of the model is known
+            interval: max_interval,
+            since_estimate: 0,
+            motion: 0.0,
+            estimate_time: None,
+            frame_period: None,
+            last_frame: None,
+            last_pha: None,
+            prev_gray: Mat::default(),
+            gray: Mat::default(),
+            flow: Mat::default(),
+            flow_full: Mat::default(),
+            grid: Mat::default(),
+            map: Mat::default(),
+            timings: StageTimings::default(),
+        }
+    }
+
+    fn needs_estimate(&self, size: Size) -> Result<bool, FilterError> {
+        let pha_size = match &self.last_pha {
+            Some(pha) => pha.size()?,
+            None => return Ok(true),
+        };
+        Ok(pha_size != size
+            || self.since_estimate + 1 >= self.interval
+            || self.motion > MAX_MOTION)
+    }
+
+    /// Update the interval after the model took `elapsed`.
+    fn update_interval(&mut self, elapsed: Duration) {
+        let estimate_time = update_average(&mut self.estimate_time, elapsed.as_secs_f64());
+        if let Some(frame_period) = self.frame_period {
+            let frames = (estimate_time / (LOAD_SHARE * frame_period)).ceil();
+            self.interval = (frames as u32).clamp(1, self.max_interval);
+        }
+    }
+
+    /// Scale the frame down to `self.gray`.
+    fn downscale(&mut self, src_image: &Mat) -> Result<(), FilterError> {
+        let height = (src_image.rows() * FLOW_WIDTH / src_image.cols().max(1)).max(1);
+        let mut small = Mat::default();
+        resize(src_image, &mut small, Size::new(FLOW_WIDTH, height), 0.0, 0.0, INTER_AREA)?;
+        cvt_color(&small, &mut self.gray, COLOR_RGB2GRAY, 0)?;
+        Ok(())
+    }
+
+    /// Compute the flow from the current to the last frame and add up the motion.
+    fn compute_flow(&mut self) -> Result<(), FilterError> {
+        calc_optical_flow_farneback(
+            &self.gray,
+            &self.prev_gray,
+            &mut self.flow,
+            0.5, // Each pyramid level is half the size of the previous one
+            3,   // Pyramid levels
+            15,  // Window size
+            3,   // Iterations per level
+            5,   // Neighbourhood of the polynomial expansion
+            1.2, // Standard deviation of the polynomial expansion
+            0,
+        )?;
+        let mut magnitude = Mat::default();
+        opencv::core::absdiff(&self.flow, &Scalar::all(0.0), &mut magnitude)?;
+        let mean = opencv::core::mean(&magnitude, &opencv::core::no_array())?;
+        self.motion += mean[0] + mean[1];
+        Ok(())
+    }
+
+    /// Warp the last matte along the flow to match a frame of `size`.
+    fn warp(&mut self, size: Size) -> Result<Mat, FilterError> {
+        if self.grid.size()? != size {
+            self.grid = Mat::new_size_with_default(size, CV_32FC2, Scalar::all(0.0))?;
+            for y in 0..size.height {
+                for x in 0..size.width {
+                    *self.grid.at_2d_mut::<Vec2f>(y, x)? = Vec2f::from([x as f32, y as f32]);
+                }
+            }
+        }
+        let flow_size = self.flow.size()?;
+        let mut scaled = Mat::default();
+        resize(&self.flow, &mut scaled, size, 0.0, 0.0, INTER_LINEAR)?;
+        // The flow is measured in pixels of the small frames
+        let scale = Scalar::new(
+            size.width as f64 / flow_size.width as f64,
+            size.height as f64 / flow_size.height as f64,
+            0.0,
+            0.0,
+        );
+        opencv::core::multiply(&scaled, &scale, &mut self.flow_full, 1.0, -1)?;
+        opencv::core::add(
+            &self.grid,
+            &self.flow_full,
+            &mut self.map,
+            &opencv::core::no_array(),
+            -1,
+        )?;
+
+        let last_pha = self
+            .last_pha
+            .as_ref()
+            .ok_or_else(|| FilterError::Other(String::from("No matte to warp")))?;
+        // A new Mat, the last matte may still be shared with the previous frame
+        let mut warped = Mat::default();
+        remap(
+            last_pha,
+            &mut warped,
+            &self.map,
+            &opencv::core::no_array(),
+            INTER_LINEAR,
+            BORDER_REPLICATE,
+            Scalar::default(),
+        )?;
+        Ok(warped)
+    }
+}
+
+impl MatteEstimator for TemporalFilter {
+    fn estimate(&mut self, src_image: &Mat) -> Result<Matte, FilterError> {
+        let arrival = Instant::now();
+        if let Some(last_arrival) = self.last_frame {
+            let period = arrival.duration_since(last_arrival).as_secs_f64();
+            update_average(&mut self.frame_period, period);
+        }
+
+        self.timings.clear();
+        let start = Instant::now();
+        self.downscale(src_image)?;
+        self.timings.record("downscale", start);
+
+        let size = src_image.size()?;
+        let warped = !self.needs_estimate(size)?;
+        let matte = if warped {
+            let start = Instant::now();
+            self.compute_flow()?;
+            self.timings.record("flow", start);
+            let start = Instant::now();
+            let pha = self.warp(size)?;
+            self.timings.record("warp", start);
+            self.since_estimate += 1;
+            Matte { fgr: None, pha }
+        } else {
+            let start = Instant::now();
+            let matte = self.estimator.estimate(src_image)?;
+            self.update_interval(start.elapsed());
+            if let Some(timings) = self.estimator.timings() {
+                self.timings.extend(timings);
+            }
+            self.since_estimate = 0;
+            self.motion = 0.0;
+            matte
+        };
+
+        self.last_pha = Some(Mat::copy(&matte.pha)?);
+        mem::swap(&mut self.prev_gray, &mut self.gray);
+        self.last_frame = Some(arrival);
+        Ok(matte)
+    }
+
+    fn timings(&self) -> Option<&StageTimings> {
+        Some(&self.timings)
+    }
+
+    fn set_quality(&mut self, quality: &Quality) {
+        self.max_interval = quality.inference_interval.max(1);
+        // Lowered again by the next estimate if the model is fast enough
+        self.interval = self.max_interval;
+        self.estimator.set_quality(quality);
+    }
+
+    /// Forget the last matte, so the model runs on the next frame. The measured load is kept.
+    fn reset(&mut self) {
+        self.estimator.reset();
+        self.last_pha = None;
+        self.last_frame = None;
+        self.since_estimate = 0;
+        self.motion = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{CV_32FC1, CV_8UC3};
+
+    #[derive(Debug)]
+    struct ConstantEstimator;
+
+    impl MatteEstimator for ConstantEstimator {
+        fn estimate(&mut self, src_image: &Mat) -> Result<Matte, FilterError> {
+            let pha = Mat::new_size_with_default(src_image.size()?, CV_32FC1, Scalar::all(1.0))?;
+            Ok(Matte { fgr: None, pha })
+        }
+    }
+
+    #[test]
+    fn inference_interval_from_quality() {
+        let mut filter = TemporalFilter::new(Box::new(ConstantEstimator), 1);
+        filter.set_quality(&Quality {
+            downsample_ratio: 0.25,
+            inference_scale: 1.0,
+            inference_interval: 3,
+        });
+        let frame = Mat::new_rows_cols_with_default(48, 64, CV_8UC3, Scalar::all(128.0)).unwrap();
+        // The load of the model only changes the interval once it ran twice
+        let warped: Vec<bool> = (0..4)
+            .map(|_| {
+                filter.estimate(&frame).unwrap();
+                filter.timings.duration("warp").is_some()
+            })
+            .collect();
+        assert_eq!(warped, [false, true, true, false]);
+    }
+}
@@ -0,0 +1,184 @@
+//! This module converts between the raw video formats the element accepts and the RGB images the
+//! filters work on. RGB frames are filtered in place, all other formats are converted to a
+//! scratch RGB image and back.
+
+use crate::filter::FilterError;
+use gstreamer_video::VideoFormat;
+use opencv::core::{Size, Vector, CV_8UC1, CV_8UC2, CV_8UC3, CV_8UC4};
+use opencv::imgproc::{
+    cvt_color, resize, COLOR_BGR2RGB, COLOR_RGB2BGR, COLOR_RGB2YUV_I420, COLOR_YUV2RGB_I420,
+    COLOR_YUV2RGB_NV12, COLOR_YUV2RGB_YUY2, INTER_NEAREST,
+};
+use opencv::prelude::*;
+
+/// The formats accepted on the sink pad, in order of preference.
+pub const SUPPORTED_FORMATS: &[VideoFormat] = &[
+    VideoFormat::Rgb,
+    VideoFormat::Bgr,
+    VideoFormat::Rgbx,
+    VideoFormat::Bgrx,
+    VideoFormat::Rgba,
+    VideoFormat::Bgra,
+    VideoFormat::Yuy2,
+    VideoFormat::Nv12,
+    VideoFormat::I420,
+];
+
+/// The formats produced on the source pad in alpha mode.
+pub const ALPHA_FORMATS: &[VideoFormat] = &[VideoFormat::Rgba, VideoFormat::Bgra];
+
+/// Returns the number of rows and the OpenCV type of a Mat holding a whole frame of the given
+/// format, using the layout OpenCV expects for its colour conversions. Planar formats are
+/// stored as one single-channel Mat with the chroma planes below the luma plane.
+pub fn frame_layout(format: VideoFormat, height: i32) -> Result<(i32, i32), FilterError> {
+    match format {
+        VideoFormat::Rgb | VideoFormat::Bgr => Ok((height, CV_8UC3)),
+        VideoFormat::Rgbx | VideoFormat::Bgrx | VideoFormat::Rgba | VideoFormat::Bgra => {
+            Ok((height, CV_8UC4))
+        }
+        VideoFormat::Yuy2 => Ok((height, CV_8UC2)),
+        VideoFormat::Nv12 | VideoFormat::I420 => Ok((height * 3 / 2, CV_8UC1)),
+        other => Err(FilterError::Other(format!("Unsupported video format {}", other))),
+    }
+}
+
+/// Converts frames of one format to RGB and back, reusing its buffers between frames.
+#[derive(Debug)]
+pub struct FormatConverter {
+    format: VideoFormat,
+    /// The frame in RGB, this is what the filters work on
+    rgb: Mat,
+    /// The frame in I420, used as intermediate step when converting back to YUV formats
+    i420: Mat,
+}
+
+impl FormatConverter {
+    pub fn new(format: VideoFormat) -> Result<FormatConverter, FilterError> {
+        if !SUPPORTED_FORMATS.contains(&format) {
+            return Err(FilterError::Other(format!("Unsupported video format {}", format)));
+        }
+        Ok(FormatConverter {
+            format,
+            rgb: Mat::default(),
+            i420: Mat::default(),
+        })
+    }
+
+    pub fn format(&self) -> VideoFormat {
+        self.format
+    }
+
+    /// Whether frames can be filtered in place without any conversion.
+    pub fn is_native(&self) -> bool {
+        self.format == VideoFormat::Rgb
+    }
+
+    /// The RGB image written by [`FormatConverter::to_rgb`].
+    pub fn rgb_mut(&mut self) -> &mut Mat {
+        &mut self.rgb
+    }
+
+    /// Convert `frame`, laid out as described by [`frame_layout`], to RGB.
+    pub fn to_rgb(&mut self, frame: &Mat) -> Result<(), FilterError> {
+        match self.format {
+            VideoFormat::Rgb => frame.copy_to(&mut self.rgb)?,
+            VideoFormat::Bgr => cvt_color(frame, &mut self.rgb, COLOR_BGR2RGB, 0)?,
+            VideoFormat::Rgbx | VideoFormat::Rgba | VideoFormat::Bgrx | VideoFormat::Bgra => {
+                // mix_channels needs an allocated destination
+                if self.rgb.size()? != frame.size()? || self.rgb.typ() != CV_8UC3 {
+                    self.rgb = Mat::new_size_with_default(
+                        frame.size()?,
+                        CV_8UC3,
+                        opencv::core::Scalar::all(0.0),
+                    )?;
+                }
+                let channels = self.rgb_channels();
+                opencv::core::mix_channels(frame, &mut self.rgb, channels)?;
+            }
+            VideoFormat::Yuy2 => cvt_color(frame, &mut self.rgb, COLOR_YUV2RGB_YUY2, 0)?,
+            VideoFormat::Nv12 => cvt_color(frame, &mut self.rgb, COLOR_YUV2RGB_NV12, 0)?,
+            VideoFormat::I420 => cvt_color(frame, &mut self.rgb, COLOR_YUV2RGB_I420, 0)?,
+            _ => unreachable!("Unsupported formats are rejected on construction"),
+        }
+        Ok(())
+    }
+
+    /// Write the RGB image back to `frame` in the original format.
+    pub fn from_rgb(&mut self, frame: &mut Mat) -> Result<(), FilterError> {
+        match self.format {
+            VideoFormat::Rgb => self.rgb.copy_to(frame)?,
+            VideoFormat::Bgr => cvt_color(&self.rgb, frame, COLOR_RGB2BGR, 0)?,
+            VideoFormat::Rgbx | VideoFormat::Rgba | VideoFormat::Bgrx | VideoFormat::Bgra => {
+                // Only the colour channels are written so that the alpha channel is preserved
+                opencv::core::mix_channels(&self.rgb, frame, self.rgb_channels())?;
+            }
+            VideoFormat::Yuy2 | VideoFormat::Nv12 | VideoFormat::I420 => {
+                self.from_rgb_yuv(frame)?
+            }
+            _ => unreachable!("Unsupported formats are rejected on construction"),
+        }
+        Ok(())
+    }
+
+    /// Channel mapping between the RGB image and a frame with four channels.
+    fn rgb_channels(&self) -> &'static [i32] {
+        match self.format {
+            VideoFormat::Bgrx | VideoFormat::Bgra => &[0, 2, 1, 1, 2, 0],
+            _ => &[0, 0, 1, 1, 2, 2],
+        }
+    }
+
+    /// OpenCV can only convert RGB to planar 4:2:0 YUV, so we always convert to I420 and then
+    /// rearrange the planes for the other YUV formats. This keeps the colour matrix identical
+    /// to the one used when converting to RGB.
+    fn from_rgb_yuv(&mut self, frame: &mut Mat) -> Result<(), FilterError> {
+        let width = self.rgb.cols();
+        let height = self.rgb.rows();
+        if width % 2 != 0 || height % 2 != 0 {
+            return Err(FilterError::Other(format!(
+                "YUV frames need an even width and height, got {}x{}",
+                width, height
+            )));
+        }
+        if self.format == VideoFormat::I420 {
+            cvt_color(&self.rgb, frame, COLOR_RGB2YUV_I420, 0)?;
+            return Ok(());
+        }
+        cvt_color(&self.rgb, &mut self.i420, COLOR_RGB2YUV_I420, 0)?;
+        // The U and V planes follow the Y plane, each taking up a quarter of its rows
+        let y = self.i420.row_bounds(0, height)?;
+        let u = self
+            .i420
+            .row_bounds(height, height + height / 4)?
+            .reshape(1, height / 2)?;
+        let v = self
+            .i420
+            .row_bounds(height + height / 4, height * 3 / 2)?
+            .reshape(1, height / 2)?;
+        match self.format {
+            VideoFormat::Nv12 => {
+                // Y plane followed by interleaved UV pairs
+                y.copy_to(&mut frame.row_bounds(0, height)?)?;
+                let mut uv = Mat::default();
+                opencv::core::merge(&Vector::<Mat>::from(vec![u, v]), &mut uv)?;
+                uv.reshape(1, height / 2)?
+                    .copy_to(&mut frame.row_bounds(height, height * 3 / 2)?)?;
+            }
+            VideoFormat::Yuy2 => {
+                // Packed as Y0 U0 Y1 V0, with chroma for every row
+                let mut u_full = Mat::default();
+                let mut v_full = Mat::default();
+                let chroma_size = Size::new(width / 2, height);
+                resize(&u, &mut u_full, chroma_size, 0.0, 0.0, INTER_NEAREST)?;
+                resize(&v, &mut v_full, chroma_size, 0.0, 0.0, INTER_NEAREST)?;
+                let mut uv = Mat::default();
+                opencv::core::merge(&Vector::<Mat>::from(vec![u_full, v_full]), &mut uv)?;
+                let uv = uv.reshape(1, height)?;
+                opencv::core::merge(&Vector::<Mat>::from(vec![y, uv]), frame)?;
+            }
+            _ => unreachable!("Only called for YUV formats"),
+        }
+        Ok(())
+    }
+}
+